@@ -1,11 +1,19 @@
 use std::time::Duration;
 
-use glam::Mat4;
+use embree4_sys::{
+    rtcAttachGeometry, rtcCommitGeometry, rtcCommitScene, rtcGetGeometryBufferData, rtcIntersect1,
+    rtcNewGeometry, rtcNewScene, rtcReleaseGeometry, rtcReleaseScene, rtcSetGeometryBuildQuality,
+    rtcSetGeometryInstancedScene, rtcSetGeometryTransform, rtcSetNewGeometryBuffer,
+    rtcSetSceneBuildQuality, rtcSetSceneFlags, rtcUpdateGeometryBuffer, RTCBufferType,
+    RTCBuildQuality, RTCFormat, RTCGeometry, RTCGeometryType, RTCHit, RTCRay, RTCRayHit, RTCScene,
+    RTCSceneFlags, RTC_INVALID_GEOMETRY_ID,
+};
+use glam::{Affine3A, Mat4, Vec3A};
 use obvhs::{
     ray::{Ray, RayHit},
     triangle::Triangle,
 };
-use traversable::{SceneTri, Traversable};
+use traversable::{Intersectable, SceneTri, Traversable};
 
 pub fn embree_attach_geometry(
     objects: &Vec<Vec<Triangle>>,
@@ -70,3 +78,302 @@ impl<'a> Traversable for EmbreeSceneAndObjects<'a> {
         Mat4::default()
     }
 }
+
+/// Two-level Embree scene: each mesh lives in its own Embree scene and is attached to the top level scene as an
+/// instance. Used to compare Embree's TLAS traversal against `CwBvhTlasScene`.
+pub struct EmbreeInstancedScene {
+    pub scene: RTCScene,
+    pub blas_scenes: Vec<RTCScene>,
+    /// Mesh index of each instance, indexed by instance id.
+    pub instance_meshes: Vec<u32>,
+    pub instance_transforms: Vec<Affine3A>,
+}
+
+// Embree scenes are safe to traverse from multiple threads once committed.
+unsafe impl Send for EmbreeInstancedScene {}
+unsafe impl Sync for EmbreeInstancedScene {}
+
+impl Drop for EmbreeInstancedScene {
+    fn drop(&mut self) {
+        unsafe {
+            rtcReleaseScene(self.scene);
+            for blas_scene in &self.blas_scenes {
+                rtcReleaseScene(*blas_scene);
+            }
+        }
+    }
+}
+
+/// Builds one Embree scene per mesh and a top level scene with an instance geometry for each of `instances`
+/// (mesh index, transform). Time spent creating, filling and committing the mesh scenes is added to `blas_build_time`
+/// (like `embree_attach_geometry`, which includes filling the geometry buffers), time spent creating and committing the
+/// top level scene is added to `tlas_build_time`.
+pub fn embree_build_instanced_scene(
+    meshes: &[Vec<Triangle>],
+    instances: &[(u32, Affine3A)],
+    device: &embree4_rs::Device,
+    blas_build_time: &mut Duration,
+    tlas_build_time: &mut Duration,
+) -> EmbreeInstancedScene {
+    let mut blas_scenes = Vec::with_capacity(meshes.len());
+    for mesh in meshes {
+        let start_time = std::time::Instant::now();
+        unsafe {
+            let blas_scene = rtcNewScene(device.handle);
+            rtcSetSceneBuildQuality(blas_scene, RTCBuildQuality::HIGH);
+            let geometry = rtcNewGeometry(device.handle, RTCGeometryType::TRIANGLE);
+            let verts = std::slice::from_raw_parts_mut(
+                rtcSetNewGeometryBuffer(
+                    geometry,
+                    RTCBufferType::VERTEX,
+                    0,
+                    RTCFormat::FLOAT3,
+                    3 * std::mem::size_of::<f32>(),
+                    mesh.len() * 3,
+                ) as *mut [f32; 3],
+                mesh.len() * 3,
+            );
+            let indices = std::slice::from_raw_parts_mut(
+                rtcSetNewGeometryBuffer(
+                    geometry,
+                    RTCBufferType::INDEX,
+                    0,
+                    RTCFormat::UINT3,
+                    3 * std::mem::size_of::<u32>(),
+                    mesh.len(),
+                ) as *mut [u32; 3],
+                mesh.len(),
+            );
+            for (i, tri) in mesh.iter().enumerate() {
+                verts[i * 3] = tri.v0.into();
+                verts[i * 3 + 1] = tri.v1.into();
+                verts[i * 3 + 2] = tri.v2.into();
+                let first_vert = i as u32 * 3;
+                indices[i] = [first_vert, first_vert + 1, first_vert + 2];
+            }
+            rtcCommitGeometry(geometry);
+            rtcAttachGeometry(blas_scene, geometry);
+            rtcReleaseGeometry(geometry);
+            rtcCommitScene(blas_scene);
+            *blas_build_time += start_time.elapsed();
+            blas_scenes.push(blas_scene);
+        }
+    }
+
+    let start_time = std::time::Instant::now();
+    let scene = unsafe { rtcNewScene(device.handle) };
+    unsafe { rtcSetSceneBuildQuality(scene, RTCBuildQuality::HIGH) };
+    for (mesh_index, transform) in instances {
+        unsafe {
+            let instance = rtcNewGeometry(device.handle, RTCGeometryType::INSTANCE);
+            rtcSetGeometryInstancedScene(instance, blas_scenes[*mesh_index as usize]);
+            let columns = transform.to_cols_array();
+            rtcSetGeometryTransform(
+                instance,
+                0,
+                RTCFormat::FLOAT3X4_COLUMN_MAJOR,
+                columns.as_ptr().cast(),
+            );
+            rtcCommitGeometry(instance);
+            rtcAttachGeometry(scene, instance);
+            rtcReleaseGeometry(instance);
+        }
+    }
+    unsafe { rtcCommitScene(scene) };
+    *tlas_build_time += start_time.elapsed();
+
+    EmbreeInstancedScene {
+        scene,
+        blas_scenes,
+        instance_meshes: instances.iter().map(|(mesh, _)| *mesh).collect(),
+        instance_transforms: instances.iter().map(|(_, transform)| *transform).collect(),
+    }
+}
+
+pub struct EmbreeInstancedSceneAndObjects<'a> {
+    pub scene: &'a EmbreeInstancedScene,
+    pub objects: &'a [Vec<SceneTri>],
+}
+
+impl<'a> Traversable for EmbreeInstancedSceneAndObjects<'a> {
+    type Primitive = SceneTri;
+
+    fn traverse(&self, ray: Ray) -> RayHit {
+        if let Some(ray_hit) = rtc_intersect_1(self.scene.scene, &ray) {
+            let instance_id = ray_hit.hit.instID[0];
+            RayHit {
+                primitive_id: ray_hit.hit.primID,
+                // Each mesh scene only has one geometry, report the mesh instead so get_primitive can find the tri.
+                geometry_id: self.scene.instance_meshes[instance_id as usize],
+                instance_id,
+                t: ray_hit.ray.tfar,
+            }
+        } else {
+            RayHit::none()
+        }
+    }
+
+    fn get_primitive(&self, geometry_id: u32, primitive_id: u32) -> &SceneTri {
+        &self.objects[geometry_id as usize][primitive_id as usize]
+    }
+
+    fn get_instance_transform(&self, instance_id: u32) -> Mat4 {
+        Mat4::from(self.scene.instance_transforms[instance_id as usize])
+    }
+
+    /// The triangles are in the object space of the instance.
+    fn hit_normal(&self, hit: &RayHit, ray: &Ray) -> Vec3A {
+        let n = self
+            .get_primitive(hit.geometry_id, hit.primitive_id)
+            .compute_normal(ray);
+        self.scene.instance_transforms[hit.instance_id as usize]
+            .transform_vector3a(n)
+            .normalize_or_zero()
+    }
+}
+
+/// Embree scene with a single triangle mesh whose vertices are updated in place. With `RTCBuildQuality::REFIT` Embree
+/// only refits the existing BVH when the scene is committed after an update, otherwise the BVH is rebuilt.
+pub struct EmbreeDynamicScene {
+    pub scene: RTCScene,
+    geometry: RTCGeometry,
+    tri_count: usize,
+}
+
+// Embree scenes are safe to traverse from multiple threads once committed.
+unsafe impl Send for EmbreeDynamicScene {}
+unsafe impl Sync for EmbreeDynamicScene {}
+
+impl Drop for EmbreeDynamicScene {
+    fn drop(&mut self) {
+        unsafe {
+            rtcReleaseGeometry(self.geometry);
+            rtcReleaseScene(self.scene);
+        }
+    }
+}
+
+impl EmbreeDynamicScene {
+    /// Time spent committing is added to `build_time`.
+    pub fn new(
+        tris: &[Triangle],
+        device: &embree4_rs::Device,
+        quality: RTCBuildQuality,
+        build_time: &mut Duration,
+    ) -> Self {
+        unsafe {
+            let scene = rtcNewScene(device.handle);
+            rtcSetSceneFlags(scene, RTCSceneFlags::DYNAMIC);
+            rtcSetSceneBuildQuality(scene, RTCBuildQuality::HIGH);
+            let geometry = rtcNewGeometry(device.handle, RTCGeometryType::TRIANGLE);
+            rtcSetGeometryBuildQuality(geometry, quality);
+            rtcSetNewGeometryBuffer(
+                geometry,
+                RTCBufferType::VERTEX,
+                0,
+                RTCFormat::FLOAT3,
+                3 * std::mem::size_of::<f32>(),
+                tris.len() * 3,
+            );
+            let indices = std::slice::from_raw_parts_mut(
+                rtcSetNewGeometryBuffer(
+                    geometry,
+                    RTCBufferType::INDEX,
+                    0,
+                    RTCFormat::UINT3,
+                    3 * std::mem::size_of::<u32>(),
+                    tris.len(),
+                ) as *mut [u32; 3],
+                tris.len(),
+            );
+            for (i, index) in indices.iter_mut().enumerate() {
+                let first_vert = i as u32 * 3;
+                *index = [first_vert, first_vert + 1, first_vert + 2];
+            }
+            rtcAttachGeometry(scene, geometry);
+            let mut dynamic_scene = EmbreeDynamicScene {
+                scene,
+                geometry,
+                tri_count: tris.len(),
+            };
+            dynamic_scene.update(tris, build_time);
+            dynamic_scene
+        }
+    }
+
+    /// Writes the new vertex positions and recommits the scene. `tris` must have the same length as the tris the
+    /// scene was created with. Time spent committing is added to `build_time`.
+    pub fn update(&mut self, tris: &[Triangle], build_time: &mut Duration) {
+        assert_eq!(tris.len(), self.tri_count);
+        unsafe {
+            let verts = std::slice::from_raw_parts_mut(
+                rtcGetGeometryBufferData(self.geometry, RTCBufferType::VERTEX, 0) as *mut [f32; 3],
+                tris.len() * 3,
+            );
+            for (i, tri) in tris.iter().enumerate() {
+                verts[i * 3] = tri.v0.into();
+                verts[i * 3 + 1] = tri.v1.into();
+                verts[i * 3 + 2] = tri.v2.into();
+            }
+            let start_time = std::time::Instant::now();
+            rtcUpdateGeometryBuffer(self.geometry, RTCBufferType::VERTEX, 0);
+            rtcCommitGeometry(self.geometry);
+            rtcCommitScene(self.scene);
+            *build_time += start_time.elapsed();
+        }
+    }
+}
+
+pub struct EmbreeDynamicSceneAndTris<'a> {
+    pub scene: &'a EmbreeDynamicScene,
+    pub tris: &'a [SceneTri],
+}
+
+impl<'a> Traversable for EmbreeDynamicSceneAndTris<'a> {
+    type Primitive = SceneTri;
+
+    fn traverse(&self, ray: Ray) -> RayHit {
+        if let Some(ray_hit) = rtc_intersect_1(self.scene.scene, &ray) {
+            RayHit {
+                primitive_id: ray_hit.hit.primID,
+                geometry_id: 0,
+                instance_id: 0,
+                t: ray_hit.ray.tfar,
+            }
+        } else {
+            RayHit::none()
+        }
+    }
+
+    fn get_primitive(&self, _geometry_id: u32, primitive_id: u32) -> &SceneTri {
+        &self.tris[primitive_id as usize]
+    }
+
+    fn get_instance_transform(&self, _instance_id: u32) -> Mat4 {
+        Mat4::default()
+    }
+}
+
+fn rtc_intersect_1(scene: RTCScene, ray: &Ray) -> Option<RTCRayHit> {
+    let mut ray_hit = RTCRayHit {
+        ray: RTCRay {
+            org_x: ray.origin.x,
+            org_y: ray.origin.y,
+            org_z: ray.origin.z,
+            dir_x: ray.direction.x,
+            dir_y: ray.direction.y,
+            dir_z: ray.direction.z,
+            tnear: ray.tmin,
+            tfar: ray.tmax,
+            mask: u32::MAX,
+            ..Default::default()
+        },
+        hit: RTCHit {
+            geomID: RTC_INVALID_GEOMETRY_ID,
+            instID: [RTC_INVALID_GEOMETRY_ID],
+            ..Default::default()
+        },
+    };
+    unsafe { rtcIntersect1(scene, &mut ray_hit, std::ptr::null_mut()) };
+    (ray_hit.hit.geomID != RTC_INVALID_GEOMETRY_ID).then_some(ray_hit)
+}
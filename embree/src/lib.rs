@@ -4,12 +4,25 @@ pub mod embree_managed;
 pub mod gpu_bvh_builder_embree;
 pub mod gpu_bvh_builder_embree_bvh2;
 
-pub fn new_embree_device(threads: usize, verbose: bool, limit_to_sse2: bool) -> embree4_rs::Device {
+/// `isa` limits Embree to the given instruction set (sse2, sse4.2, avx, avx2, avx512). If `None` Embree will use
+/// the best one supported by the CPU. `extra_config` is appended to the device config string as is, and can hold any
+/// other comma separated Embree device options (eg. `frequency_level=simd256`).
+pub fn new_embree_device(
+    threads: usize,
+    verbose: bool,
+    isa: Option<&str>,
+    extra_config: &str,
+) -> embree4_rs::Device {
     embree4_rs::Device::try_new(Some(&format!(
-        "threads={}{}{}\0",
+        "threads={}{}{}{}\0",
         threads,
-        if limit_to_sse2 { ",isa=sse2" } else { "" },
-        if verbose { ",verbose=4" } else { "" }
+        isa.map(|isa| format!(",isa={isa}")).unwrap_or_default(),
+        if verbose { ",verbose=4" } else { "" },
+        if extra_config.is_empty() {
+            String::new()
+        } else {
+            format!(",{extra_config}")
+        }
     )))
     .unwrap()
 }
@@ -0,0 +1,216 @@
+use std::fs::{File, OpenOptions};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::{objectives::Objectives, spec::TuneParams};
+use crate::{bvh_cache::Fnv1a, Options};
+
+/// One scene of an evaluation. The scenes of an evaluation are written together as soon as it is done.
+#[derive(Serialize, Deserialize)]
+struct CheckpointRecord {
+    /// Settings that have to match for a resume, see `run_description`.
+    run: String,
+    build: String,
+    cpu: bool,
+    split: bool,
+    search_distance: u32,
+    sort_precision: u8,
+    reinsertion_batch_ratio: f32,
+    search_depth_threshold: usize,
+    max_prims_per_leaf: u32,
+    post_collapse_reinsertion_batch_ratio_multiplier: f32,
+    collapse_traversal_cost: f32,
+    rt_vgpr_stack_size: u32,
+    rt_lds_stack_size: u32,
+    rt_group_width: u32,
+    rt_group_height: u32,
+    /// Render time of this evaluation, less than `--render-time` for the early successive halving rounds.
+    render_time: f32,
+    scene: String,
+    traversal_ms: f32,
+    blas_build_time_s: f32,
+    tlas_build_time_ms: f32,
+    bvh_memory_mb: f32,
+    /// Tuning time in seconds when the evaluation finished, including the time of previous runs.
+    elapsed: f32,
+}
+
+impl CheckpointRecord {
+    fn params(&self) -> TuneParams {
+        TuneParams {
+            build: self.build.clone(),
+            cpu: self.cpu,
+            split: self.split,
+            search_distance: self.search_distance,
+            sort_precision: self.sort_precision,
+            reinsertion_batch_ratio: self.reinsertion_batch_ratio,
+            search_depth_threshold: self.search_depth_threshold,
+            max_prims_per_leaf: self.max_prims_per_leaf,
+            post_collapse_reinsertion_batch_ratio_multiplier: self
+                .post_collapse_reinsertion_batch_ratio_multiplier,
+            collapse_traversal_cost: self.collapse_traversal_cost,
+            rt_vgpr_stack_size: self.rt_vgpr_stack_size,
+            rt_lds_stack_size: self.rt_lds_stack_size,
+            rt_group_width: self.rt_group_width,
+            rt_group_height: self.rt_group_height,
+        }
+    }
+}
+
+/// CSV the auto-tune results are appended to after each evaluation. When resuming with `--tune-resume`, the search is
+/// replayed from the start and evaluations found in the file are taken from it instead of rendering again. The
+/// strategies only depend on the evaluations and the seed, so they make the same choices as an uninterrupted run.
+pub struct Checkpoint {
+    pub path: String,
+    run: String,
+    scene_count: usize,
+    records: Vec<CheckpointRecord>,
+    writer: csv::Writer<File>,
+}
+
+impl Checkpoint {
+    pub fn open(options: &Options) -> Self {
+        let run = run_description(options);
+        let scene_count = options.input.split(',').count();
+        if options.tune_resume.is_empty() {
+            let now: DateTime<Utc> = Utc::now();
+            let path = format!("tune_checkpoint_{}.csv", now.format("%Y-%m-%d_%H-%M-%S"));
+            let file = File::create(&path).expect("Failed creating tuning checkpoint");
+            return Checkpoint {
+                path,
+                run,
+                scene_count,
+                records: Vec::new(),
+                writer: csv::Writer::from_writer(file),
+            };
+        }
+
+        let path = options.tune_resume.clone();
+        let mut reader = csv::Reader::from_path(&path).expect("Failed opening tuning checkpoint");
+        let records = reader
+            .deserialize()
+            .collect::<Result<Vec<CheckpointRecord>, _>>()
+            .unwrap_or_else(|e| panic!("Failed to read tuning checkpoint {}: {}", path, e));
+        if let Some(record) = records.iter().find(|r| r.run != run) {
+            panic!(
+                "Tuning checkpoint {} is from a different run:\n  {}\nthis run is:\n  {}",
+                path, record.run, run
+            );
+        }
+        let file = OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .expect("Failed opening tuning checkpoint");
+        // Only a new or empty file needs the header
+        let writer = csv::WriterBuilder::new()
+            .has_headers(file.metadata().map_or(true, |m| m.len() == 0))
+            .from_writer(file);
+        Checkpoint {
+            path,
+            run,
+            scene_count,
+            records,
+            writer,
+        }
+    }
+
+    pub fn resumed_count(&self) -> usize {
+        self.records.len() / self.scene_count
+    }
+
+    /// Tuning time of the previous runs.
+    pub fn elapsed_offset(&self) -> f32 {
+        self.records.last().map_or(0.0, |r| r.elapsed)
+    }
+
+    /// Per scene objectives from an earlier run. An evaluation that was cut off while being written doesn't count.
+    pub fn lookup(
+        &self,
+        params: &TuneParams,
+        render_time: f32,
+    ) -> Option<Vec<(String, Objectives)>> {
+        let records = self
+            .records
+            .iter()
+            .filter(|r| r.render_time == render_time && r.params() == *params)
+            .collect::<Vec<_>>();
+        // Rendered again after a partial write the complete rows come last
+        let scenes = records[records.len().saturating_sub(self.scene_count)..]
+            .iter()
+            .map(|r| {
+                (
+                    r.scene.clone(),
+                    [
+                        r.traversal_ms,
+                        r.blas_build_time_s,
+                        r.tlas_build_time_ms,
+                        r.bvh_memory_mb,
+                    ],
+                )
+            })
+            .collect::<Vec<_>>();
+        (scenes.len() == self.scene_count).then_some(scenes)
+    }
+
+    pub fn append(
+        &mut self,
+        params: &TuneParams,
+        render_time: f32,
+        scenes: &[(String, Objectives)],
+        elapsed: f32,
+    ) {
+        for (scene, [traversal_ms, blas_build_time_s, tlas_build_time_ms, bvh_memory_mb]) in scenes
+        {
+            self.write(CheckpointRecord {
+                run: self.run.clone(),
+                build: params.build.clone(),
+                cpu: params.cpu,
+                split: params.split,
+                search_distance: params.search_distance,
+                sort_precision: params.sort_precision,
+                reinsertion_batch_ratio: params.reinsertion_batch_ratio,
+                search_depth_threshold: params.search_depth_threshold,
+                max_prims_per_leaf: params.max_prims_per_leaf,
+                post_collapse_reinsertion_batch_ratio_multiplier: params
+                    .post_collapse_reinsertion_batch_ratio_multiplier,
+                collapse_traversal_cost: params.collapse_traversal_cost,
+                rt_vgpr_stack_size: params.rt_vgpr_stack_size,
+                rt_lds_stack_size: params.rt_lds_stack_size,
+                rt_group_width: params.rt_group_width,
+                rt_group_height: params.rt_group_height,
+                render_time,
+                scene: scene.clone(),
+                traversal_ms: *traversal_ms,
+                blas_build_time_s: *blas_build_time_s,
+                tlas_build_time_ms: *tlas_build_time_ms,
+                bvh_memory_mb: *bvh_memory_mb,
+                elapsed,
+            });
+        }
+        // Flushed every time so a crash loses at most the evaluation in progress
+        if let Err(e) = self.writer.flush() {
+            eprintln!("Error writing tuning checkpoint: {}", e);
+        }
+    }
+
+    fn write(&mut self, record: CheckpointRecord) {
+        if let Err(e) = self.writer.serialize(&record) {
+            eprintln!("Error writing tuning checkpoint: {}", e);
+        }
+    }
+}
+
+/// All options but where to resume from and when to stop, and a hash of the `--tune-spec` file. A checkpoint can only
+/// be resumed with the same ones.
+fn run_description(options: &Options) -> String {
+    let mut options = options.clone();
+    options.tune_resume.clear();
+    options.tune_max_evals = 0;
+    options.tune_max_time = 0.0;
+    let spec = std::fs::read(&options.tune_spec)
+        .unwrap_or_else(|e| panic!("Failed reading {}: {}", options.tune_spec, e));
+    let mut hash = Fnv1a::new();
+    hash.write(&spec);
+    format!("{:?} spec_hash {:016x}", options, hash.finish())
+}
@@ -0,0 +1,447 @@
+mod checkpoint;
+mod objectives;
+mod search;
+mod spec;
+
+use std::{collections::HashMap, error::Error, fs::File, path::Path, time::Instant};
+
+use chrono::{DateTime, Utc};
+use tabled::{settings::Style, Table, Tabled};
+
+use crate::{
+    params_file::{GpuKernelParams, ParamsFile, TunedScores},
+    render_from_options, seconds_to_hh_mm_ss, Options,
+};
+
+use checkpoint::Checkpoint;
+use objectives::{
+    arithmetic_mean, geometric_mean, normalize, pareto_front, parse_objectives, parse_weights,
+    scene_objectives, weighted_score, Objective, Objectives,
+};
+use search::{strategy_from_options, Evaluation};
+use spec::{TuneParams, TuneSpec};
+
+pub fn tune(init_options: Options, mut event_loop: winit::event_loop::EventLoop<()>) {
+    let mut model_cache = if init_options.disable_auto_tune_model_cache {
+        None
+    } else {
+        Some(HashMap::new())
+    };
+    let spec = TuneSpec::load(&init_options.tune_spec);
+    let space = spec.search_space(&init_options);
+    let permutations = space.len();
+    let valid_permutations = space.valid_len(&init_options);
+    let Some(first_valid) = (0..permutations)
+        .map(|n| space.indices(n))
+        .find(|indices| space.is_valid(indices, &init_options))
+        .map(|indices| space.params(&indices))
+    else {
+        panic!("The tuning spec has no valid permutations");
+    };
+    let weights = parse_weights(&init_options.tune_weights);
+    if weights.iter().all(|w| *w == 0.0) {
+        panic!("--tune-weights needs at least one objective with a nonzero weight");
+    }
+    let pareto_objectives = parse_objectives(&init_options.tune_pareto);
+    let mut results = Vec::new();
+    // Per scene objectives of each result, in the same order
+    let mut scene_results = Vec::new();
+    {
+        // Warmup. If skipped the first permutation or so may be faster because the clock speed has not normalized.
+        let mut options = init_options.clone();
+        first_valid.apply(&mut options);
+        let (_, _, _) =
+            render_from_options(&options, &mut event_loop, &mut model_cache, &mut Vec::new());
+    }
+    println!(
+        "{} permutations ({} invalid are skipped) from {}, {} search",
+        permutations,
+        permutations - valid_permutations,
+        init_options.tune_spec,
+        init_options.tune_strategy,
+    );
+    let is_dimension = |name| space.dimensions.iter().any(|d| d.name == name);
+    if !is_dimension("build") {
+        println!("  build: {}", space.base.build);
+    }
+    if !is_dimension("cpu") {
+        println!("  target: {}", if space.base.cpu { "cpu" } else { "gpu" });
+    }
+    for dimension in &space.dimensions {
+        println!("  {}: {:?}", dimension.name, dimension.labels);
+    }
+    // Upper bound in full render time evaluations, the smart strategies usually stop earlier. Successive halving
+    // evaluates at most every valid permutation once per rung, but the shorter rungs only count with their fraction.
+    let max_evaluations = match init_options.tune_max_evals {
+        0 => valid_permutations,
+        max => max.min(valid_permutations),
+    } as f32;
+    let mut checkpoint = Checkpoint::open(&init_options);
+    if checkpoint.resumed_count() > 0 {
+        println!(
+            "Resuming from {} with {} evaluations",
+            checkpoint.path,
+            checkpoint.resumed_count()
+        );
+    } else {
+        println!("Saving evaluations to {}", checkpoint.path);
+    }
+    let elapsed_offset = checkpoint.elapsed_offset();
+    let mut strategy = strategy_from_options(&init_options, &space);
+    let mut history = Vec::new();
+    // Evaluations weighted by their fraction of the render time. Invalid combinations are in the history so the
+    // strategies move on, but don't count.
+    let mut evaluations = 0.0;
+    let mut front = Vec::new();
+    let test_start_time = Instant::now();
+    loop {
+        let elapsed_time = elapsed_offset + test_start_time.elapsed().as_secs_f32();
+        if evaluations >= max_evaluations
+            || (init_options.tune_max_time > 0.0 && elapsed_time >= init_options.tune_max_time)
+        {
+            println!("Tuning budget reached");
+            break;
+        }
+        let Some(candidate) = strategy.next(&history) else {
+            break;
+        };
+        let params = space.params(&candidate.indices);
+        if !space.is_valid(&candidate.indices, &init_options) {
+            history.push(Evaluation {
+                indices: candidate.indices,
+                fidelity: candidate.fidelity,
+                score: f32::INFINITY,
+            });
+            continue;
+        }
+        let mut options = init_options.clone();
+        params.apply(&mut options);
+        options.render_time *= candidate.fidelity;
+
+        let scenes = match checkpoint.lookup(&params, options.render_time) {
+            Some(scenes) => scenes,
+            None => {
+                let mut stats = Vec::new();
+                render_from_options(&options, &mut event_loop, &mut model_cache, &mut stats);
+                let scenes = scene_objectives(&stats);
+                let elapsed_time = elapsed_offset + test_start_time.elapsed().as_secs_f32();
+                checkpoint.append(&params, options.render_time, &scenes, elapsed_time);
+                scenes
+            }
+        };
+        let scenes = scenes.into_iter().map(|(_, s)| s).collect::<Vec<_>>();
+        let [avg_traversal_time, avg_blas_build_time, avg_tlas_build_time, avg_bvh_memory] =
+            arithmetic_mean(&scenes);
+        evaluations += candidate.fidelity;
+        history.push(Evaluation {
+            indices: candidate.indices,
+            fidelity: candidate.fidelity,
+            score: weighted_score(&geometric_mean(&scenes), &weights),
+        });
+
+        if candidate.fidelity < 1.0 {
+            // Only used to pick what to evaluate with the full render time
+            println!(
+                "{:.3}s render time: {:.3}ms traversal",
+                options.render_time, avg_traversal_time
+            );
+        } else {
+            results.push(TuningSet {
+                build: params.build.clone(),
+                target: String::from(if params.cpu { "cpu" } else { "gpu" }),
+                search_distance: params.search_distance,
+                sort_precision: params.sort_precision,
+                reinsertion_batch_ratio: params.reinsertion_batch_ratio,
+                search_depth_threshold: params.search_depth_threshold,
+                avg_traversal_time,
+                avg_blas_build_time,
+                avg_tlas_build_time,
+                avg_bvh_memory,
+                split: params.split,
+                max_prims_per_leaf: params.max_prims_per_leaf,
+                post_collapse_reinsertion_batch_ratio_multiplier: params
+                    .post_collapse_reinsertion_batch_ratio_multiplier,
+                collapse_traversal_cost: params.collapse_traversal_cost,
+                gpu_kernel: gpu_kernel_params(&params),
+                norm_best_blas_build_time: 0.0,
+                norm_best_tlas_build_time: 0.0,
+                norm_best_traversal_time: 0.0,
+                norm_best_bvh_memory: 0.0,
+                score: 0.0,
+            });
+            scene_results.push(scenes);
+            normalize_results(&mut results, &scene_results, &weights);
+
+            let new_front = front_indices(&results, &pareto_objectives);
+            if new_front != front {
+                println!("Pareto front so far ({}):", init_options.tune_pareto);
+                println!(
+                    "{}",
+                    Table::new(new_front.iter().map(|i| &results[*i])).with(Style::blank())
+                );
+            }
+            front = new_front;
+        }
+
+        let elapsed_time = elapsed_offset + test_start_time.elapsed().as_secs_f32();
+        let avg_permutation_duration = elapsed_time / evaluations;
+        let mut expected_remaining_duration =
+            (max_evaluations - evaluations).max(0.0) * avg_permutation_duration;
+        if init_options.tune_max_time > 0.0 {
+            expected_remaining_duration =
+                expected_remaining_duration.min(init_options.tune_max_time - elapsed_time);
+        }
+
+        println!(
+            "Expected Remaining Duration (at most): {}",
+            seconds_to_hh_mm_ss(expected_remaining_duration.max(0.0))
+        );
+        println!("Avg permutation time: {:.2}s", avg_permutation_duration);
+        println!("Time elapsed: {}", seconds_to_hh_mm_ss(elapsed_time));
+        println!("{:.2} / {}", evaluations, max_evaluations);
+    }
+
+    let front_results = |objectives: &[Objective]| {
+        front_indices(&results, objectives)
+            .into_iter()
+            .map(|i| results[i].clone())
+            .collect::<Vec<_>>()
+    };
+    let pareto_results = front_results(&pareto_objectives);
+    let blas_filtered_results = front_results(&[Objective::Traversal, Objective::BlasBuild]);
+    let tlas_filtered_results = front_results(&[Objective::Traversal, Objective::TlasBuild]);
+    let mut ranked_results = results.clone();
+    ranked_results.sort_by(|a, b| a.score.total_cmp(&b.score));
+
+    println!("Pareto front ({}):", init_options.tune_pareto);
+    println!("{}", Table::new(&pareto_results).with(Style::blank()));
+    println!("Best by weighted score ({}):", init_options.tune_weights);
+    println!(
+        "{}",
+        Table::new(ranked_results.iter().take(10)).with(Style::blank())
+    );
+    save_results("results", &results);
+    save_results("pareto_results", &pareto_results);
+    save_results("blas_filtered_results", &blas_filtered_results);
+    save_results("tlas_filtered_results", &tlas_filtered_results);
+    save_results("ranked_results", &ranked_results);
+    save_params_files(&pareto_results, &init_options);
+
+    fn save_results(name: &str, tlas_filtered_results: &[TuningSet]) {
+        match save_tuning_results_to_csv(&tlas_filtered_results, name) {
+            Ok(filename) => println!("CSV file saved successfully as '{}'.", filename),
+            Err(e) => eprintln!("Error saving CSV file: {}", e),
+        }
+    }
+}
+
+/// The `--rt-*` options of a GPU result.
+fn gpu_kernel_params(params: &TuneParams) -> Option<GpuKernelParams> {
+    (!params.cpu).then_some(GpuKernelParams {
+        rt_vgpr_stack_size: params.rt_vgpr_stack_size,
+        rt_lds_stack_size: params.rt_lds_stack_size,
+        rt_group_width: params.rt_group_width,
+        rt_group_height: params.rt_group_height,
+    })
+}
+
+fn display_gpu_kernel(kernel: &Option<GpuKernelParams>) -> String {
+    match kernel {
+        Some(kernel) => format!(
+            "stack {}+{} group {}x{}",
+            kernel.rt_vgpr_stack_size,
+            kernel.rt_lds_stack_size,
+            kernel.rt_group_width,
+            kernel.rt_group_height
+        ),
+        None => String::from("-"),
+    }
+}
+
+/// Writes each result as a `--params-file` with its scores, to a new `pareto_params_<date>` directory.
+fn save_params_files(tuning_sets: &[TuningSet], options: &Options) {
+    let now: DateTime<Utc> = Utc::now();
+    let dir = format!("pareto_params_{}", now.format("%Y-%m-%d_%H-%M-%S"));
+    if let Err(e) = std::fs::create_dir_all(&dir) {
+        eprintln!("Error creating {}: {}", dir, e);
+        return;
+    }
+    for (i, tuning_set) in tuning_sets.iter().enumerate() {
+        let params = ParamsFile {
+            build: Some(tuning_set.build.clone()),
+            cpu: Some(tuning_set.target == "cpu"),
+            gpu_kernel: tuning_set.gpu_kernel,
+            pre_split: tuning_set.split,
+            ploc_search_distance: tuning_set.search_distance,
+            search_depth_threshold: tuning_set.search_depth_threshold,
+            reinsertion_batch_ratio: tuning_set.reinsertion_batch_ratio,
+            sort_precision: tuning_set.sort_precision,
+            max_prims_per_leaf: tuning_set.max_prims_per_leaf,
+            post_collapse_reinsertion_batch_ratio_multiplier: tuning_set
+                .post_collapse_reinsertion_batch_ratio_multiplier,
+            collapse_traversal_cost: tuning_set.collapse_traversal_cost,
+            tuned: Some(TunedScores {
+                scenes: options.input.clone(),
+                avg_traversal_ms: tuning_set.avg_traversal_time,
+                avg_blas_build_s: tuning_set.avg_blas_build_time,
+                avg_tlas_build_ms: tuning_set.avg_tlas_build_time,
+                avg_bvh_memory_mb: tuning_set.avg_bvh_memory,
+                norm_traversal: tuning_set.norm_best_traversal_time,
+                norm_blas_build: tuning_set.norm_best_blas_build_time,
+                norm_tlas_build: tuning_set.norm_best_tlas_build_time,
+                norm_bvh_memory: tuning_set.norm_best_bvh_memory,
+                score: tuning_set.score,
+            }),
+        };
+        params.save(&format!(
+            "{}/{}_{}_{}.ron",
+            dir, i, tuning_set.build, tuning_set.target
+        ));
+    }
+    println!("Pareto front params files saved to '{}'.", dir);
+}
+
+/// Fills in the normalized objectives and the weighted score. They are relative to the best result on each scene so
+/// they change as results are added.
+fn normalize_results(
+    results: &mut [TuningSet],
+    scene_results: &[Vec<Objectives>],
+    weights: &Objectives,
+) {
+    for (result, norm) in results.iter_mut().zip(normalize(scene_results)) {
+        [
+            result.norm_best_traversal_time,
+            result.norm_best_blas_build_time,
+            result.norm_best_tlas_build_time,
+            result.norm_best_bvh_memory,
+        ] = norm;
+        result.score = weighted_score(&norm, weights);
+    }
+}
+
+/// Indices of the Pareto front over the normalized `objectives`.
+fn front_indices(results: &[TuningSet], objectives: &[Objective]) -> Vec<usize> {
+    let norm = results
+        .iter()
+        .map(|r| {
+            [
+                r.norm_best_traversal_time,
+                r.norm_best_blas_build_time,
+                r.norm_best_tlas_build_time,
+                r.norm_best_bvh_memory,
+            ]
+        })
+        .collect::<Vec<_>>();
+    pareto_front(&norm, objectives)
+}
+
+#[derive(Debug, Tabled, Clone, PartialEq)]
+struct TuningSet {
+    build: String,
+    /// cpu or gpu (software traversal)
+    target: String,
+    /// Split large tris into multiple AABBs
+    split: bool,
+    /// In PLOC, the number of nodes before and after the current one that are evaluated for pairing
+    search_distance: u32,
+    /// Bits used for ploc radix sort
+    sort_precision: u8,
+    /// Typically 0..1: Ratio of nodes considered as candidates for reinsertion. Above 1 to evaluate the whole set multiple times
+    reinsertion_batch_ratio: f32,
+    /// Below this depth a search distance of 1 will be used
+    search_depth_threshold: usize,
+    /// Maximum primitives per leaf. For CWBVH the limit is 3
+    max_prims_per_leaf: u32,
+    /// For BVH2 only, reinsertion after collapse relative to reinsertion_batch_ratio
+    post_collapse_reinsertion_batch_ratio_multiplier: f32,
+    /// Multiplier for traversal cost calculation during collapse. A higher value will result in more primitives per leaf.
+    collapse_traversal_cost: f32,
+    /// GPU traversal stack (registers + LDS entries) and workgroup size, - on the CPU
+    #[tabled(display_with = "display_gpu_kernel")]
+    gpu_kernel: Option<GpuKernelParams>,
+    /// Average of the traversal times for all the scene for these settings
+    avg_traversal_time: f32,
+    /// Average of the builds times for all the scene for these settings
+    avg_blas_build_time: f32,
+    avg_tlas_build_time: f32,
+    /// Average BVH memory in MB, NaN if not measured for the builder
+    avg_bvh_memory: f32,
+    /// Geometric mean over the scenes of the ratio to the best traversal time of each scene: worse than best is above 1
+    norm_best_traversal_time: f32,
+    /// Same as norm_best_traversal_time for the build times
+    norm_best_blas_build_time: f32,
+    norm_best_tlas_build_time: f32,
+    norm_best_bvh_memory: f32,
+    /// Weighted sum of the log of the normalized objectives with --tune-weights, lower is better
+    score: f32,
+}
+
+fn save_tuning_results_to_csv(
+    tuning_sets: &[TuningSet],
+    filename: &str,
+) -> Result<String, Box<dyn Error>> {
+    // Get the current date and time
+    let now: DateTime<Utc> = Utc::now();
+    let filename = format!("{}_{}.csv", filename, now.format("%Y-%m-%d_%H-%M-%S"));
+
+    // Create a CSV writer
+    let path = Path::new(&filename);
+    let file = File::create(path)?;
+    let mut wtr = csv::Writer::from_writer(file);
+
+    // Write the headers
+    wtr.write_record(&[
+        "build",
+        "target",
+        "split",
+        "search_distance",
+        "sort_precision",
+        "reinsertion_batch_ratio",
+        "search_depth_threshold",
+        "max_prims_per_leaf",
+        "post_collapse_reinsertion_batch_ratio_multiplier",
+        "collapse_traversal_cost",
+        "gpu_kernel",
+        "avg_traversal_time",
+        "avg_blas_build_time",
+        "avg_tlas_build_time",
+        "avg_bvh_memory",
+        "norm_best_traversal_time",
+        "norm_best_blas_build_time",
+        "norm_best_tlas_build_time",
+        "norm_best_bvh_memory",
+        "score",
+    ])?;
+
+    // Write the data
+    for tuning_set in tuning_sets {
+        wtr.write_record(&[
+            tuning_set.build.clone(),
+            tuning_set.target.clone(),
+            tuning_set.split.to_string(),
+            tuning_set.search_distance.to_string(),
+            tuning_set.sort_precision.to_string(),
+            tuning_set.reinsertion_batch_ratio.to_string(),
+            tuning_set.search_depth_threshold.to_string(),
+            tuning_set.max_prims_per_leaf.to_string(),
+            tuning_set
+                .post_collapse_reinsertion_batch_ratio_multiplier
+                .to_string(),
+            tuning_set.collapse_traversal_cost.to_string(),
+            display_gpu_kernel(&tuning_set.gpu_kernel),
+            tuning_set.avg_traversal_time.to_string(),
+            tuning_set.avg_blas_build_time.to_string(),
+            tuning_set.avg_tlas_build_time.to_string(),
+            tuning_set.avg_bvh_memory.to_string(),
+            tuning_set.norm_best_traversal_time.to_string(),
+            tuning_set.norm_best_blas_build_time.to_string(),
+            tuning_set.norm_best_tlas_build_time.to_string(),
+            tuning_set.norm_best_bvh_memory.to_string(),
+            tuning_set.score.to_string(),
+        ])?;
+    }
+
+    // Flush the writer to ensure all data is written to the file
+    wtr.flush()?;
+    Ok(filename)
+}
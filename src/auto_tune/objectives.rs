@@ -0,0 +1,174 @@
+use crate::Stats;
+
+/// Values below this (e.g. builds loaded from the BVH cache) are clamped so ratios and logarithms stay finite.
+const MIN_VALUE: f32 = 1e-6;
+
+/// Keeps NaN, `f32::max` would replace it.
+fn clamp_min(value: f32) -> f32 {
+    if value.is_nan() {
+        value
+    } else {
+        value.max(MIN_VALUE)
+    }
+}
+
+/// What auto-tune measures for each scene. Lower is better for all of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Objective {
+    Traversal,
+    BlasBuild,
+    TlasBuild,
+    Memory,
+}
+
+impl Objective {
+    pub const ALL: [Objective; 4] = [
+        Objective::Traversal,
+        Objective::BlasBuild,
+        Objective::TlasBuild,
+        Objective::Memory,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Objective::Traversal => "traversal",
+            Objective::BlasBuild => "blas_build",
+            Objective::TlasBuild => "tlas_build",
+            Objective::Memory => "memory",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Self {
+        Objective::ALL
+            .into_iter()
+            .find(|o| o.name() == name)
+            .unwrap_or_else(|| {
+                panic!(
+                    "Unknown tuning objective {}, expected traversal, blas_build, tlas_build or memory",
+                    name
+                )
+            })
+    }
+}
+
+/// One value per `Objective`, in the order of `Objective::ALL`.
+pub type Objectives = [f32; 4];
+
+/// Traversal ms, BLAS build s, TLAS build ms and BVH memory MB of each scene, without the "Avg" row. The memory of the
+/// builders that don't measure it (reported as 0) is NaN, so those results are left out of the memory objective.
+pub fn scene_objectives(stats: &[Stats]) -> Vec<(String, Objectives)> {
+    stats
+        .iter()
+        .filter(|s| s.name != "Avg")
+        .map(|s| {
+            (
+                s.name.clone(),
+                [
+                    s.traversal_ms,
+                    s.blas_build_time_s,
+                    s.tlas_build_time_ms,
+                    if s.bvh_memory_mb > 0.0 {
+                        s.bvh_memory_mb
+                    } else {
+                        f32::NAN
+                    },
+                ],
+            )
+        })
+        .collect()
+}
+
+pub fn arithmetic_mean(scenes: &[Objectives]) -> Objectives {
+    let len = scenes.len().max(1) as f32;
+    std::array::from_fn(|o| scenes.iter().map(|s| s[o]).sum::<f32>() / len)
+}
+
+pub fn geometric_mean(scenes: &[Objectives]) -> Objectives {
+    let len = scenes.len().max(1) as f32;
+    std::array::from_fn(|o| (scenes.iter().map(|s| clamp_min(s[o]).ln()).sum::<f32>() / len).exp())
+}
+
+/// For each result and objective, the geometric mean over the scenes of the ratio to the best value any result got on
+/// that scene. 1 is the best on every scene, and each scene counts the same no matter how long it takes. Unmeasured
+/// (NaN) values don't count for the best and stay NaN.
+pub fn normalize(results: &[Vec<Objectives>]) -> Vec<Objectives> {
+    let Some(first) = results.first() else {
+        return Vec::new();
+    };
+    let mut best = first.clone();
+    for scenes in results {
+        for (best, scene) in best.iter_mut().zip(scenes) {
+            for (best, value) in best.iter_mut().zip(scene) {
+                *best = best.min(*value);
+            }
+        }
+    }
+    results
+        .iter()
+        .map(|scenes| {
+            let ratios = scenes
+                .iter()
+                .zip(&best)
+                .map(|(scene, best)| {
+                    std::array::from_fn(|o| clamp_min(scene[o]) / clamp_min(best[o]))
+                })
+                .collect::<Vec<_>>();
+            geometric_mean(&ratios)
+        })
+        .collect()
+}
+
+/// Parses `--tune-weights`, like `traversal=1,memory=0.5`. Objectives that are left out get a weight of 0.
+pub fn parse_weights(weights: &str) -> Objectives {
+    let mut parsed = [0.0; 4];
+    for weight in weights.split(',').filter(|w| !w.is_empty()) {
+        let Some((name, value)) = weight.split_once('=') else {
+            panic!(
+                "Expected objective=weight in --tune-weights, got {}",
+                weight
+            )
+        };
+        parsed[Objective::from_name(name.trim()) as usize] = value
+            .trim()
+            .parse()
+            .unwrap_or_else(|e| panic!("Invalid weight {} for {}: {}", value, name, e));
+    }
+    parsed
+}
+
+pub fn parse_objectives(objectives: &str) -> Vec<Objective> {
+    objectives
+        .split(',')
+        .map(|name| Objective::from_name(name.trim()))
+        .collect()
+}
+
+/// Weighted sum of the log of each objective. With normalized values 0 means best on every scene in every weighted
+/// objective. Unmeasured (NaN) objectives are left out. Since the log of a geometric mean of ratios only differs by a
+/// constant from the log of the geometric mean of the raw values, the ranking is the same for both, so the search
+/// strategies can score with raw values.
+pub fn weighted_score(values: &Objectives, weights: &Objectives) -> f32 {
+    values
+        .iter()
+        .zip(weights)
+        .filter(|(v, w)| **w != 0.0 && !v.is_nan())
+        .map(|(v, w)| v.max(MIN_VALUE).ln() * w)
+        .sum()
+}
+
+/// Indices of the results no other result dominates, in the original order. A result dominates another if it's at
+/// least as good in all of the `objectives` and better in at least one. Objectives that are unmeasured (NaN) for either
+/// result aren't compared.
+pub fn pareto_front(values: &[Objectives], objectives: &[Objective]) -> Vec<usize> {
+    let dominates = |a: &Objectives, b: &Objectives| {
+        let compared = objectives
+            .iter()
+            .map(|o| (a[*o as usize], b[*o as usize]))
+            .filter(|(a, b)| !a.is_nan() && !b.is_nan())
+            .collect::<Vec<_>>();
+        compared.iter().all(|(a, b)| a <= b) && compared.iter().any(|(a, b)| a < b)
+    };
+    (0..values.len())
+        .filter(|i| !values.iter().any(|other| dominates(other, &values[*i])))
+        .collect()
+}
@@ -0,0 +1,372 @@
+use std::collections::{HashSet, VecDeque};
+
+use super::spec::SearchSpace;
+use crate::Options;
+
+/// Successive halving keeps the best 1/ETA of the configurations of each rung and gives them ETA times more
+/// render time.
+const HALVING_ETA: usize = 3;
+/// TPE samples randomly until it has this many full evaluations.
+const TPE_STARTUP: usize = 10;
+/// Fraction of the evaluations TPE treats as good.
+const TPE_GAMMA: f32 = 0.25;
+/// Candidates TPE draws from the good distribution for each proposal.
+const TPE_CANDIDATES: usize = 24;
+
+/// A point to evaluate. `fidelity` scales `--render-time`, only successive halving uses less than 1.
+pub struct Candidate {
+    pub indices: Vec<usize>,
+    pub fidelity: f32,
+}
+
+/// A finished evaluation. Lower scores are better.
+pub struct Evaluation {
+    pub indices: Vec<usize>,
+    pub fidelity: f32,
+    pub score: f32,
+}
+
+pub trait Strategy {
+    /// Next point to evaluate, `None` once the strategy has nothing left to try.
+    fn next(&mut self, history: &[Evaluation]) -> Option<Candidate>;
+}
+
+pub fn strategy_from_options(options: &Options, space: &SearchSpace) -> Box<dyn Strategy> {
+    let rng = Rng(options.tune_seed);
+    match options.tune_strategy.as_str() {
+        "grid" => Box::new(Grid {
+            space: space.clone(),
+            n: 0,
+        }),
+        "random" => Box::new(Random {
+            sampler: Sampler::new(space, options, rng),
+        }),
+        "coordinate" => Box::new(CoordinateDescent::new(space)),
+        "halving" => Box::new(SuccessiveHalving::new(space, options, rng)),
+        "tpe" => Box::new(Tpe {
+            sampler: Sampler::new(space, options, rng),
+            space: space.clone(),
+        }),
+        _ => panic!("Unknown tuning strategy {}", options.tune_strategy),
+    }
+}
+
+fn full_candidate(indices: Vec<usize>) -> Candidate {
+    Candidate {
+        indices,
+        fidelity: 1.0,
+    }
+}
+
+/// Score of the full fidelity evaluation of `indices`, if there is one.
+fn score_of(history: &[Evaluation], indices: &[usize]) -> Option<f32> {
+    history
+        .iter()
+        .find(|e| e.fidelity == 1.0 && e.indices == indices)
+        .map(|e| e.score)
+}
+
+/// Every permutation in order, same as the original nested loops.
+struct Grid {
+    space: SearchSpace,
+    n: usize,
+}
+
+impl Strategy for Grid {
+    fn next(&mut self, _history: &[Evaluation]) -> Option<Candidate> {
+        (self.n < self.space.len()).then(|| {
+            self.n += 1;
+            full_candidate(self.space.indices(self.n - 1))
+        })
+    }
+}
+
+/// SplitMix64, enough for sampling the search space and reproducible with `--tune-seed`.
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    fn unit(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+/// Draws valid points that have not been drawn before.
+struct Sampler {
+    space: SearchSpace,
+    options: Options,
+    lens: Vec<usize>,
+    total: usize,
+    seen: HashSet<Vec<usize>>,
+    rng: Rng,
+}
+
+impl Sampler {
+    fn new(space: &SearchSpace, options: &Options, rng: Rng) -> Self {
+        Sampler {
+            space: space.clone(),
+            options: options.clone(),
+            lens: space.dimensions.iter().map(|d| d.values.len()).collect(),
+            total: space.len(),
+            seen: HashSet::new(),
+            rng,
+        }
+    }
+
+    fn exhausted(&self) -> bool {
+        self.seen.len() >= self.total
+    }
+
+    /// Marks `indices` as drawn, false if it already was.
+    fn take(&mut self, indices: &[usize]) -> bool {
+        self.seen.insert(indices.to_vec())
+    }
+
+    /// Invalid points are marked as drawn and skipped.
+    fn random(&mut self) -> Option<Vec<usize>> {
+        while !self.exhausted() {
+            let indices = self
+                .lens
+                .clone()
+                .into_iter()
+                .map(|len| self.rng.below(len))
+                .collect::<Vec<_>>();
+            if self.take(&indices) && self.space.is_valid(&indices, &self.options) {
+                return Some(indices);
+            }
+        }
+        None
+    }
+}
+
+/// Uniform random sampling without repeats.
+struct Random {
+    sampler: Sampler,
+}
+
+impl Strategy for Random {
+    fn next(&mut self, _history: &[Evaluation]) -> Option<Candidate> {
+        self.sampler.random().map(full_candidate)
+    }
+}
+
+/// Starts in the middle of every dimension and tries all values of one dimension at a time, moving to the best one.
+/// Stops when a sweep over all dimensions doesn't improve the score.
+struct CoordinateDescent {
+    lens: Vec<usize>,
+    current: Vec<usize>,
+    current_score: Option<f32>,
+    dimension: usize,
+    improved: bool,
+    line: Vec<Vec<usize>>,
+    pending: VecDeque<Vec<usize>>,
+}
+
+impl CoordinateDescent {
+    fn new(space: &SearchSpace) -> Self {
+        let lens = space
+            .dimensions
+            .iter()
+            .map(|d| d.values.len())
+            .collect::<Vec<_>>();
+        let current = lens.iter().map(|len| len / 2).collect::<Vec<_>>();
+        CoordinateDescent {
+            pending: VecDeque::from([current.clone()]),
+            lens,
+            current,
+            current_score: None,
+            dimension: 0,
+            improved: false,
+            line: Vec::new(),
+        }
+    }
+}
+
+impl Strategy for CoordinateDescent {
+    fn next(&mut self, history: &[Evaluation]) -> Option<Candidate> {
+        loop {
+            while let Some(indices) = self.pending.pop_front() {
+                if score_of(history, &indices).is_none() {
+                    return Some(full_candidate(indices));
+                }
+            }
+            if self.current_score.is_none() {
+                self.current_score = score_of(history, &self.current);
+            } else {
+                // The line along the last dimension is done, move to its best point
+                for indices in self.line.drain(..) {
+                    let score = score_of(history, &indices);
+                    if let (Some(score), Some(current_score)) = (score, self.current_score) {
+                        if score < current_score {
+                            self.current = indices;
+                            self.current_score = Some(score);
+                            self.improved = true;
+                        }
+                    }
+                }
+                self.dimension += 1;
+                if self.dimension == self.lens.len() {
+                    if !self.improved {
+                        return None;
+                    }
+                    self.dimension = 0;
+                    self.improved = false;
+                }
+            }
+            if self.lens.is_empty() {
+                return None;
+            }
+            for value in 0..self.lens[self.dimension] {
+                if value != self.current[self.dimension] {
+                    let mut indices = self.current.clone();
+                    indices[self.dimension] = value;
+                    self.line.push(indices.clone());
+                    self.pending.push_back(indices);
+                }
+            }
+        }
+    }
+}
+
+/// Evaluates `--tune-halving-configs` random configurations with a fraction of the render time, then keeps the best
+/// 1/ETA for the next rung with ETA times more render time, until the last rung runs with the full render time.
+struct SuccessiveHalving {
+    rung: usize,
+    rungs: usize,
+    configs: Vec<Vec<usize>>,
+    pending: VecDeque<Vec<usize>>,
+}
+
+impl SuccessiveHalving {
+    fn new(space: &SearchSpace, options: &Options, rng: Rng) -> Self {
+        let mut sampler = Sampler::new(space, options, rng);
+        let configs = (0..options.tune_halving_configs.max(1))
+            .map_while(|_| sampler.random())
+            .collect::<Vec<_>>();
+        let mut rungs = 1;
+        while configs.len() >= HALVING_ETA.pow(rungs as u32) {
+            rungs += 1;
+        }
+        SuccessiveHalving {
+            rung: 0,
+            rungs,
+            pending: configs.iter().cloned().collect(),
+            configs,
+        }
+    }
+
+    fn fidelity(&self) -> f32 {
+        1.0 / HALVING_ETA.pow((self.rungs - 1 - self.rung) as u32) as f32
+    }
+}
+
+impl Strategy for SuccessiveHalving {
+    fn next(&mut self, history: &[Evaluation]) -> Option<Candidate> {
+        if self.pending.is_empty() {
+            if self.rung + 1 >= self.rungs {
+                return None;
+            }
+            let fidelity = self.fidelity();
+            let score = |indices: &Vec<usize>| {
+                history
+                    .iter()
+                    .find(|e| e.fidelity == fidelity && &e.indices == indices)
+                    .map_or(f32::MAX, |e| e.score)
+            };
+            self.configs.sort_by(|a, b| score(a).total_cmp(&score(b)));
+            self.configs
+                .truncate(self.configs.len().div_ceil(HALVING_ETA));
+            self.rung += 1;
+            self.pending = self.configs.iter().cloned().collect();
+        }
+        let fidelity = self.fidelity();
+        self.pending
+            .pop_front()
+            .map(|indices| Candidate { indices, fidelity })
+    }
+}
+
+/// Tree-structured Parzen Estimator over the discrete space. After some random samples the evaluations are split
+/// into good and bad by score, each gets a smoothed categorical distribution per dimension, and the candidate drawn
+/// from the good distribution with the highest good/bad likelihood ratio is evaluated next.
+struct Tpe {
+    space: SearchSpace,
+    sampler: Sampler,
+}
+
+impl Strategy for Tpe {
+    fn next(&mut self, history: &[Evaluation]) -> Option<Candidate> {
+        if history.len() < TPE_STARTUP {
+            return self.sampler.random().map(full_candidate);
+        }
+        let mut sorted = history.iter().collect::<Vec<_>>();
+        sorted.sort_by(|a, b| a.score.total_cmp(&b.score));
+        let good_count = ((sorted.len() as f32 * TPE_GAMMA).ceil() as usize).max(1);
+        let (good, bad) = sorted.split_at(good_count);
+
+        // Laplace smoothed value frequencies per dimension
+        let density = |evaluations: &[&Evaluation]| {
+            self.space
+                .dimensions
+                .iter()
+                .enumerate()
+                .map(|(d, dimension)| {
+                    let len = dimension.values.len();
+                    let mut counts = vec![1.0; len];
+                    for e in evaluations {
+                        counts[e.indices[d]] += 1.0;
+                    }
+                    let total = (evaluations.len() + len) as f32;
+                    counts.iter().map(|c| c / total).collect::<Vec<f32>>()
+                })
+                .collect::<Vec<_>>()
+        };
+        let (l, g) = (density(good), density(bad));
+
+        let mut best: Option<(f32, Vec<usize>)> = None;
+        for _ in 0..TPE_CANDIDATES {
+            let indices = l
+                .iter()
+                .map(|p| {
+                    // Sample the categorical distribution
+                    let mut u = self.sampler.rng.unit();
+                    p.iter()
+                        .position(|p| {
+                            u -= p;
+                            u <= 0.0
+                        })
+                        .unwrap_or(p.len() - 1)
+                })
+                .collect::<Vec<_>>();
+            if self.sampler.seen.contains(&indices) {
+                continue;
+            }
+            let ratio = indices
+                .iter()
+                .enumerate()
+                .map(|(d, i)| l[d][*i].ln() - g[d][*i].ln())
+                .sum::<f32>();
+            if best.as_ref().is_none_or(|(best, _)| ratio > *best) {
+                best = Some((ratio, indices));
+            }
+        }
+        match best {
+            Some((_, indices)) => {
+                self.sampler.take(&indices);
+                Some(full_candidate(indices))
+            }
+            None => self.sampler.random().map(full_candidate),
+        }
+    }
+}
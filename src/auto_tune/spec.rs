@@ -0,0 +1,355 @@
+use std::{fmt::Display, fs::File};
+
+use ron::de::from_reader;
+use serde::Deserialize;
+
+use crate::{
+    build_has_target, build_supports_tlas, rt_gpu::rt_gpu_software::rt_kernel_error, Options,
+};
+
+/// Parameters of the GPU software RT kernel. They don't change anything on the CPU.
+const GPU_KERNEL_PARAMS: [&str; 4] = [
+    "rt_vgpr_stack_size",
+    "rt_lds_stack_size",
+    "rt_group_width",
+    "rt_group_height",
+];
+
+/// Values to try for one parameter: an explicit `List([..])` or an inclusive `Range(start: .., end: .., step: ..)`.
+#[derive(Deserialize, Debug, Clone)]
+pub enum Values<T> {
+    List(Vec<T>),
+    Range { start: T, end: T, step: T },
+}
+
+/// Numeric parameters that can be given as a range.
+pub trait RangeValue: Copy {
+    fn to_f64(self) -> f64;
+    fn from_f64(v: f64) -> Self;
+}
+
+macro_rules! impl_range_value {
+    ($t:ty, $from:expr) => {
+        impl RangeValue for $t {
+            fn to_f64(self) -> f64 {
+                self as f64
+            }
+            fn from_f64(v: f64) -> Self {
+                $from(v) as $t
+            }
+        }
+    };
+}
+
+impl_range_value!(u8, f64::round);
+impl_range_value!(u32, f64::round);
+impl_range_value!(usize, f64::round);
+impl_range_value!(f32, std::convert::identity);
+
+impl<T: RangeValue> Values<T> {
+    pub fn expand(&self) -> Vec<T> {
+        match self {
+            Values::List(values) => values.clone(),
+            Values::Range { start, end, step } => {
+                let (start, end, step) = (start.to_f64(), end.to_f64(), step.to_f64());
+                if step <= 0.0 {
+                    panic!("Tuning spec range step must be positive");
+                }
+                // Small epsilon so float ranges like 0.1..=0.3 step 0.1 include the end
+                let count = ((end - start) / step + 1e-6).floor().max(-1.0) as i64 + 1;
+                (0..count)
+                    .map(|i| T::from_f64(start + step * i as f64))
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Search space for `--auto-tune`, loaded from `--tune-spec`. Each parameter maps to the command line option of the
+/// same name (and from there to `BvhBuildParams`). Parameters that are left out keep the value from the command line.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct TuneSpec {
+    /// Builders to compare, overrides `--build`.
+    pub build: Option<Vec<String>>,
+    /// `cpu` and/or `gpu` (software traversal), overrides `--cpu`.
+    pub target: Option<Vec<String>>,
+    pub split: Option<Vec<bool>>,
+    pub search_distance: Option<Values<u32>>,
+    pub sort_precision: Option<Values<u8>>,
+    pub reinsertion_batch_ratio: Option<Values<f32>>,
+    pub search_depth_threshold: Option<Values<usize>>,
+    pub max_prims_per_leaf: Option<Values<u32>>,
+    pub post_collapse_reinsertion_batch_ratio_multiplier: Option<Values<f32>>,
+    pub collapse_traversal_cost: Option<Values<f32>>,
+    pub rt_vgpr_stack_size: Option<Values<u32>>,
+    pub rt_lds_stack_size: Option<Values<u32>>,
+    pub rt_group_width: Option<Values<u32>>,
+    pub rt_group_height: Option<Values<u32>>,
+}
+
+/// One point in the search space.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TuneParams {
+    pub build: String,
+    pub cpu: bool,
+    pub split: bool,
+    pub search_distance: u32,
+    pub sort_precision: u8,
+    pub reinsertion_batch_ratio: f32,
+    pub search_depth_threshold: usize,
+    pub max_prims_per_leaf: u32,
+    pub post_collapse_reinsertion_batch_ratio_multiplier: f32,
+    pub collapse_traversal_cost: f32,
+    pub rt_vgpr_stack_size: u32,
+    pub rt_lds_stack_size: u32,
+    pub rt_group_width: u32,
+    pub rt_group_height: u32,
+}
+
+impl TuneParams {
+    pub fn from_options(options: &Options) -> Self {
+        TuneParams {
+            build: options.build.clone(),
+            cpu: options.cpu,
+            split: options.split,
+            search_distance: options.search_distance,
+            sort_precision: options.sort_precision,
+            reinsertion_batch_ratio: options.reinsertion_batch_ratio,
+            search_depth_threshold: options.search_depth_threshold,
+            max_prims_per_leaf: options.max_prims_per_leaf,
+            post_collapse_reinsertion_batch_ratio_multiplier: options
+                .post_collapse_reinsertion_batch_ratio_multiplier,
+            collapse_traversal_cost: options.collapse_traversal_cost,
+            rt_vgpr_stack_size: options.rt_vgpr_stack_size,
+            rt_lds_stack_size: options.rt_lds_stack_size,
+            rt_group_width: options.rt_group_width,
+            rt_group_height: options.rt_group_height,
+        }
+    }
+
+    pub fn apply(&self, options: &mut Options) {
+        options.build = self.build.clone();
+        options.cpu = self.cpu;
+        options.split = self.split;
+        options.search_distance = self.search_distance;
+        options.sort_precision = self.sort_precision;
+        options.reinsertion_batch_ratio = self.reinsertion_batch_ratio;
+        options.search_depth_threshold = self.search_depth_threshold;
+        options.max_prims_per_leaf = self.max_prims_per_leaf;
+        options.post_collapse_reinsertion_batch_ratio_multiplier =
+            self.post_collapse_reinsertion_batch_ratio_multiplier;
+        options.collapse_traversal_cost = self.collapse_traversal_cost;
+        options.rt_vgpr_stack_size = self.rt_vgpr_stack_size;
+        options.rt_lds_stack_size = self.rt_lds_stack_size;
+        options.rt_group_width = self.rt_group_width;
+        options.rt_group_height = self.rt_group_height;
+    }
+
+    /// False for combinations that can't be rendered: CWBVH with more than 3 primitives per leaf, builders without a
+    /// path for the target, builders without TLAS support with `--tlas` and GPU stack and workgroup sizes
+    /// `rt_kernel_error` rejects.
+    pub fn is_valid(&self, options: &Options) -> bool {
+        !(self.build.contains("cwbvh") && self.max_prims_per_leaf > 3)
+            && build_has_target(&self.build, self.cpu)
+            && (!options.tlas || build_supports_tlas(&self.build))
+            && (self.cpu
+                || rt_kernel_error(
+                    self.rt_vgpr_stack_size,
+                    self.rt_lds_stack_size,
+                    self.rt_group_width,
+                    self.rt_group_height,
+                )
+                .is_none())
+    }
+
+    /// Sets the numeric parameter with the given `TuneSpec` field name.
+    fn set(&mut self, name: &str, value: f64) {
+        match name {
+            "cpu" => self.cpu = value != 0.0,
+            "split" => self.split = value != 0.0,
+            "search_distance" => self.search_distance = value as u32,
+            "sort_precision" => self.sort_precision = value as u8,
+            "reinsertion_batch_ratio" => self.reinsertion_batch_ratio = value as f32,
+            "search_depth_threshold" => self.search_depth_threshold = value as usize,
+            "max_prims_per_leaf" => self.max_prims_per_leaf = value as u32,
+            "post_collapse_reinsertion_batch_ratio_multiplier" => {
+                self.post_collapse_reinsertion_batch_ratio_multiplier = value as f32
+            }
+            "collapse_traversal_cost" => self.collapse_traversal_cost = value as f32,
+            "rt_vgpr_stack_size" => self.rt_vgpr_stack_size = value as u32,
+            "rt_lds_stack_size" => self.rt_lds_stack_size = value as u32,
+            "rt_group_width" => self.rt_group_width = value as u32,
+            "rt_group_height" => self.rt_group_height = value as u32,
+            _ => panic!("Unknown tuning parameter {}", name),
+        }
+    }
+}
+
+/// A parameter the spec gives more than one value for. Values are stored as f64 (bools as 0 or 1, builders as their
+/// index) in the order of the spec.
+#[derive(Debug, Clone)]
+pub struct Dimension {
+    pub name: &'static str,
+    pub values: Vec<f64>,
+    /// The values as written in the spec, the builder names for `build`.
+    pub labels: Vec<String>,
+}
+
+impl Dimension {
+    fn apply(&self, index: usize, params: &mut TuneParams) {
+        match self.name {
+            "build" => params.build = self.labels[index].clone(),
+            name => params.set(name, self.values[index]),
+        }
+    }
+}
+
+/// The expanded spec. A point in the space is an index into the values of each dimension, parameters that are not a
+/// dimension come from `base`.
+#[derive(Debug, Clone)]
+pub struct SearchSpace {
+    pub base: TuneParams,
+    pub dimensions: Vec<Dimension>,
+}
+
+impl SearchSpace {
+    /// Number of permutations.
+    pub fn len(&self) -> usize {
+        self.dimensions.iter().map(|d| d.values.len()).product()
+    }
+
+    /// Indices of the nth permutation. The last dimension changes fastest.
+    pub fn indices(&self, mut n: usize) -> Vec<usize> {
+        let mut indices = vec![0; self.dimensions.len()];
+        for (index, dimension) in indices.iter_mut().zip(&self.dimensions).rev() {
+            *index = n % dimension.values.len();
+            n /= dimension.values.len();
+        }
+        indices
+    }
+
+    pub fn params(&self, indices: &[usize]) -> TuneParams {
+        let mut params = self.base.clone();
+        for (dimension, index) in self.dimensions.iter().zip(indices) {
+            dimension.apply(*index, &mut params);
+        }
+        params
+    }
+
+    /// `TuneParams::is_valid`, and on the CPU only the first value of the GPU kernel parameters since the others would
+    /// repeat the same evaluation.
+    pub fn is_valid(&self, indices: &[usize], options: &Options) -> bool {
+        let params = self.params(indices);
+        params.is_valid(options)
+            && !(params.cpu
+                && self
+                    .dimensions
+                    .iter()
+                    .zip(indices)
+                    .any(|(d, i)| GPU_KERNEL_PARAMS.contains(&d.name) && *i != 0))
+    }
+
+    /// Number of permutations `is_valid` accepts.
+    pub fn valid_len(&self, options: &Options) -> usize {
+        (0..self.len())
+            .filter(|n| self.is_valid(&self.indices(*n), options))
+            .count()
+    }
+}
+
+impl TuneSpec {
+    pub fn load(path: &str) -> Self {
+        let f = File::open(path).expect("Failed opening tuning spec");
+        match from_reader(f) {
+            Ok(spec) => spec,
+            Err(e) => panic!("Failed to load tuning spec {}: {}", path, e),
+        }
+    }
+
+    /// Expands the ranges. Parameters that are not in the spec take their value from `options`.
+    pub fn search_space(&self, options: &Options) -> SearchSpace {
+        fn expand<T: RangeValue + Display>(
+            values: &Option<Values<T>>,
+        ) -> Option<(Vec<f64>, Vec<String>)> {
+            values.as_ref().map(|v| {
+                let values = v.expand();
+                (
+                    values.iter().map(|v| v.to_f64()).collect(),
+                    values.iter().map(|v| v.to_string()).collect(),
+                )
+            })
+        }
+        let builds = self.build.as_ref().map(|builds| {
+            (
+                (0..builds.len()).map(|i| i as f64).collect(),
+                builds.clone(),
+            )
+        });
+        let targets = self.target.as_ref().map(|targets| {
+            let cpu = targets.iter().map(|target| match target.as_str() {
+                "cpu" => 1.0,
+                "gpu" => 0.0,
+                _ => panic!("Unknown tuning target {}, expected cpu or gpu", target),
+            });
+            (cpu.collect(), targets.clone())
+        });
+        let splits = self.split.as_ref().map(|v| {
+            (
+                v.iter().map(|s| *s as u8 as f64).collect(),
+                v.iter().map(|s| s.to_string()).collect(),
+            )
+        });
+        let dimensions = [
+            ("build", builds),
+            ("cpu", targets),
+            ("split", splits),
+            ("search_distance", expand(&self.search_distance)),
+            ("sort_precision", expand(&self.sort_precision)),
+            (
+                "reinsertion_batch_ratio",
+                expand(&self.reinsertion_batch_ratio),
+            ),
+            (
+                "search_depth_threshold",
+                expand(&self.search_depth_threshold),
+            ),
+            ("max_prims_per_leaf", expand(&self.max_prims_per_leaf)),
+            (
+                "post_collapse_reinsertion_batch_ratio_multiplier",
+                expand(&self.post_collapse_reinsertion_batch_ratio_multiplier),
+            ),
+            (
+                "collapse_traversal_cost",
+                expand(&self.collapse_traversal_cost),
+            ),
+            ("rt_vgpr_stack_size", expand(&self.rt_vgpr_stack_size)),
+            ("rt_lds_stack_size", expand(&self.rt_lds_stack_size)),
+            ("rt_group_width", expand(&self.rt_group_width)),
+            ("rt_group_height", expand(&self.rt_group_height)),
+        ];
+
+        let mut base = TuneParams::from_options(options);
+        let mut space_dimensions = Vec::new();
+        for (name, values) in dimensions {
+            let Some((values, labels)) = values else {
+                continue;
+            };
+            let dimension = Dimension {
+                name,
+                values,
+                labels,
+            };
+            match dimension.values.len() {
+                0 => panic!("Tuning spec has no values for {}", name),
+                // Single values are not worth a dimension
+                1 => dimension.apply(0, &mut base),
+                _ => space_dimensions.push(dimension),
+            }
+        }
+        SearchSpace {
+            base,
+            dimensions: space_dimensions,
+        }
+    }
+}
@@ -0,0 +1,258 @@
+use std::time::{Duration, Instant};
+
+use glam::{Mat4, Vec3A, Vec4};
+use obvhs::{
+    aabb::Aabb,
+    bvh2::Bvh2,
+    ray::{Ray, RayHit},
+};
+use traversable::{Intersectable, SceneRtTri, Traversable};
+
+/// Stack size traversal uses without allocating. Deeper trees get a stack sized from `Bvh4::stack_size`.
+const TRAVERSAL_STACK_SIZE: usize = 256;
+
+/// 4 wide node with the child bounds in SoA layout, so one ray can be tested against all children at once. glam's
+/// `Vec4` is an `__m128` on x86_64, so the box tests compile to SSE.
+#[derive(Clone, Copy)]
+#[repr(C, align(64))]
+pub struct Bvh4Node {
+    /// Child bounds per axis, one lane per child. Empty lanes have inverted bounds so they are never hit.
+    pub min: [Vec4; 3],
+    pub max: [Vec4; 3],
+    /// Node index for inner children, first index into `primitive_indices` for leaves.
+    pub child: [u32; 4],
+    /// Number of primitives for leaf children, 0 for inner and empty children.
+    pub prim_count: [u32; 4],
+}
+
+impl Default for Bvh4Node {
+    fn default() -> Self {
+        Bvh4Node {
+            min: [Vec4::INFINITY; 3],
+            max: [Vec4::NEG_INFINITY; 3],
+            child: [u32::MAX; 4],
+            prim_count: [0; 4],
+        }
+    }
+}
+
+impl Bvh4Node {
+    fn set_child_aabb(&mut self, lane: usize, aabb: &Aabb) {
+        for axis in 0..3 {
+            self.min[axis][lane] = aabb.min[axis];
+            self.max[axis][lane] = aabb.max[axis];
+        }
+    }
+
+    pub fn child_aabb(&self, lane: usize) -> Aabb {
+        Aabb::new(
+            Vec3A::new(self.min[0][lane], self.min[1][lane], self.min[2][lane]),
+            Vec3A::new(self.max[0][lane], self.max[1][lane], self.max[2][lane]),
+        )
+    }
+
+    pub fn child_is_empty(&self, lane: usize) -> bool {
+        self.child[lane] == u32::MAX
+    }
+
+    pub fn child_is_inner(&self, lane: usize) -> bool {
+        !self.child_is_empty(lane) && self.prim_count[lane] == 0
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Bvh4 {
+    pub nodes: Vec<Bvh4Node>,
+    /// Same order as the `Bvh2` it was collapsed from.
+    pub primitive_indices: Vec<u32>,
+    /// Most entries the traversal stack can hold, each level below the root adds up to 3.
+    pub stack_size: usize,
+}
+
+impl Bvh4 {
+    /// Collapses a `Bvh2` by repeatedly opening the inner child with the largest surface area until each node has 4
+    /// children or only leaves are left. Leaves keep their primitive ranges.
+    pub fn from_bvh2(bvh2: &Bvh2, core_build_time: &mut Duration) -> Self {
+        let start_time = Instant::now();
+        let mut bvh4 = Bvh4 {
+            nodes: Vec::with_capacity(bvh2.nodes.len() / 3 + 1),
+            primitive_indices: bvh2.primitive_indices.clone(),
+            stack_size: 0,
+        };
+        if !bvh2.nodes.is_empty() {
+            bvh4.nodes.push(Bvh4Node::default());
+            let depth = bvh4.collapse(bvh2, 0, 0);
+            bvh4.stack_size = 3 * depth + 1;
+        }
+        *core_build_time += start_time.elapsed();
+        bvh4
+    }
+
+    /// Returns the depth of the collapsed subtree in nodes.
+    fn collapse(&mut self, bvh2: &Bvh2, bvh2_index: usize, node_index: usize) -> usize {
+        let bvh2_node = &bvh2.nodes[bvh2_index];
+        let mut children = Vec::with_capacity(4);
+        if bvh2_node.is_leaf() {
+            // Only happens for the root
+            children.push(bvh2_index);
+        } else {
+            let first = bvh2_node.first_index as usize;
+            children.extend([first, first + 1]);
+            while children.len() < 4 {
+                let Some((i, _)) = children
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, c)| !bvh2.nodes[**c].is_leaf())
+                    .max_by(|(_, a), (_, b)| {
+                        let area = |c: &usize| bvh2.nodes[*c].aabb.half_area();
+                        area(a).total_cmp(&area(b))
+                    })
+                else {
+                    break;
+                };
+                let first = bvh2.nodes[children.swap_remove(i)].first_index as usize;
+                children.extend([first, first + 1]);
+            }
+        }
+
+        let mut node = Bvh4Node::default();
+        let mut inner = Vec::with_capacity(4);
+        for (lane, c) in children.iter().enumerate() {
+            let child = &bvh2.nodes[*c];
+            node.set_child_aabb(lane, &child.aabb);
+            if child.is_leaf() {
+                node.child[lane] = child.first_index;
+                node.prim_count[lane] = child.prim_count;
+            } else {
+                // Reserve the child nodes first so siblings end up next to each other
+                node.child[lane] = self.nodes.len() as u32;
+                inner.push((*c, self.nodes.len()));
+                self.nodes.push(Bvh4Node::default());
+            }
+        }
+        self.nodes[node_index] = node;
+        1 + inner
+            .into_iter()
+            .map(|(bvh2_index, node_index)| self.collapse(bvh2, bvh2_index, node_index))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Same interface as `Bvh2::ray_traverse`. `intersection_fn` is called with the index into `primitive_indices`,
+    /// which is also what ends up in `hit.primitive_id`.
+    #[inline(always)]
+    pub fn ray_traverse<F: FnMut(&Ray, usize) -> f32>(
+        &self,
+        ray: Ray,
+        hit: &mut RayHit,
+        intersection_fn: F,
+    ) -> bool {
+        if self.nodes.is_empty() {
+            return false;
+        }
+        if self.stack_size <= TRAVERSAL_STACK_SIZE {
+            let mut stack = [(0u32, 0.0f32); TRAVERSAL_STACK_SIZE];
+            self.ray_traverse_with_stack(&mut stack, ray, hit, intersection_fn)
+        } else {
+            let mut stack = vec![(0u32, 0.0f32); self.stack_size];
+            self.ray_traverse_with_stack(&mut stack, ray, hit, intersection_fn)
+        }
+    }
+
+    /// `stack` needs at least `stack_size` entries.
+    #[inline(always)]
+    fn ray_traverse_with_stack<F: FnMut(&Ray, usize) -> f32>(
+        &self,
+        stack: &mut [(u32, f32)],
+        mut ray: Ray,
+        hit: &mut RayHit,
+        mut intersection_fn: F,
+    ) -> bool {
+        // Pick the near and far planes by ray direction once, so inverted (empty) lanes always miss.
+        let negative = ray.inv_direction.cmplt(Vec3A::ZERO).bitmask();
+        let origin = [0, 1, 2].map(|axis| Vec4::splat(ray.origin[axis]));
+        let inv_direction = [0, 1, 2].map(|axis| Vec4::splat(ray.inv_direction[axis]));
+
+        let mut found = false;
+        let mut stack_len = 1;
+        stack[0] = (0, ray.tmin);
+        while stack_len > 0 {
+            stack_len -= 1;
+            let (node_index, t) = stack[stack_len];
+            if t >= ray.tmax {
+                continue;
+            }
+            let node = &self.nodes[node_index as usize];
+
+            let mut t_near = Vec4::splat(ray.tmin);
+            let mut t_far = Vec4::splat(ray.tmax);
+            for axis in 0..3 {
+                let (near, far) = if negative & (1 << axis) != 0 {
+                    (node.max[axis], node.min[axis])
+                } else {
+                    (node.min[axis], node.max[axis])
+                };
+                t_near = t_near.max((near - origin[axis]) * inv_direction[axis]);
+                t_far = t_far.min((far - origin[axis]) * inv_direction[axis]);
+            }
+            let mut mask = t_near.cmple(t_far).bitmask();
+            let t_near = t_near.to_array();
+
+            // Leaves are intersected right away, inner children are pushed far to near
+            let mut inner = [(0u32, 0.0f32); 4];
+            let mut inner_len = 0;
+            while mask != 0 {
+                let lane = mask.trailing_zeros() as usize;
+                mask &= mask - 1;
+                let prim_count = node.prim_count[lane] as usize;
+                if prim_count == 0 {
+                    inner[inner_len] = (node.child[lane], t_near[lane]);
+                    inner_len += 1;
+                    continue;
+                }
+                let first = node.child[lane] as usize;
+                for primitive_id in first..first + prim_count {
+                    let t = intersection_fn(&ray, primitive_id);
+                    if t < ray.tmax {
+                        hit.primitive_id = primitive_id as u32;
+                        hit.t = t;
+                        ray.tmax = t;
+                        found = true;
+                    }
+                }
+            }
+            let inner = &mut inner[..inner_len];
+            inner.sort_unstable_by(|a, b| b.1.total_cmp(&a.1));
+            stack[stack_len..stack_len + inner_len].copy_from_slice(inner);
+            stack_len += inner_len;
+        }
+        found
+    }
+}
+
+pub struct Bvh4Scene<'a> {
+    pub bvh: &'a Bvh4,
+    pub tris: &'a [SceneRtTri],
+}
+
+impl Traversable for Bvh4Scene<'_> {
+    type Primitive = SceneRtTri;
+
+    #[inline(always)]
+    fn traverse(&self, ray: Ray) -> RayHit {
+        let mut hit = RayHit::none();
+        self.bvh
+            .ray_traverse(ray, &mut hit, |ray, id| self.tris[id].intersect(ray));
+        hit
+    }
+
+    #[inline(always)]
+    fn get_primitive(&self, _geometry_id: u32, primitive_id: u32) -> &SceneRtTri {
+        &self.tris[primitive_id as usize]
+    }
+
+    #[inline(always)]
+    fn get_instance_transform(&self, _instance_id: u32) -> Mat4 {
+        Mat4::default()
+    }
+}
@@ -0,0 +1,329 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use bytemuck::Zeroable;
+use glam::Vec3A;
+use obvhs::{
+    aabb::Aabb,
+    bvh2::{node::Bvh2Node, Bvh2},
+    cwbvh::{node::CwBvhNode, CwBvh},
+    triangle::Triangle,
+};
+
+use crate::{build_params_from_options, sweep_sah::SweepSahParams, Options};
+
+/// Bump when the file layout or anything that affects the built BVHs changes (e.g. an obvhs update) so old cache
+/// entries are ignored.
+pub const BVH_CACHE_VERSION: u32 = 1;
+const MAGIC: [u8; 4] = *b"TRBV";
+const KIND_BVH2: u32 = 1;
+const KIND_CWBVH: u32 = 2;
+
+/// Loads the CwBvh for these triangles from `--bvh-cache` if it was built before with the same builder and
+/// `BvhBuildParams`, otherwise builds it with `build` and saves it. Does nothing if `--bvh-cache` isn't set.
+pub fn cached_cwbvh(
+    triangles: &[Triangle],
+    options: &Options,
+    build: impl FnOnce() -> CwBvh,
+) -> CwBvh {
+    if options.bvh_cache.is_empty() {
+        return build();
+    }
+    let key = cache_key(triangles, options, KIND_CWBVH);
+    let path = cache_path(options, "cwbvh", key);
+    match load_cwbvh(&path, key) {
+        Ok(bvh) => {
+            if options.verbose {
+                println!("Loaded BVH from {}", path.display());
+            }
+            return bvh;
+        }
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            println!("Ignoring BVH cache entry {}: {}", path.display(), e)
+        }
+        Err(_) => (),
+    }
+    let bvh = build();
+    if let Err(e) = write_atomic(&path, |w| save_cwbvh(w, &bvh, key)) {
+        println!("Failed to save BVH to {}: {}", path.display(), e);
+    }
+    bvh
+}
+
+/// Same as `cached_cwbvh` for Bvh2.
+pub fn cached_bvh2(
+    triangles: &[Triangle],
+    options: &Options,
+    build: impl FnOnce() -> Bvh2,
+) -> Bvh2 {
+    if options.bvh_cache.is_empty() {
+        return build();
+    }
+    let key = cache_key(triangles, options, KIND_BVH2);
+    let path = cache_path(options, "bvh2", key);
+    match load_bvh2(&path, key) {
+        Ok(bvh) => {
+            if options.verbose {
+                println!("Loaded BVH from {}", path.display());
+            }
+            return bvh;
+        }
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            println!("Ignoring BVH cache entry {}: {}", path.display(), e)
+        }
+        Err(_) => (),
+    }
+    let bvh = build();
+    if let Err(e) = write_atomic(&path, |w| save_bvh2(w, &bvh, key)) {
+        println!("Failed to save BVH to {}: {}", path.display(), e);
+    }
+    bvh
+}
+
+/// FNV-1a hash of the triangles, the builder and the build params.
+pub fn cache_key(triangles: &[Triangle], options: &Options, kind: u32) -> u64 {
+    let mut hash = Fnv1a::new();
+    hash.write(&BVH_CACHE_VERSION.to_le_bytes());
+    hash.write(&kind.to_le_bytes());
+    hash.write(options.build.as_bytes());
+    hash.write(format!("{:?}", build_params_from_options(options)).as_bytes());
+    // The SAH costs only change the sweep SAH and SBVH builds
+    if options.build.starts_with("sweep_sah_") || options.build.starts_with("sbvh_") {
+        hash.write(format!("{:?}", SweepSahParams::from_options(options)).as_bytes());
+    }
+    // Embree builds depend on the ISA and the device config
+    if options.build.starts_with("embree") {
+        for s in [&options.embree_isa, &options.embree_config] {
+            hash.write(&(s.len() as u64).to_le_bytes());
+            hash.write(s.as_bytes());
+        }
+    }
+    hash.write(&(triangles.len() as u64).to_le_bytes());
+    for tri in triangles {
+        for v in [tri.v0, tri.v1, tri.v2] {
+            for c in v.to_array() {
+                hash.write(&c.to_le_bytes());
+            }
+        }
+    }
+    hash.finish()
+}
+
+fn cache_path(options: &Options, kind: &str, key: u64) -> PathBuf {
+    Path::new(&options.bvh_cache).join(format!("{}_{:016x}.bvh", kind, key))
+}
+
+/// Also keys the SPIR-V in `--shader-cache`.
+pub struct Fnv1a(u64);
+
+impl Fnv1a {
+    pub fn new() -> Self {
+        Fnv1a(0xcbf29ce484222325)
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Writes to a temporary file first so an interrupted run never leaves a truncated cache entry behind. The temporary
+/// file is unique per process and write, `--parallel-blas` can write the same entry from several threads at once.
+fn write_atomic(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    static WRITE_COUNT: AtomicU64 = AtomicU64::new(0);
+    let tmp_path = path.with_extension(format!(
+        "{}_{}.tmp",
+        process::id(),
+        WRITE_COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut w = BufWriter::new(File::create(&tmp_path)?);
+    write(&mut w)?;
+    w.flush()?;
+    drop(w);
+    fs::rename(tmp_path, path)
+}
+
+// File layout, little endian:
+// magic "TRBV", version u32, kind u32, key u64, then the BVH:
+// Bvh2:  uses_spatial_splits u32, node count u64, nodes (aabb, prim_count u32, first_index u32), primitive indices
+// CwBvh: uses_spatial_splits u32, total_aabb, node count u64, nodes (80 bytes, copied as is like for the GPU),
+//        primitive indices, exact_node_aabbs count u64 (u64::MAX if None), exact_node_aabbs
+// Primitive indices are a count u64 followed by the u32 indices. Aabbs are min xyz, max xyz as f32.
+
+pub fn save_bvh2(w: &mut impl Write, bvh: &Bvh2, key: u64) -> io::Result<()> {
+    write_header(w, KIND_BVH2, key)?;
+    write_u32(w, bvh.uses_spatial_splits as u32)?;
+    write_u64(w, bvh.nodes.len() as u64)?;
+    for node in &bvh.nodes {
+        write_aabb(w, &node.aabb)?;
+        write_u32(w, node.prim_count)?;
+        write_u32(w, node.first_index)?;
+    }
+    write_indices(w, &bvh.primitive_indices)
+}
+
+pub fn load_bvh2(path: &Path, key: u64) -> io::Result<Bvh2> {
+    let r = &mut BufReader::new(File::open(path)?);
+    read_header(r, KIND_BVH2, key)?;
+    let uses_spatial_splits = read_u32(r)? != 0;
+    let node_count = read_u64(r)? as usize;
+    let mut nodes = Vec::with_capacity(node_count);
+    for _ in 0..node_count {
+        let aabb = read_aabb(r)?;
+        let prim_count = read_u32(r)?;
+        let first_index = read_u32(r)?;
+        nodes.push(Bvh2Node::new(aabb, prim_count, first_index));
+    }
+    let primitive_indices = read_indices(r)?;
+    Ok(Bvh2 {
+        nodes,
+        primitive_indices,
+        uses_spatial_splits,
+        ..Default::default()
+    })
+}
+
+pub fn save_cwbvh(w: &mut impl Write, bvh: &CwBvh, key: u64) -> io::Result<()> {
+    write_header(w, KIND_CWBVH, key)?;
+    write_u32(w, bvh.uses_spatial_splits as u32)?;
+    write_aabb(w, &bvh.total_aabb)?;
+    write_u64(w, bvh.nodes.len() as u64)?;
+    w.write_all(bytemuck::cast_slice(&bvh.nodes))?;
+    write_indices(w, &bvh.primitive_indices)?;
+    match &bvh.exact_node_aabbs {
+        Some(aabbs) => {
+            write_u64(w, aabbs.len() as u64)?;
+            for aabb in aabbs {
+                write_aabb(w, aabb)?;
+            }
+        }
+        None => write_u64(w, u64::MAX)?,
+    }
+    Ok(())
+}
+
+pub fn load_cwbvh(path: &Path, key: u64) -> io::Result<CwBvh> {
+    let r = &mut BufReader::new(File::open(path)?);
+    read_header(r, KIND_CWBVH, key)?;
+    let uses_spatial_splits = read_u32(r)? != 0;
+    let total_aabb = read_aabb(r)?;
+    let node_count = read_u64(r)? as usize;
+    let mut nodes = vec![CwBvhNode::zeroed(); node_count];
+    r.read_exact(bytemuck::cast_slice_mut(&mut nodes))?;
+    let primitive_indices = read_indices(r)?;
+    let exact_node_aabbs = match read_u64(r)? {
+        u64::MAX => None,
+        count => Some(
+            (0..count)
+                .map(|_| read_aabb(r))
+                .collect::<io::Result<Vec<_>>>()?,
+        ),
+    };
+    Ok(CwBvh {
+        nodes,
+        primitive_indices,
+        total_aabb,
+        exact_node_aabbs,
+        uses_spatial_splits,
+    })
+}
+
+fn write_header(w: &mut impl Write, kind: u32, key: u64) -> io::Result<()> {
+    w.write_all(&MAGIC)?;
+    write_u32(w, BVH_CACHE_VERSION)?;
+    write_u32(w, kind)?;
+    write_u64(w, key)
+}
+
+fn read_header(r: &mut impl Read, kind: u32, key: u64) -> io::Result<()> {
+    let mut magic = [0u8; 4];
+    r.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(invalid_data("not a BVH cache file"));
+    }
+    let version = read_u32(r)?;
+    if version != BVH_CACHE_VERSION {
+        return Err(invalid_data(&format!(
+            "version {} (expected {})",
+            version, BVH_CACHE_VERSION
+        )));
+    }
+    if read_u32(r)? != kind {
+        return Err(invalid_data("wrong BVH type"));
+    }
+    if read_u64(r)? != key {
+        return Err(invalid_data("key mismatch"));
+    }
+    Ok(())
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn write_u32(w: &mut impl Write, v: u32) -> io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+
+fn write_u64(w: &mut impl Write, v: u64) -> io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+
+fn write_aabb(w: &mut impl Write, aabb: &Aabb) -> io::Result<()> {
+    for c in aabb.min.to_array().into_iter().chain(aabb.max.to_array()) {
+        w.write_all(&c.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn write_indices(w: &mut impl Write, indices: &[u32]) -> io::Result<()> {
+    write_u64(w, indices.len() as u64)?;
+    for i in indices {
+        write_u32(w, *i)?;
+    }
+    Ok(())
+}
+
+fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    r.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    r.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_f32(r: &mut impl Read) -> io::Result<f32> {
+    Ok(f32::from_bits(read_u32(r)?))
+}
+
+fn read_aabb(r: &mut impl Read) -> io::Result<Aabb> {
+    let min = Vec3A::new(read_f32(r)?, read_f32(r)?, read_f32(r)?);
+    let max = Vec3A::new(read_f32(r)?, read_f32(r)?, read_f32(r)?);
+    Ok(Aabb::new(min, max))
+}
+
+fn read_indices(r: &mut impl Read) -> io::Result<Vec<u32>> {
+    let count = read_u64(r)? as usize;
+    (0..count).map(|_| read_u32(r)).collect()
+}
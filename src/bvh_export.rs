@@ -0,0 +1,178 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use glam::{Vec3, Vec3A};
+use obvhs::aabb::Aabb;
+
+use crate::{
+    metrics::{node_cost, MetricsTree, SahCosts},
+    rt_cpu::rt_cpu::temperature,
+    Options,
+};
+
+/// Writes the node bounds selected by the `--export-*` options as a wireframe with 8 vertices and 12 edges per node.
+/// Vertices are coloured by depth or by SAH contribution so the tree can be inspected overlaid on the scene, e.g. in
+/// Blender.
+pub fn export_bvh_wireframe(tree: &MetricsTree, options: &Options, scene_name: &str) {
+    if tree.nodes.is_empty() {
+        return;
+    }
+
+    let mut root = tree.root;
+    let mut root_depth = 0;
+    for slot in options
+        .export_subtree
+        .split(",")
+        .filter(|s| !s.trim().is_empty())
+    {
+        let slot = slot
+            .trim()
+            .parse::<usize>()
+            .expect("Invalid --export-subtree slot");
+        let node = &tree.nodes[root as usize];
+        if node.is_leaf {
+            panic!(
+                "--export-subtree continues past a leaf at depth {}",
+                root_depth
+            );
+        }
+        root = *tree.node_children(node).get(slot).unwrap_or_else(|| {
+            panic!(
+                "--export-subtree slot {} at depth {} is out of range, the node has {} children",
+                slot, root_depth, node.count
+            )
+        });
+        root_depth += 1;
+    }
+
+    let costs = SahCosts::from_options(options);
+    let root_area = tree.nodes[tree.root as usize].aabb.half_area();
+    let max_depth = options.export_max_depth.unwrap_or(usize::MAX);
+    let mut boxes = Vec::new();
+    let mut stack = vec![(root, root_depth)];
+    while let Some((node_index, depth)) = stack.pop() {
+        let node = &tree.nodes[node_index as usize];
+        if depth > max_depth {
+            continue;
+        }
+        if depth >= options.export_min_depth && (node.is_leaf || !options.export_leaves_only) {
+            let value = match options.export_color.as_str() {
+                "sah" => node.aabb.half_area() / root_area * node_cost(node, &costs),
+                _ => depth as f32,
+            };
+            boxes.push((node.aabb, value));
+        }
+        if !node.is_leaf {
+            stack.extend(tree.node_children(node).iter().map(|c| (*c, depth + 1)));
+        }
+    }
+
+    // SAH contributions span several orders of magnitude so they are coloured on a log scale.
+    let to_scale = |value: f32| match options.export_color.as_str() {
+        "sah" => value.max(1e-12).log10(),
+        _ => value,
+    };
+    let (min_value, max_value) = boxes.iter().fold((f32::MAX, f32::MIN), |(min, max), b| {
+        (min.min(to_scale(b.1)), max.max(to_scale(b.1)))
+    });
+    let range = (max_value - min_value).max(f32::MIN_POSITIVE);
+    let boxes = boxes
+        .into_iter()
+        .map(|(aabb, value)| (aabb, temperature((to_scale(value) - min_value) / range)))
+        .collect::<Vec<_>>();
+
+    let path = export_path(options, scene_name);
+    let mut w = BufWriter::new(File::create(&path).expect("Failed to create BVH export file"));
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("obj") => write_obj(&mut w, &boxes),
+        Some("ply") => write_ply(&mut w, &boxes),
+        _ => panic!("--export-bvh needs to be an .obj or .ply file"),
+    }
+    .and_then(|_| w.flush())
+    .expect("Failed to write BVH export");
+    println!("Exported {} BVH nodes to {}", boxes.len(), path.display());
+}
+
+fn export_path(options: &Options, scene_name: &str) -> PathBuf {
+    let path = Path::new(&options.export_bvh);
+    if !options.input.contains(",") {
+        return path.to_path_buf();
+    }
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("bvh");
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    path.with_file_name(format!("{}_{}.{}", stem, scene_name, extension))
+}
+
+fn box_corners(aabb: &Aabb) -> [Vec3A; 8] {
+    std::array::from_fn(|i| {
+        Vec3A::new(
+            if i & 1 == 0 { aabb.min.x } else { aabb.max.x },
+            if i & 2 == 0 { aabb.min.y } else { aabb.max.y },
+            if i & 4 == 0 { aabb.min.z } else { aabb.max.z },
+        )
+    })
+}
+
+/// Pairs of corners that differ along one axis.
+fn box_edges() -> impl Iterator<Item = (usize, usize)> {
+    (0..8).flat_map(|i| {
+        [1, 2, 4]
+            .into_iter()
+            .filter(move |bit| i & bit == 0)
+            .map(move |bit| (i, i | bit))
+    })
+}
+
+/// OBJ with vertex colours (`v x y z r g b`) and `l` line elements.
+fn write_obj(w: &mut impl Write, boxes: &[(Aabb, Vec3)]) -> io::Result<()> {
+    writeln!(w, "# tray_racing BVH export, {} nodes", boxes.len())?;
+    for (aabb, color) in boxes {
+        for v in box_corners(aabb) {
+            writeln!(
+                w,
+                "v {} {} {} {} {} {}",
+                v.x, v.y, v.z, color.x, color.y, color.z
+            )?;
+        }
+    }
+    for i in 0..boxes.len() {
+        for (a, b) in box_edges() {
+            // OBJ indices start at 1
+            writeln!(w, "l {} {}", i * 8 + a + 1, i * 8 + b + 1)?;
+        }
+    }
+    Ok(())
+}
+
+/// ASCII PLY with vertex colours and an edge element.
+fn write_ply(w: &mut impl Write, boxes: &[(Aabb, Vec3)]) -> io::Result<()> {
+    writeln!(w, "ply")?;
+    writeln!(w, "format ascii 1.0")?;
+    writeln!(w, "comment tray_racing BVH export")?;
+    writeln!(w, "element vertex {}", boxes.len() * 8)?;
+    for axis in ["x", "y", "z"] {
+        writeln!(w, "property float {}", axis)?;
+    }
+    for channel in ["red", "green", "blue"] {
+        writeln!(w, "property uchar {}", channel)?;
+    }
+    writeln!(w, "element edge {}", boxes.len() * 12)?;
+    writeln!(w, "property int vertex1")?;
+    writeln!(w, "property int vertex2")?;
+    writeln!(w, "end_header")?;
+    for (aabb, color) in boxes {
+        let c = (*color * 255.0).round().as_uvec3();
+        for v in box_corners(aabb) {
+            writeln!(w, "{} {} {} {} {} {}", v.x, v.y, v.z, c.x, c.y, c.z)?;
+        }
+    }
+    for i in 0..boxes.len() {
+        for (a, b) in box_edges() {
+            writeln!(w, "{} {}", i * 8 + a, i * 8 + b)?;
+        }
+    }
+    Ok(())
+}
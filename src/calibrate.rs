@@ -0,0 +1,296 @@
+use std::{collections::HashMap, error::Error, fs::File, ops::Add};
+
+use chrono::{DateTime, Utc};
+use glam::{uvec2, Vec2};
+use obvhs::{ray::Ray, triangle::Triangle};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use serde::Serialize;
+use tabled::{settings::Style, Table, Tabled};
+
+use crate::{
+    build_label,
+    metrics::MetricsTree,
+    render_from_options,
+    rt_cpu::rt_cpu::{ao_ray, primary_ray},
+    Camera, Options, ViewUniform,
+};
+
+/// Trees are built with every combination of these and the leaf sizes below, so node visits and primitive tests vary
+/// independently enough to separate their costs.
+const COLLAPSE_TRAVERSAL_COSTS: [f32; 5] = [0.5, 1.0, 2.0, 4.0, 8.0];
+const CWBVH_MAX_PRIMS_PER_LEAF: [u32; 3] = [1, 2, 3];
+const MAX_PRIMS_PER_LEAF: [u32; 4] = [1, 2, 4, 8];
+
+/// Totals over one frame of the renderer's rays (one primary and, on hits, one AO ray per pixel).
+#[derive(Default, Clone, Copy, Debug)]
+pub struct RayCounts {
+    /// Inner nodes reached.
+    pub node_visits: u64,
+    /// Child bounds tested against the ray.
+    pub aabb_tests: u64,
+    /// Primitives tested against the ray.
+    pub tri_tests: u64,
+}
+
+impl Add for RayCounts {
+    type Output = RayCounts;
+
+    fn add(self, rhs: RayCounts) -> RayCounts {
+        RayCounts {
+            node_visits: self.node_visits + rhs.node_visits,
+            aabb_tests: self.aabb_tests + rhs.aabb_tests,
+            tri_tests: self.tri_tests + rhs.tri_tests,
+        }
+    }
+}
+
+/// Replays the rays of one frame of `rt_cpu::start` over `tree` and counts the work. The GPU kernels trace the same
+/// rays, so the counts are used for both targets. `tris` are the triangles the tree's primitives index into.
+pub fn count_rays(
+    tree: &MetricsTree,
+    tris: &[Triangle],
+    camera: &Camera,
+    options: &Options,
+) -> RayCounts {
+    let cam = ViewUniform::from_camera(camera, options.width as f32, options.height as f32, 0);
+    let target_size = Vec2::new(options.width as f32, options.height as f32);
+    (0..options.width * options.height)
+        .into_par_iter()
+        .map(|i| {
+            let frag_coord = uvec2(i % options.width, i / options.width);
+            let mut counts = RayCounts::default();
+            let ray = primary_ray(&cam, frag_coord, target_size);
+            let (t, primitive) = traverse_counted(tree, tris, ray, &mut counts);
+            if t < f32::MAX {
+                let mut n = tris[primitive].compute_normal();
+                n *= n.dot(-ray.direction).signum(); //Double sided
+                let ao_ray = ao_ray(&ray, t, n, frag_coord, 0);
+                traverse_counted(tree, tris, ao_ray, &mut counts);
+            }
+            counts
+        })
+        .reduce(RayCounts::default, |a, b| a + b)
+}
+
+/// Closest hit (t, primitive) with a stack traversal that tests all children of each inner node it reaches, like the
+/// CWBVH traversals. Leaves reached are intersected without counting a node visit, matching `node_cost`.
+fn traverse_counted(
+    tree: &MetricsTree,
+    tris: &[Triangle],
+    mut ray: Ray,
+    counts: &mut RayCounts,
+) -> (f32, usize) {
+    let mut hit = (f32::MAX, 0);
+    if tree.nodes.is_empty() {
+        return hit;
+    }
+    counts.aabb_tests += 1;
+    let t = tree.nodes[tree.root as usize].aabb.intersect_ray(&ray);
+    let mut stack = vec![(tree.root, t)];
+    while let Some((index, t)) = stack.pop() {
+        if t >= ray.tmax {
+            continue;
+        }
+        let node = &tree.nodes[index as usize];
+        let range = node.first as usize..(node.first + node.count) as usize;
+        if node.is_leaf {
+            for primitive in &tree.primitives[range] {
+                counts.tri_tests += 1;
+                let t = tris[*primitive as usize].intersect(&ray);
+                if t < ray.tmax {
+                    ray.tmax = t;
+                    hit = (t, *primitive as usize);
+                }
+            }
+            continue;
+        }
+        counts.node_visits += 1;
+        let first = stack.len();
+        for child in &tree.children[range] {
+            counts.aabb_tests += 1;
+            let t = tree.nodes[*child as usize].aabb.intersect_ray(&ray);
+            if t < ray.tmax {
+                stack.push((*child, t));
+            }
+        }
+        // Farthest first so the nearest child is visited first
+        stack[first..].sort_by(|a, b| b.1.total_cmp(&a.1));
+    }
+    hit
+}
+
+/// One tree on one scene.
+#[derive(Serialize)]
+struct Sample {
+    target: String,
+    scene: String,
+    build: String,
+    max_prims_per_leaf: u32,
+    collapse_traversal_cost: f32,
+    traversal_ms: f32,
+    node_visits: u64,
+    aabb_tests: u64,
+    tri_tests: u64,
+}
+
+/// Fitted cost of one node visit and one primitive test.
+struct Fit {
+    node_visit_ms: f64,
+    tri_test_ms: f64,
+    r2: f64,
+}
+
+/// Least squares fit of `traversal_ms = scene intercept + node_visit_ms * node_visits + tri_test_ms * tri_tests`.
+/// The per scene intercepts take the work that doesn't depend on the tree (ray generation, shading, the dispatch) and
+/// are removed by centering the samples of each scene. `None` if the trees didn't vary enough to separate the costs.
+fn fit(samples: &[Sample]) -> Option<Fit> {
+    let mut scenes: HashMap<&str, Vec<&Sample>> = HashMap::new();
+    for sample in samples {
+        scenes.entry(&sample.scene).or_default().push(sample);
+    }
+    // Centered (node visits, tri tests, time) of every sample
+    let mut centered = Vec::new();
+    for scene in scenes.values() {
+        let len = scene.len() as f64;
+        let mean_nodes = scene.iter().map(|s| s.node_visits as f64).sum::<f64>() / len;
+        let mean_tris = scene.iter().map(|s| s.tri_tests as f64).sum::<f64>() / len;
+        let mean_ms = scene.iter().map(|s| s.traversal_ms as f64).sum::<f64>() / len;
+        centered.extend(scene.iter().map(|s| {
+            (
+                s.node_visits as f64 - mean_nodes,
+                s.tri_tests as f64 - mean_tris,
+                s.traversal_ms as f64 - mean_ms,
+            )
+        }));
+    }
+
+    let sum = |f: &dyn Fn(&(f64, f64, f64)) -> f64| centered.iter().map(f).sum::<f64>();
+    let s11 = sum(&|(n, _, _)| n * n);
+    let s12 = sum(&|(n, t, _)| n * t);
+    let s22 = sum(&|(_, t, _)| t * t);
+    let s1y = sum(&|(n, _, y)| n * y);
+    let s2y = sum(&|(_, t, y)| t * y);
+    let syy = sum(&|(_, _, y)| y * y);
+    let det = s11 * s22 - s12 * s12;
+    if det <= 1e-9 * s11 * s22 || syy == 0.0 {
+        return None;
+    }
+    let node_visit_ms = (s1y * s22 - s2y * s12) / det;
+    let tri_test_ms = (s2y * s11 - s1y * s12) / det;
+    let residual = sum(&|(n, t, y)| (y - node_visit_ms * n - tri_test_ms * t).powi(2));
+    Some(Fit {
+        node_visit_ms,
+        tri_test_ms,
+        r2: 1.0 - residual / syy,
+    })
+}
+
+#[derive(Tabled)]
+struct CalibrationStats {
+    target: String,
+    samples: usize,
+    node_visit_ns: f32,
+    tri_test_ns: f32,
+    /// Node visit cost relative to a primitive test, the SAH traversal cost with an intersection cost of 1
+    traversal_cost: f32,
+    r2: f32,
+}
+
+/// Builds trees with a range of leaf sizes and collapse costs, renders them on each `--calibrate-targets` and fits the
+/// cost of a node visit and of a primitive test from the timings and the ray counts. Prints the fitted constants per
+/// target and saves the samples to a CSV.
+pub fn calibrate_costs(init_options: Options, mut event_loop: winit::event_loop::EventLoop<()>) {
+    let mut model_cache = Some(HashMap::new());
+    let max_prims_per_leaf = if init_options.build.contains("cwbvh") {
+        &CWBVH_MAX_PRIMS_PER_LEAF[..]
+    } else {
+        &MAX_PRIMS_PER_LEAF[..]
+    };
+    let mut samples = Vec::new();
+    let mut calibration_stats = Vec::new();
+    for target in init_options.calibrate_targets.split(',') {
+        let mut options = init_options.clone();
+        options.cpu = match target {
+            "cpu" => true,
+            "gpu" => false,
+            _ => panic!("Unknown calibration target {}, expected cpu or gpu", target),
+        };
+        // Warmup, see auto_tune
+        render_from_options(&options, &mut event_loop, &mut model_cache, &mut Vec::new());
+
+        let mut target_samples = Vec::new();
+        for max_prims in max_prims_per_leaf {
+            for collapse_traversal_cost in COLLAPSE_TRAVERSAL_COSTS {
+                options.max_prims_per_leaf = *max_prims;
+                options.collapse_traversal_cost = collapse_traversal_cost;
+                let mut stats = Vec::new();
+                render_from_options(&options, &mut event_loop, &mut model_cache, &mut stats);
+                for stat in stats.iter().filter(|s| s.name != "Avg") {
+                    let Some(counts) = stat.ray_counts else {
+                        panic!(
+                            "--calibrate-costs needs a builder with --metrics support, not {}",
+                            build_label(&options)
+                        );
+                    };
+                    target_samples.push(Sample {
+                        target: target.to_string(),
+                        scene: stat.name.clone(),
+                        build: stat.build.clone(),
+                        max_prims_per_leaf: *max_prims,
+                        collapse_traversal_cost,
+                        traversal_ms: stat.traversal_ms,
+                        node_visits: counts.node_visits,
+                        aabb_tests: counts.aabb_tests,
+                        tri_tests: counts.tri_tests,
+                    });
+                }
+            }
+        }
+
+        match fit(&target_samples) {
+            Some(fit) => calibration_stats.push(CalibrationStats {
+                target: target.to_string(),
+                samples: target_samples.len(),
+                node_visit_ns: (fit.node_visit_ms * 1e6) as f32,
+                tri_test_ns: (fit.tri_test_ms * 1e6) as f32,
+                traversal_cost: (fit.node_visit_ms / fit.tri_test_ms) as f32,
+                r2: fit.r2 as f32,
+            }),
+            None => println!(
+                "Not enough variation in the {} samples to fit the costs",
+                target
+            ),
+        }
+        samples.append(&mut target_samples);
+    }
+
+    println!("{}", Table::new(&calibration_stats).with(Style::blank()));
+    for stats in &calibration_stats {
+        if stats.node_visit_ns > 0.0 && stats.tri_test_ns > 0.0 {
+            println!(
+                "{}: --sah-traversal-cost {:.2} --sah-intersection-cost 1.0 --collapse-traversal-cost {:.2}",
+                stats.target, stats.traversal_cost, stats.traversal_cost
+            );
+        } else {
+            println!(
+                "{}: the fit has a negative cost, try a longer --render-time or more scenes",
+                stats.target
+            );
+        }
+    }
+    match save_samples(&samples) {
+        Ok(filename) => println!("CSV file saved successfully as '{}'.", filename),
+        Err(e) => eprintln!("Error saving CSV file: {}", e),
+    }
+}
+
+fn save_samples(samples: &[Sample]) -> Result<String, Box<dyn Error>> {
+    let now: DateTime<Utc> = Utc::now();
+    let filename = format!("calibration_{}.csv", now.format("%Y-%m-%d_%H-%M-%S"));
+    let mut wtr = csv::Writer::from_writer(File::create(&filename)?);
+    for sample in samples {
+        wtr.serialize(sample)?;
+    }
+    wtr.flush()?;
+    Ok(filename)
+}
@@ -0,0 +1,121 @@
+use std::time::Instant;
+
+use glam::{UVec3, Vec3A};
+use obvhs::{aabb::Aabb, triangle::Triangle};
+use rayon::{
+    iter::{
+        IndexedParallelIterator, IntoParallelRefIterator, IntoParallelRefMutIterator,
+        ParallelIterator,
+    },
+    slice::ParallelSliceMut,
+};
+
+use crate::Options;
+
+const KMEANS_ITERATIONS: usize = 10;
+
+/// Splits all triangles of the scene spatially into `--chunks` objects, so each gets its own BLAS under the TLAS.
+/// `morton` sorts by the Morton code of the centroids and cuts the sorted list into equal sized ranges. `kmeans` starts
+/// from the centroids of those ranges and runs a few iterations of Lloyd's algorithm, so chunk sizes can differ.
+pub fn chunk_objects(objects: Vec<Vec<Triangle>>, options: &Options) -> Vec<Vec<Triangle>> {
+    let start_time = Instant::now();
+    let mut tris = objects.into_iter().flatten().collect::<Vec<_>>();
+    let chunks = options.chunks.clamp(1, tris.len().max(1));
+
+    let bounds = tris
+        .iter()
+        .fold(Aabb::INVALID, |aabb, tri| aabb.union(&tri.aabb()));
+    let scale = 1023.0 / (bounds.max - bounds.min).max(Vec3A::splat(f32::MIN_POSITIVE));
+    tris.par_sort_by_cached_key(|tri| {
+        let p = ((tri.aabb().center() - bounds.min) * scale).as_uvec3();
+        morton_encode(p)
+    });
+    let ranges = (0..chunks)
+        .map(|i| i * tris.len() / chunks..(i + 1) * tris.len() / chunks)
+        .collect::<Vec<_>>();
+
+    let objects = match options.chunk_method.as_str() {
+        "kmeans" => {
+            let centers = ranges
+                .iter()
+                .map(|range| centroid_mean(tris[range.clone()].iter()))
+                .collect::<Vec<_>>();
+            kmeans(tris, centers)
+        }
+        _ => ranges
+            .into_iter()
+            .map(|range| tris[range].to_vec())
+            .collect(),
+    };
+
+    if options.verbose {
+        let sizes = objects.iter().map(|o| o.len());
+        println!(
+            "Chunked into {} objects with {} to {} triangles ({} {:.2}ms)",
+            objects.len(),
+            sizes.clone().min().unwrap_or(0),
+            sizes.max().unwrap_or(0),
+            options.chunk_method,
+            start_time.elapsed().as_secs_f32() * 1000.0
+        );
+    }
+    objects
+}
+
+fn kmeans(tris: Vec<Triangle>, mut centers: Vec<Vec3A>) -> Vec<Vec<Triangle>> {
+    let centroids = tris
+        .par_iter()
+        .map(|tri| tri.aabb().center())
+        .collect::<Vec<_>>();
+    let mut assignment = vec![0; tris.len()];
+    for _ in 0..KMEANS_ITERATIONS {
+        centroids
+            .par_iter()
+            .zip(assignment.par_iter_mut())
+            .for_each(|(c, a)| *a = nearest(&centers, *c));
+        let mut sums = vec![(Vec3A::ZERO, 0u32); centers.len()];
+        for (c, a) in centroids.iter().zip(&assignment) {
+            sums[*a].0 += *c;
+            sums[*a].1 += 1;
+        }
+        for (center, (sum, count)) in centers.iter_mut().zip(sums) {
+            // Empty clusters keep their center and are dropped at the end
+            if count > 0 {
+                *center = sum / count as f32;
+            }
+        }
+    }
+
+    let mut objects = vec![Vec::new(); centers.len()];
+    for (tri, a) in tris.into_iter().zip(assignment) {
+        objects[a].push(tri);
+    }
+    objects.retain(|o| !o.is_empty());
+    objects
+}
+
+fn nearest(centers: &[Vec3A], p: Vec3A) -> usize {
+    centers
+        .iter()
+        .enumerate()
+        .min_by(|(_, a), (_, b)| a.distance_squared(p).total_cmp(&b.distance_squared(p)))
+        .map(|(i, _)| i)
+        .unwrap_or(0)
+}
+
+fn centroid_mean<'a>(tris: impl ExactSizeIterator<Item = &'a Triangle>) -> Vec3A {
+    let count = tris.len().max(1) as f32;
+    tris.map(|tri| tri.aabb().center()).sum::<Vec3A>() / count
+}
+
+/// Interleaves the low 10 bits of each component.
+fn morton_encode(p: UVec3) -> u32 {
+    fn spread(mut x: u32) -> u32 {
+        x &= 0x3ff;
+        x = (x | (x << 16)) & 0x030000ff;
+        x = (x | (x << 8)) & 0x0300f00f;
+        x = (x | (x << 4)) & 0x030c30c3;
+        (x | (x << 2)) & 0x09249249
+    }
+    spread(p.x) | (spread(p.y) << 1) | (spread(p.z) << 2)
+}
@@ -1,22 +1,33 @@
-use glam::Mat4;
+use glam::{Affine3A, Mat4, UVec3, Vec3A};
 use obvhs::{
+    aabb::Aabb,
     cwbvh::{
         builder::{build_cwbvh, build_cwbvh_from_tris},
+        node::CwBvhNode,
         CwBvh,
     },
     ray::{Ray, RayHit},
     triangle::Triangle,
+    PerComponent,
+};
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use std::{
+    mem::size_of_val,
+    ops::Range,
+    time::{Duration, Instant},
 };
-use std::time::Duration;
 
 #[cfg(feature = "embree")]
 use obvhs_embree::{
     gpu_bvh_builder_embree::{self, embree_build_cwbvh_from_aabbs},
     gpu_bvh_builder_embree_bvh2,
 };
-use traversable::{SceneRtTri, Traversable};
+use traversable::{Intersectable, SceneRtTri, Traversable};
 
-use crate::{build_params_from_options, Options};
+use crate::{
+    build_params_from_options, bvh_cache::cached_cwbvh, dynamic::transform_aabb,
+    node_layout::layout_blas, sweep_sah, Options,
+};
 
 #[cfg(feature = "tinybvh")]
 use crate::tinybvh::{self, convert_tinybvh_cwbvh};
@@ -31,7 +42,78 @@ pub fn cwbvh_from_tris(
         println!("Building BVH with {}", options.build);
     }
 
-    let bvh = if options.build == "embree_cwbvh" {
+    let bvh = cached_cwbvh(triangles, options, || {
+        build_cwbvh_with_options(
+            triangles,
+            options,
+            core_build_time,
+            #[cfg(feature = "embree")]
+            embree_device,
+        )
+    });
+
+    if options.verbose {
+        println!("{}", bvh.validate(triangles, false));
+    }
+    bvh
+}
+
+/// Builds a BLAS per object and applies `--node-layout`. With `--parallel-blas` the objects are built concurrently and
+/// `blas_build_time` is the wall time of the whole batch instead of the sum of the individual builds.
+pub fn build_blas(
+    objects: &[Vec<Triangle>],
+    options: &Options,
+    blas_build_time: &mut Duration,
+    layout_rays: &[Ray],
+    #[cfg(feature = "embree")] embree_device: Option<&embree4_rs::Device>,
+) -> Vec<CwBvh> {
+    let mut blas = if options.parallel_blas {
+        #[cfg(feature = "embree")]
+        if embree_device.is_some() {
+            panic!("--parallel-blas is not supported with the Embree builders");
+        }
+        let start_time = Instant::now();
+        let blas = objects
+            .par_iter()
+            .map(|tris| {
+                cwbvh_from_tris(
+                    tris,
+                    options,
+                    &mut Duration::default(),
+                    #[cfg(feature = "embree")]
+                    None,
+                )
+            })
+            .collect::<Vec<_>>();
+        *blas_build_time += start_time.elapsed();
+        blas
+    } else {
+        objects
+            .iter()
+            .map(|tris| {
+                cwbvh_from_tris(
+                    tris,
+                    options,
+                    blas_build_time,
+                    #[cfg(feature = "embree")]
+                    embree_device,
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+    for (bvh, tris) in blas.iter_mut().zip(objects) {
+        layout_blas(bvh, tris, options, layout_rays);
+    }
+    blas
+}
+
+fn build_cwbvh_with_options(
+    triangles: &[Triangle],
+    options: &Options,
+    core_build_time: &mut Duration,
+    #[cfg(feature = "embree")] embree_device: Option<&embree4_rs::Device>,
+) -> CwBvh {
+    if options.build == "embree_cwbvh" {
         #[cfg(feature = "embree")]
         {
             let raw_device = embree_device.as_ref().unwrap().handle;
@@ -92,31 +174,61 @@ pub fn cwbvh_from_tris(
         }
         #[cfg(not(feature = "tinybvh"))]
         panic!("Need to enable tinybvh feature")
+    } else if options.build == "sweep_sah_cwbvh" || options.build == "sbvh_cwbvh" {
+        sweep_sah::build_sweep_sah_cwbvh(triangles, options, core_build_time)
     } else if options.build.contains("ploc_cwbvh") {
         let config = build_params_from_options(options);
         build_cwbvh_from_tris(triangles, config, core_build_time)
     } else {
         panic!("NO BVH BUILDER SPECIFIED")
-    };
-
-    if options.verbose {
-        println!("{}", bvh.validate(triangles, false));
     }
-    bvh
 }
 
+/// Bytes of the nodes of all the BLAS and the TLAS, and the TLAS primitive indices. BLAS primitive indices are not
+/// counted since the triangles are reordered to match them.
+pub fn cwbvh_memory(blas: &[CwBvh], tlas: Option<&CwBvh>) -> usize {
+    blas.iter()
+        .map(|bvh| size_of_val(bvh.nodes.as_slice()))
+        .sum::<usize>()
+        + tlas.map_or(0, |tlas| {
+            size_of_val(tlas.nodes.as_slice()) + size_of_val(tlas.primitive_indices.as_slice())
+        })
+}
+
+/// Builds a TLAS over the transformed bounds of the BLAS of each instance. The TLAS primitive indices index into
+/// `instances`.
 pub fn tlas_from_blas(
     blas: &Vec<CwBvh>,
+    instances: &[(u32, Affine3A)],
+    options: &Options,
+    tlas_build_time: &mut Duration,
+    #[cfg(feature = "embree")] embree_device: Option<&embree4_rs::Device>,
+) -> CwBvh {
+    let tlas_aabbs = instances
+        .iter()
+        .map(|(mesh, transform)| transform_aabb(transform, &blas[*mesh as usize].total_aabb))
+        .collect::<Vec<_>>();
+    tlas_from_aabbs(
+        &tlas_aabbs,
+        options,
+        tlas_build_time,
+        #[cfg(feature = "embree")]
+        embree_device,
+    )
+}
+
+/// Builds a TLAS over the given instance bounds with the builder selected in `options`.
+pub fn tlas_from_aabbs(
+    tlas_aabbs: &[Aabb],
     options: &Options,
     tlas_build_time: &mut Duration,
     #[cfg(feature = "embree")] embree_device: Option<&embree4_rs::Device>,
 ) -> CwBvh {
-    let tlas_aabbs = blas.iter().map(|b| b.total_aabb).collect::<Vec<_>>();
     let tlas_bvh = if options.build == "embree_cwbvh" {
         #[cfg(feature = "embree")]
         {
             let raw_device = embree_device.as_ref().unwrap().handle;
-            embree_build_cwbvh_from_aabbs(&tlas_aabbs, tlas_build_time, raw_device)
+            embree_build_cwbvh_from_aabbs(tlas_aabbs, tlas_build_time, raw_device)
         }
         #[cfg(not(feature = "embree"))]
         panic!("Embree feature not enabled")
@@ -127,35 +239,38 @@ pub fn tlas_from_blas(
         }
         #[cfg(not(feature = "embree"))]
         panic!("Embree feature not enabled")
+    } else if options.build == "sweep_sah_cwbvh" || options.build == "sbvh_cwbvh" {
+        sweep_sah::build_sweep_sah_cwbvh_from_aabbs(tlas_aabbs, options, tlas_build_time)
     } else if options.build.contains("ploc_cwbvh") {
         let config = build_params_from_options(options);
-        build_cwbvh(&tlas_aabbs, config, tlas_build_time)
+        build_cwbvh(tlas_aabbs, config, tlas_build_time)
     } else {
         panic!("NO BVH BUILDER SPECIFIED")
     };
     tlas_bvh
 }
-pub struct CwBvhTlasScene {
+
+pub struct CwBvhTlasScene<T = SceneRtTri> {
     pub blas: Vec<CwBvh>,
-    pub meshes: Vec<Vec<SceneRtTri>>,
+    pub meshes: Vec<Vec<T>>,
     pub tlas: CwBvh,
 }
 
-impl Traversable for CwBvhTlasScene {
-    type Primitive = SceneRtTri;
+impl<T: Intersectable> Traversable for CwBvhTlasScene<T> {
+    type Primitive = T;
 
     #[inline(always)]
     fn traverse(&self, ray: Ray) -> RayHit {
         let mut hit = RayHit::none();
         self.tlas
             .ray_traverse_tlas_blas(&self.blas, ray, &mut hit, |ray, geom_id, prim_id| {
-                self.meshes[geom_id][prim_id].0.intersect(ray)
+                self.meshes[geom_id][prim_id].intersect(ray)
             });
         hit
     }
 
     #[inline(always)]
-    fn get_primitive(&self, geometry_id: u32, primitive_id: u32) -> &SceneRtTri {
+    fn get_primitive(&self, geometry_id: u32, primitive_id: u32) -> &T {
         &self.meshes[geometry_id as usize][primitive_id as usize]
     }
 
@@ -165,24 +280,117 @@ impl Traversable for CwBvhTlasScene {
     }
 }
 
-pub struct CwBvhScene<'a> {
+/// Like `CwBvhTlasScene`, but the TLAS primitives are instances of the BLAS (see `instancing::detect_instances`).
+/// Rays are moved into the object space of the instance before traversing its BLAS.
+pub struct CwBvhInstancedTlasScene<T = SceneRtTri> {
+    pub blas: Vec<CwBvh>,
+    pub meshes: Vec<Vec<T>>,
+    pub tlas: CwBvh,
+    pub instances: Vec<(u32, Affine3A)>,
+    pub world_to_object: Vec<Affine3A>,
+}
+
+impl<T> CwBvhInstancedTlasScene<T> {
+    pub fn new(
+        blas: Vec<CwBvh>,
+        meshes: Vec<Vec<T>>,
+        tlas: CwBvh,
+        instances: Vec<(u32, Affine3A)>,
+    ) -> Self {
+        let world_to_object = instances.iter().map(|(_, t)| t.inverse()).collect();
+        CwBvhInstancedTlasScene {
+            blas,
+            meshes,
+            tlas,
+            instances,
+            world_to_object,
+        }
+    }
+
+    /// Instance index and the ray in its object space for a TLAS primitive. The transforms from instance detection are
+    /// rigid, so t is the same in both spaces.
+    #[inline(always)]
+    pub fn object_ray(&self, ray: &Ray, tlas_primitive: usize) -> (usize, Ray) {
+        let instance = self.tlas.primitive_indices[tlas_primitive] as usize;
+        let world_to_object = &self.world_to_object[instance];
+        let object_ray = Ray::new(
+            world_to_object.transform_point3a(ray.origin),
+            world_to_object.transform_vector3a(ray.direction),
+            ray.tmin,
+            ray.tmax,
+        );
+        (instance, object_ray)
+    }
+}
+
+impl<T: Intersectable> Traversable for CwBvhInstancedTlasScene<T> {
+    type Primitive = T;
+
+    #[inline(always)]
+    fn traverse(&self, ray: Ray) -> RayHit {
+        // The TLAS hit only has the TLAS primitive, the BLAS hit is kept separately.
+        let mut tlas_hit = RayHit::none();
+        let mut hit = RayHit::none();
+        self.tlas.ray_traverse(ray, &mut tlas_hit, |ray, id| {
+            let (instance, object_ray) = self.object_ray(ray, id);
+            let mesh = self.instances[instance].0 as usize;
+            let mut blas_hit = RayHit::none();
+            if self.blas[mesh].ray_traverse(object_ray, &mut blas_hit, |ray, id| {
+                self.meshes[mesh][id].intersect(ray)
+            }) {
+                hit = RayHit {
+                    primitive_id: blas_hit.primitive_id,
+                    geometry_id: mesh as u32,
+                    instance_id: instance as u32,
+                    t: blas_hit.t,
+                };
+            }
+            blas_hit.t
+        });
+        hit
+    }
+
+    #[inline(always)]
+    fn get_primitive(&self, geometry_id: u32, primitive_id: u32) -> &T {
+        &self.meshes[geometry_id as usize][primitive_id as usize]
+    }
+
+    #[inline(always)]
+    fn get_instance_transform(&self, instance_id: u32) -> Mat4 {
+        Mat4::from(self.instances[instance_id as usize].1)
+    }
+
+    /// The primitives are in the object space of the instance.
+    #[inline(always)]
+    fn hit_normal(&self, hit: &RayHit, ray: &Ray) -> Vec3A {
+        let n = self
+            .get_primitive(hit.geometry_id, hit.primitive_id)
+            .compute_normal(ray);
+        self.instances[hit.instance_id as usize]
+            .1
+            .transform_vector3a(n)
+            .normalize_or_zero()
+    }
+}
+
+pub struct CwBvhScene<'a, T = SceneRtTri> {
     pub bvh: &'a CwBvh,
-    pub tris: &'a [SceneRtTri],
+    pub tris: &'a [T],
 }
 
-impl Traversable for CwBvhScene<'_> {
-    type Primitive = SceneRtTri;
+impl<T: Intersectable> Traversable for CwBvhScene<'_, T> {
+    type Primitive = T;
 
     #[inline(always)]
     fn traverse(&self, ray: Ray) -> RayHit {
         let mut hit = RayHit::none();
         self.bvh
-            .ray_traverse(ray, &mut hit, |ray, id| self.tris[id].0.intersect(ray));
+            .ray_traverse(ray, &mut hit, |ray, id| self.tris[id].intersect(ray));
         hit
     }
 
     #[inline(always)]
-    fn get_primitive(&self, _geometry_id: u32, primitive_id: u32) -> &SceneRtTri {
+    fn get_primitive(&self, _geometry_id: u32, primitive_id: u32) -> &T {
         &self.tris[primitive_id as usize]
     }
 
@@ -191,3 +399,98 @@ impl Traversable for CwBvhScene<'_> {
         Mat4::default()
     }
 }
+
+/// Decodes the per child data of a CwBvh node. See "Efficient Incoherent Ray Traversal on GPUs Through Compressed
+/// Wide BVHs" (Ylitie et al.) for the layout.
+pub trait CwBvhNodeExt {
+    fn child_is_empty(&self, ch: usize) -> bool;
+    fn child_is_inner(&self, ch: usize) -> bool;
+    /// Index of the child node in `CwBvh::nodes`. Only valid for inner children.
+    fn child_node_index(&self, ch: usize) -> usize;
+    /// Range into `CwBvh::primitive_indices`. Only valid for leaf children.
+    fn child_primitives(&self, ch: usize) -> Range<usize>;
+    /// Dequantized (conservative) bounds of the child.
+    fn child_aabb(&self, ch: usize) -> Aabb;
+}
+
+impl CwBvhNodeExt for CwBvhNode {
+    #[inline(always)]
+    fn child_is_empty(&self, ch: usize) -> bool {
+        self.child_meta[ch] == 0
+    }
+
+    #[inline(always)]
+    fn child_is_inner(&self, ch: usize) -> bool {
+        self.imask & (1 << ch) != 0
+    }
+
+    #[inline(always)]
+    fn child_node_index(&self, ch: usize) -> usize {
+        // Inner children are stored contiguously in slot order
+        let preceding_inner = (self.imask as u32 & ((1 << ch) - 1)).count_ones();
+        (self.child_base_idx + preceding_inner) as usize
+    }
+
+    #[inline(always)]
+    fn child_primitives(&self, ch: usize) -> Range<usize> {
+        let meta = self.child_meta[ch];
+        let start = self.primitive_base_idx as usize + (meta & 0b11111) as usize;
+        start..start + (meta >> 5).count_ones() as usize
+    }
+
+    #[inline(always)]
+    fn child_aabb(&self, ch: usize) -> Aabb {
+        let e = Vec3A::new(
+            f32::from_bits((self.e[0] as u32) << 23),
+            f32::from_bits((self.e[1] as u32) << 23),
+            f32::from_bits((self.e[2] as u32) << 23),
+        );
+        let p = Vec3A::from(self.p);
+        let q_min = Vec3A::new(
+            self.child_min_x[ch] as f32,
+            self.child_min_y[ch] as f32,
+            self.child_min_z[ch] as f32,
+        );
+        let q_max = Vec3A::new(
+            self.child_max_x[ch] as f32,
+            self.child_max_y[ch] as f32,
+            self.child_max_z[ch] as f32,
+        );
+        Aabb::new(p + q_min * e, p + q_max * e)
+    }
+}
+
+/// Re-quantizes the bounds of a node and its children, leaving the topology (imask, child_meta, base indices) as is.
+/// Same quantization as `embree_to_cwbvh`. Empty children are skipped.
+pub fn quantize_cwbvh_node(node: &mut CwBvhNode, bounds: &Aabb, child_aabbs: &[Aabb; 8]) {
+    const NQ: u32 = 8;
+    const DENOM: f32 = 1.0 / ((1 << NQ) - 1) as f32;
+
+    let p = bounds.min;
+    let e = ((bounds.max - bounds.min).max(Vec3A::splat(1e-20)) * DENOM)
+        .log2()
+        .ceil()
+        .exp2();
+    let rcp_e = 1.0 / e;
+    let e: UVec3 = e.per_comp(|c: f32| c.to_bits() >> 23);
+    node.p = p.into();
+    node.e = [e.x as u8, e.y as u8, e.z as u8];
+
+    for (ch, child_bounds) in child_aabbs.iter().enumerate() {
+        if node.child_is_empty(ch) {
+            continue;
+        }
+        let child_min = ((child_bounds.min - p) * rcp_e)
+            .floor()
+            .clamp(Vec3A::ZERO, Vec3A::splat(255.0));
+        let child_max = ((child_bounds.max - p) * rcp_e)
+            .ceil()
+            .clamp(Vec3A::ZERO, Vec3A::splat(255.0));
+        node.child_min_x[ch] = child_min.x as u8;
+        node.child_min_y[ch] = child_min.y as u8;
+        node.child_min_z[ch] = child_min.z as u8;
+        node.child_max_x[ch] = child_max.x as u8;
+        node.child_max_y[ch] = child_max.y as u8;
+        node.child_max_z[ch] = child_max.z as u8;
+    }
+}
@@ -0,0 +1,599 @@
+use std::{
+    error::Error,
+    f32::consts::TAU,
+    fs::File,
+    hint::black_box,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use glam::{uvec2, Affine3A, Quat, Vec2, Vec3, Vec3A};
+use obvhs::{
+    aabb::Aabb,
+    bvh2::{builder::build_bvh2_from_tris, Bvh2},
+    cwbvh::{
+        builder::{build_cwbvh, build_cwbvh_from_tris},
+        CwBvh,
+    },
+    triangle::Triangle,
+};
+use rayon::iter::{
+    IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator, ParallelIterator,
+};
+use ron::de::from_reader;
+use serde::Deserialize;
+use tabled::{settings::Style, Table, Tabled};
+use traversable::{SceneRtTri, Traversable};
+
+#[cfg(feature = "embree")]
+use embree4_sys::RTCBuildQuality;
+#[cfg(feature = "embree")]
+use obvhs_embree::embree_managed::{EmbreeDynamicScene, EmbreeDynamicSceneAndTris};
+#[cfg(feature = "embree")]
+use traversable::SceneTri;
+
+#[cfg(feature = "embree")]
+use crate::embree_device_from_options;
+use crate::{
+    build_params_from_options,
+    cwbvh::{CwBvhInstancedTlasScene, CwBvhScene, CwBvhTlasScene},
+    load_scene,
+    metrics::{bvh2_sah_cost, cwbvh_sah_cost, tlas_sah_cost, SahCosts},
+    refit::{refit_bvh2, refit_cwbvh},
+    rt_cpu::{rt_cpu::primary_ray, Bvh2Scene},
+    Options, ViewUniform,
+};
+
+/// Rigid transform of one object for one frame, applied around the center of the object's bounds.
+#[derive(Deserialize, Clone, Copy)]
+pub struct ObjectTransform {
+    pub translation: Vec3,
+    #[serde(default)]
+    pub rotation: Quat,
+}
+
+pub enum Animation {
+    /// Vertices are pushed around by a travelling sine wave. The offset only depends on the rest position of the
+    /// vertex so shared vertices stay shared and the mesh doesn't crack.
+    Displace { amplitude: f32, wavelength: f32 },
+    /// Objects are moved rigidly by per frame transforms. Read from a RON file with one list of transforms per frame
+    /// and one transform per object, for example:
+    /// `[[(translation: (0.0, 0.1, 0.0), rotation: (0.0, 0.0, 0.0, 1.0)), ...], ...]`
+    /// The sequence loops if there are more frames than entries.
+    Transforms(Vec<Vec<ObjectTransform>>),
+}
+
+impl Animation {
+    pub fn from_options(options: &Options, scene_aabb: &Aabb) -> Self {
+        match options.animation.as_str() {
+            "displace" => {
+                let size = scene_aabb.diagonal().length();
+                Animation::Displace {
+                    amplitude: size * options.displacement,
+                    wavelength: size * 0.25,
+                }
+            }
+            "instances" => {
+                if options.animation_sequence.is_empty() {
+                    panic!("--animation instances requires --animation-sequence");
+                }
+                let f = File::open(&options.animation_sequence)
+                    .expect("Failed opening animation sequence");
+                match from_reader(f) {
+                    Ok(sequence) => Animation::Transforms(sequence),
+                    Err(e) => panic!("Failed to load animation sequence: {}", e),
+                }
+            }
+            _ => panic!("Unknown animation {}", options.animation),
+        }
+    }
+
+    /// Transform applied to the given object. Identity for displacement.
+    pub fn object_transform(&self, frame: usize, object: usize, pivot: Vec3A) -> Affine3A {
+        match self {
+            Animation::Displace { .. } => Affine3A::IDENTITY,
+            Animation::Transforms(sequence) => {
+                let transforms = &sequence[frame % sequence.len()];
+                let Some(transform) = transforms.get(object) else {
+                    panic!(
+                        "Animation frame {} has {} transforms but the scene has more objects",
+                        frame % sequence.len(),
+                        transforms.len()
+                    );
+                };
+                Affine3A::from_translation(Vec3::from(pivot) + transform.translation)
+                    * Affine3A::from_quat(transform.rotation)
+                    * Affine3A::from_translation(-Vec3::from(pivot))
+            }
+        }
+    }
+
+    /// Objects posed for the given frame.
+    pub fn pose(
+        &self,
+        rest: &[Vec<Triangle>],
+        pivots: &[Vec3A],
+        frame: usize,
+    ) -> Vec<Vec<Triangle>> {
+        rest.par_iter()
+            .enumerate()
+            .map(|(object, tris)| match self {
+                Animation::Displace {
+                    amplitude,
+                    wavelength,
+                } => {
+                    let k = TAU / wavelength;
+                    let phase = frame as f32 * 0.2;
+                    let displace = |p: Vec3A| {
+                        p + *amplitude
+                            * Vec3A::new(
+                                (k * p.y + phase).sin(),
+                                (k * p.z + phase * 1.3).sin(),
+                                (k * p.x + phase * 0.7).sin(),
+                            )
+                    };
+                    tris.iter()
+                        .map(|tri| Triangle {
+                            v0: displace(tri.v0),
+                            v1: displace(tri.v1),
+                            v2: displace(tri.v2),
+                        })
+                        .collect()
+                }
+                Animation::Transforms(_) => {
+                    let transform = self.object_transform(frame, object, pivots[object]);
+                    tris.iter()
+                        .map(|tri| Triangle {
+                            v0: transform.transform_point3a(tri.v0),
+                            v1: transform.transform_point3a(tri.v1),
+                            v2: transform.transform_point3a(tri.v2),
+                        })
+                        .collect()
+                }
+            })
+            .collect()
+    }
+}
+
+#[derive(Tabled, Clone)]
+struct DynamicFrame {
+    frame: usize,
+    strategy: &'static str,
+    /// Time spent refitting/rebuilding. On frame 0 this is the initial build.
+    update_ms: f32,
+    /// NaN if the strategy can't be traversed on the CPU.
+    #[tabled(display_with = "display_optional")]
+    traversal_ms: f32,
+    /// NaN if the tree isn't accessible (Embree).
+    #[tabled(display_with = "display_optional")]
+    sah_cost: f32,
+}
+
+#[derive(Tabled, Clone)]
+struct DynamicSummary {
+    name: String,
+    strategy: &'static str,
+    /// Average over the animated frames, excluding the initial build.
+    avg_update_ms: f32,
+    #[tabled(display_with = "display_optional")]
+    avg_traversal_ms: f32,
+    /// Traversal time of the last frame relative to frame 0: how much the tree degraded.
+    #[tabled(display_with = "display_optional")]
+    traversal_growth: f32,
+    #[tabled(display_with = "display_optional")]
+    sah_growth: f32,
+}
+
+fn display_optional(value: &f32) -> String {
+    if value.is_nan() {
+        String::from("-")
+    } else {
+        value.to_string()
+    }
+}
+
+/// Animates each input scene for `--dynamic-frames` frames and compares BVH update strategies. Every strategy builds
+/// its BVH on frame 0, then either refits or rebuilds it on each following frame. Traversal time is measured with one
+/// primary ray per pixel so the comparison only reflects tree quality.
+pub fn dynamic_benchmark(options: &Options) {
+    if !options.build.starts_with("ploc_") {
+        panic!(
+            "--dynamic-frames only compares the obvhs PLOC builds, --build {} is not supported",
+            options.build
+        );
+    }
+    #[cfg(feature = "embree")]
+    let embree_device = embree_device_from_options(options);
+
+    let costs = SahCosts::from_options(options);
+    let mut summaries = Vec::new();
+    for input in options.input.split(",") {
+        let (file_name, scene, objects) = load_scene(input, &mut None);
+        let object_aabbs = objects
+            .iter()
+            .map(|tris| {
+                tris.iter()
+                    .fold(Aabb::INVALID, |aabb, tri| aabb.union(&tri.aabb()))
+            })
+            .collect::<Vec<_>>();
+        let scene_aabb = object_aabbs
+            .iter()
+            .fold(Aabb::INVALID, |aabb, object_aabb| aabb.union(object_aabb));
+        let pivots = object_aabbs
+            .iter()
+            .map(|aabb| aabb.center())
+            .collect::<Vec<_>>();
+        let animation = Animation::from_options(options, &scene_aabb);
+        let cam = ViewUniform::from_camera(
+            &scene.camera,
+            options.width as f32,
+            options.height as f32,
+            0,
+        );
+
+        let mut frames = Vec::new();
+        let mut bvh2_refit = Bvh2::default();
+        let mut cwbvh_refit = CwBvh::default();
+        let mut tlas_scene = CwBvhTlasScene {
+            blas: Vec::new(),
+            meshes: Vec::new(),
+            tlas: CwBvh::default(),
+        };
+        let mut instanced_scene =
+            CwBvhInstancedTlasScene::new(Vec::new(), Vec::new(), CwBvh::default(), Vec::new());
+        let mut rest_blas_costs = Vec::new();
+        #[cfg(feature = "embree")]
+        let mut embree_scenes = Vec::new();
+
+        for frame in 0..=options.dynamic_frames {
+            let posed = animation.pose(&objects, &pivots, frame);
+            let tris = posed.concat();
+
+            let mut update_time = Duration::ZERO;
+            if frame == 0 {
+                bvh2_refit = build_bvh2_from_tris(
+                    &tris,
+                    build_params_from_options(options),
+                    &mut update_time,
+                );
+            } else {
+                let start_time = Instant::now();
+                refit_bvh2(&mut bvh2_refit, &tris);
+                update_time += start_time.elapsed();
+            }
+            let rt_tris = ordered_rt_tris(&bvh2_refit.primitive_indices, &tris);
+            frames.push(DynamicFrame {
+                frame,
+                strategy: "bvh2_refit",
+                update_ms: update_time.as_secs_f32() * 1000.0,
+                traversal_ms: trace(
+                    options,
+                    &cam,
+                    &Bvh2Scene {
+                        bvh: &bvh2_refit,
+                        tris: &rt_tris,
+                    },
+                ),
+                sah_cost: bvh2_sah_cost(&bvh2_refit, &costs),
+            });
+
+            let mut update_time = Duration::ZERO;
+            let bvh2 =
+                build_bvh2_from_tris(&tris, build_params_from_options(options), &mut update_time);
+            let rt_tris = ordered_rt_tris(&bvh2.primitive_indices, &tris);
+            frames.push(DynamicFrame {
+                frame,
+                strategy: "bvh2_rebuild",
+                update_ms: update_time.as_secs_f32() * 1000.0,
+                traversal_ms: trace(
+                    options,
+                    &cam,
+                    &Bvh2Scene {
+                        bvh: &bvh2,
+                        tris: &rt_tris,
+                    },
+                ),
+                sah_cost: bvh2_sah_cost(&bvh2, &costs),
+            });
+
+            let mut update_time = Duration::ZERO;
+            if frame == 0 {
+                cwbvh_refit = build_cwbvh_from_tris(
+                    &tris,
+                    build_params_from_options(options),
+                    &mut update_time,
+                );
+            } else {
+                let start_time = Instant::now();
+                refit_cwbvh(&mut cwbvh_refit, &tris);
+                update_time += start_time.elapsed();
+            }
+            let rt_tris = ordered_rt_tris(&cwbvh_refit.primitive_indices, &tris);
+            frames.push(DynamicFrame {
+                frame,
+                strategy: "cwbvh_refit",
+                update_ms: update_time.as_secs_f32() * 1000.0,
+                traversal_ms: trace(
+                    options,
+                    &cam,
+                    &CwBvhScene {
+                        bvh: &cwbvh_refit,
+                        tris: &rt_tris,
+                    },
+                ),
+                sah_cost: cwbvh_sah_cost(&cwbvh_refit, &costs),
+            });
+
+            let mut update_time = Duration::ZERO;
+            let cwbvh =
+                build_cwbvh_from_tris(&tris, build_params_from_options(options), &mut update_time);
+            let rt_tris = ordered_rt_tris(&cwbvh.primitive_indices, &tris);
+            frames.push(DynamicFrame {
+                frame,
+                strategy: "cwbvh_rebuild",
+                update_ms: update_time.as_secs_f32() * 1000.0,
+                traversal_ms: trace(
+                    options,
+                    &cam,
+                    &CwBvhScene {
+                        bvh: &cwbvh,
+                        tris: &rt_tris,
+                    },
+                ),
+                sah_cost: cwbvh_sah_cost(&cwbvh, &costs),
+            });
+
+            if objects.len() > 1 {
+                // One BLAS per object in world space: refit the BLAS that moved and rebuild the (small) TLAS.
+                let mut update_time = Duration::ZERO;
+                if frame == 0 {
+                    tlas_scene.blas = posed
+                        .iter()
+                        .map(|tris| {
+                            build_cwbvh_from_tris(
+                                tris,
+                                build_params_from_options(options),
+                                &mut update_time,
+                            )
+                        })
+                        .collect();
+                } else {
+                    let start_time = Instant::now();
+                    for (blas, tris) in tlas_scene.blas.iter_mut().zip(&posed) {
+                        refit_cwbvh(blas, tris);
+                    }
+                    update_time += start_time.elapsed();
+                }
+                let instance_aabbs = tlas_scene
+                    .blas
+                    .iter()
+                    .map(|blas| blas.total_aabb)
+                    .collect::<Vec<_>>();
+                tlas_scene.tlas = build_cwbvh(
+                    &instance_aabbs,
+                    build_params_from_options(options),
+                    &mut update_time,
+                );
+                tlas_scene.meshes = tlas_scene
+                    .blas
+                    .iter()
+                    .zip(&posed)
+                    .map(|(blas, tris)| ordered_rt_tris(&blas.primitive_indices, tris))
+                    .collect();
+                let blas_costs = tlas_scene
+                    .blas
+                    .iter()
+                    .map(|blas| cwbvh_sah_cost(blas, &costs))
+                    .collect::<Vec<_>>();
+                frames.push(DynamicFrame {
+                    frame,
+                    strategy: "blas_refit_tlas_rebuild",
+                    update_ms: update_time.as_secs_f32() * 1000.0,
+                    traversal_ms: trace(options, &cam, &tlas_scene),
+                    sah_cost: tlas_sah_cost(&tlas_scene.tlas, &blas_costs, &instance_aabbs, &costs),
+                });
+            }
+
+            if let Animation::Transforms(_) = animation {
+                // BLAS stay in object space and only the TLAS over the transformed BLAS bounds is rebuilt. Rays are
+                // moved into the object space of each instance, like with --detect-instances.
+                let mut update_time = Duration::ZERO;
+                if frame == 0 {
+                    let mut blas_build_time = Duration::ZERO;
+                    instanced_scene.blas = objects
+                        .iter()
+                        .map(|tris| {
+                            build_cwbvh_from_tris(
+                                tris,
+                                build_params_from_options(options),
+                                &mut blas_build_time,
+                            )
+                        })
+                        .collect::<Vec<_>>();
+                    instanced_scene.meshes = instanced_scene
+                        .blas
+                        .iter()
+                        .zip(&objects)
+                        .map(|(blas, tris)| ordered_rt_tris(&blas.primitive_indices, tris))
+                        .collect();
+                    rest_blas_costs = instanced_scene
+                        .blas
+                        .iter()
+                        .map(|blas| cwbvh_sah_cost(blas, &costs))
+                        .collect();
+                    update_time += blas_build_time;
+                }
+                let start_time = Instant::now();
+                let instances = (0..objects.len())
+                    .map(|i| (i as u32, animation.object_transform(frame, i, pivots[i])))
+                    .collect::<Vec<_>>();
+                let instance_aabbs = instanced_scene
+                    .blas
+                    .iter()
+                    .zip(&instances)
+                    .map(|(blas, (_, transform))| transform_aabb(transform, &blas.total_aabb))
+                    .collect::<Vec<_>>();
+                update_time += start_time.elapsed();
+                let tlas = build_cwbvh(
+                    &instance_aabbs,
+                    build_params_from_options(options),
+                    &mut update_time,
+                );
+                let sah_cost = tlas_sah_cost(&tlas, &rest_blas_costs, &instance_aabbs, &costs);
+                instanced_scene = CwBvhInstancedTlasScene::new(
+                    std::mem::take(&mut instanced_scene.blas),
+                    std::mem::take(&mut instanced_scene.meshes),
+                    tlas,
+                    instances,
+                );
+                frames.push(DynamicFrame {
+                    frame,
+                    strategy: "tlas_rebuild",
+                    update_ms: update_time.as_secs_f32() * 1000.0,
+                    traversal_ms: trace(options, &cam, &instanced_scene),
+                    sah_cost,
+                });
+            }
+
+            #[cfg(feature = "embree")]
+            {
+                let scene_tris = tris
+                    .iter()
+                    .map(|tri| SceneTri(tri.clone()))
+                    .collect::<Vec<_>>();
+                for (i, (strategy, quality)) in [
+                    ("embree_refit", RTCBuildQuality::REFIT),
+                    ("embree_rebuild", RTCBuildQuality::HIGH),
+                ]
+                .into_iter()
+                .enumerate()
+                {
+                    let mut update_time = Duration::ZERO;
+                    if frame == 0 {
+                        embree_scenes.push(EmbreeDynamicScene::new(
+                            &tris,
+                            &embree_device,
+                            quality,
+                            &mut update_time,
+                        ));
+                    } else {
+                        embree_scenes[i].update(&tris, &mut update_time);
+                    }
+                    frames.push(DynamicFrame {
+                        frame,
+                        strategy,
+                        update_ms: update_time.as_secs_f32() * 1000.0,
+                        traversal_ms: trace(
+                            options,
+                            &cam,
+                            &EmbreeDynamicSceneAndTris {
+                                scene: &embree_scenes[i],
+                                tris: &scene_tris,
+                            },
+                        ),
+                        sah_cost: f32::NAN,
+                    });
+                }
+            }
+        }
+
+        if options.verbose {
+            println!("{}", Table::new(&frames).with(Style::blank()));
+        }
+        match save_dynamic_results_to_csv(&frames, &format!("dynamic_{}", file_name)) {
+            Ok(filename) => println!("CSV file saved successfully as '{}'.", filename),
+            Err(e) => eprintln!("Error saving CSV file: {}", e),
+        }
+
+        let strategies = frames
+            .iter()
+            .filter(|f| f.frame == 0)
+            .map(|f| f.strategy)
+            .collect::<Vec<_>>();
+        for strategy in strategies {
+            let runs = frames
+                .iter()
+                .filter(|f| f.strategy == strategy)
+                .collect::<Vec<_>>();
+            let first = runs[0];
+            let last = runs[runs.len() - 1];
+            let animated = &runs[1..];
+            let n = animated.len() as f32;
+            summaries.push(DynamicSummary {
+                name: file_name.clone(),
+                strategy,
+                avg_update_ms: animated.iter().map(|f| f.update_ms).sum::<f32>() / n,
+                avg_traversal_ms: animated.iter().map(|f| f.traversal_ms).sum::<f32>() / n,
+                traversal_growth: last.traversal_ms / first.traversal_ms,
+                sah_growth: last.sah_cost / first.sah_cost,
+            });
+        }
+    }
+    println!("{}", Table::new(summaries).with(Style::blank()));
+}
+
+/// Bounds of the 8 transformed corners of `aabb`.
+pub fn transform_aabb(transform: &Affine3A, aabb: &Aabb) -> Aabb {
+    let mut transformed = Aabb::INVALID;
+    for i in 0..8 {
+        let corner = Vec3A::new(
+            if i & 1 == 0 { aabb.min.x } else { aabb.max.x },
+            if i & 2 == 0 { aabb.min.y } else { aabb.max.y },
+            if i & 4 == 0 { aabb.min.z } else { aabb.max.z },
+        );
+        transformed.extend(transform.transform_point3a(corner));
+    }
+    transformed
+}
+
+fn save_dynamic_results_to_csv(
+    frames: &[DynamicFrame],
+    filename: &str,
+) -> Result<String, Box<dyn Error>> {
+    let now: DateTime<Utc> = Utc::now();
+    let filename = format!("{}_{}.csv", filename, now.format("%Y-%m-%d_%H-%M-%S"));
+
+    let path = Path::new(&filename);
+    let file = File::create(path)?;
+    let mut wtr = csv::Writer::from_writer(file);
+
+    wtr.write_record(&["frame", "strategy", "update_ms", "traversal_ms", "sah_cost"])?;
+
+    for frame in frames {
+        wtr.write_record(&[
+            frame.frame.to_string(),
+            frame.strategy.to_string(),
+            frame.update_ms.to_string(),
+            frame.traversal_ms.to_string(),
+            frame.sah_cost.to_string(),
+        ])?;
+    }
+
+    wtr.flush()?;
+    Ok(filename)
+}
+
+/// Triangles in BVH order to avoid the extra indirection during traversal.
+fn ordered_rt_tris(primitive_indices: &[u32], tris: &[Triangle]) -> Vec<SceneRtTri> {
+    primitive_indices
+        .iter()
+        .map(|i| SceneRtTri((&tris[*i as usize]).into()))
+        .collect()
+}
+
+/// Milliseconds to trace one primary ray per pixel.
+fn trace<T: Traversable + Sync>(options: &Options, cam: &ViewUniform, scene: &T) -> f32 {
+    let target_size = Vec2::new(options.width as f32, options.height as f32);
+    let start_time = Instant::now();
+    let hits = (0..options.width * options.height)
+        .into_par_iter()
+        .map(|i| {
+            let frag_coord = uvec2(i % options.width, i / options.width);
+            let ray = primary_ray(cam, frag_coord, target_size);
+            (scene.traverse(ray).t < f32::MAX) as u32
+        })
+        .sum::<u32>();
+    black_box(hits);
+    start_time.elapsed().as_secs_f32() * 1000.0
+}
@@ -0,0 +1,154 @@
+use std::{collections::HashMap, mem::size_of, time::Instant};
+
+use glam::{Affine3A, Mat3A, Vec3A};
+use obvhs::{aabb::Aabb, triangle::Triangle};
+use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
+
+use crate::Options;
+
+/// Vertices have to match within this fraction of the mesh bounds diagonal for two objects to be the same mesh.
+const VERTEX_TOLERANCE: f32 = 1e-4;
+
+/// (mesh index, object to world) for each instance. Without instancing each object is its own mesh with an identity
+/// transform.
+pub type Instances = Vec<(u32, Affine3A)>;
+
+pub fn identity_instances(object_count: usize) -> Instances {
+    (0..object_count as u32)
+        .map(|i| (i, Affine3A::IDENTITY))
+        .collect()
+}
+
+/// True if the instances are just the objects with identity transforms, so paths without transform support can be
+/// used.
+pub fn instances_are_objects(instances: &[(u32, Affine3A)]) -> bool {
+    instances
+        .iter()
+        .enumerate()
+        .all(|(i, (mesh, transform))| *mesh as usize == i && *transform == Affine3A::IDENTITY)
+}
+
+/// Finds objects that are copies of an earlier object moved by a rigid transform (baked into world space in the OBJ)
+/// and returns the unique meshes and an instance per object. Objects are compared vertex by vertex in triangle order,
+/// which is how copies of the same mesh are usually exported. Each unique mesh stays in the world space of its first
+/// copy, so that one gets an identity transform.
+pub fn detect_instances(
+    objects: Vec<Vec<Triangle>>,
+    options: &Options,
+) -> (Vec<Vec<Triangle>>, Instances) {
+    let start_time = Instant::now();
+    let signatures = objects
+        .par_iter()
+        .map(|tris| Signature::new(tris))
+        .collect::<Vec<_>>();
+
+    // Meshes by triangle count
+    let mut candidates: HashMap<usize, Vec<u32>> = HashMap::new();
+    let mut meshes: Vec<Vec<Triangle>> = Vec::new();
+    let mut mesh_signatures: Vec<Signature> = Vec::new();
+    let mut instances = Vec::with_capacity(objects.len());
+    let object_tri_count = objects.iter().map(|o| o.len()).sum::<usize>();
+    for (tris, signature) in objects.into_iter().zip(signatures) {
+        let same_tri_count = candidates.entry(tris.len()).or_default();
+        let found = same_tri_count
+            .iter()
+            .filter(|mesh| mesh_signatures[**mesh as usize].may_match(&signature))
+            .find_map(|mesh| {
+                let tolerance = mesh_signatures[*mesh as usize].tolerance;
+                rigid_transform(&meshes[*mesh as usize], &tris, tolerance)
+                    .map(|transform| (*mesh, transform))
+            });
+        match found {
+            Some(instance) => instances.push(instance),
+            None => {
+                let mesh = meshes.len() as u32;
+                same_tri_count.push(mesh);
+                instances.push((mesh, Affine3A::IDENTITY));
+                meshes.push(tris);
+                mesh_signatures.push(signature);
+            }
+        }
+    }
+
+    if options.verbose || options.detect_instances {
+        let mesh_tri_count = meshes.iter().map(|m| m.len()).sum::<usize>();
+        let mb = |tris: usize| (tris * size_of::<Triangle>()) as f32 / (1024.0 * 1024.0);
+        println!(
+            "Detected {} unique meshes for {} objects in {:.2}ms. {} -> {} triangles, {:.2} MB of triangles saved",
+            meshes.len(),
+            instances.len(),
+            start_time.elapsed().as_secs_f32() * 1000.0,
+            object_tri_count,
+            mesh_tri_count,
+            mb(object_tri_count) - mb(mesh_tri_count),
+        );
+    }
+    (meshes, instances)
+}
+
+/// Cheap rigid transform invariant summary of an object. Only objects with the same triangle count whose signatures
+/// `may_match` are compared vertex by vertex.
+struct Signature {
+    /// Edge lengths of the first triangle.
+    first_edges: [f32; 3],
+    /// How far apart vertices can be and still match, `VERTEX_TOLERANCE` of the mesh bounds diagonal.
+    tolerance: f32,
+}
+
+impl Signature {
+    fn new(tris: &[Triangle]) -> Self {
+        Signature {
+            first_edges: tris.first().map_or([0.0; 3], |tri| {
+                [tri.v1 - tri.v0, tri.v2 - tri.v1, tri.v0 - tri.v2].map(|edge| edge.length())
+            }),
+            tolerance: mesh_diagonal(tris) * VERTEX_TOLERANCE,
+        }
+    }
+
+    /// If every vertex matches within the tolerance an edge can't be more than twice the tolerance longer or shorter.
+    /// Compared with a tolerance instead of hashed, so near equal copies can't end up on both sides of a bucket edge.
+    fn may_match(&self, other: &Signature) -> bool {
+        let tolerance = 2.0 * self.tolerance.max(other.tolerance);
+        self.first_edges
+            .iter()
+            .zip(&other.first_edges)
+            .all(|(a, b)| (a - b).abs() <= tolerance)
+    }
+}
+
+fn mesh_diagonal(tris: &[Triangle]) -> f32 {
+    let aabb = tris
+        .iter()
+        .fold(Aabb::INVALID, |aabb, tri| aabb.union(&tri.aabb()));
+    (aabb.max - aabb.min).length()
+}
+
+/// Orthonormal right handed frame from the edges of a triangle, `None` if it's degenerate.
+fn triangle_frame(tri: &Triangle) -> Option<Mat3A> {
+    let x = (tri.v1 - tri.v0).try_normalize()?;
+    let z = x.cross(tri.v2 - tri.v0).try_normalize()?;
+    Some(Mat3A::from_cols(x, z.cross(x), z))
+}
+
+/// Rotation and translation that moves `from` onto `to`, if every vertex matches within `tolerance`. The rotation is
+/// taken from the largest triangle of `from` to keep it stable.
+fn rigid_transform(from: &[Triangle], to: &[Triangle], tolerance: f32) -> Option<Affine3A> {
+    if from.len() != to.len() {
+        return None;
+    }
+    let (index, _) = from.iter().enumerate().max_by(|(_, a), (_, b)| {
+        let area = |tri: &Triangle| (tri.v1 - tri.v0).cross(tri.v2 - tri.v0).length_squared();
+        area(a).total_cmp(&area(b))
+    })?;
+    let rotation = triangle_frame(&to[index])? * triangle_frame(&from[index])?.transpose();
+    let transform = Affine3A::from_mat3_translation(
+        rotation.into(),
+        (to[index].v0 - rotation * from[index].v0).into(),
+    );
+
+    let matches = |a: Vec3A, b: Vec3A| transform.transform_point3a(a).distance(b) <= tolerance;
+    from.par_iter()
+        .zip(to)
+        .all(|(a, b)| matches(a.v0, b.v0) && matches(a.v1, b.v1) && matches(a.v2, b.v2))
+        .then_some(transform)
+}
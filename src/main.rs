@@ -9,11 +9,17 @@ use std::{
     f32,
     fs::File,
     io::BufReader,
+    mem::size_of_val,
     path::{Path, PathBuf},
     time::Duration,
 };
 
 use auto_tune::tune;
+use bvh_cache::cached_bvh2;
+use bvh_export::export_bvh_wireframe;
+use calibrate::{calibrate_costs, count_rays, RayCounts};
+use dynamic::dynamic_benchmark;
+use metrics::{metrics_tree_from_options, BvhMetrics, MetricsTree, SahCosts};
 
 use bytemuck::{Pod, Zeroable};
 
@@ -26,33 +32,59 @@ use obvhs::{
 use parry::ParryScene;
 use parry3d::partitioning::BvhBuildStrategy;
 use svenstaro::build_svenstaro_scene;
+use tlas_bench::tlas_rebuild_benchmark;
 use traversable::SceneRtTri;
 #[cfg(feature = "embree")]
 use traversable::SceneTri;
 
 mod auto_tune;
 pub mod binding_utils;
+mod bvh4;
+mod bvh_cache;
+mod bvh_export;
+mod calibrate;
+mod chunking;
 
 mod cwbvh;
+mod dynamic;
+mod instancing;
+mod metrics;
+mod node_layout;
+mod params_file;
 mod parry;
+mod refit;
 mod rt_cpu;
 mod rt_gpu;
 mod svenstaro;
+mod sweep_sah;
 mod timestamp;
 #[cfg(feature = "tinybvh")]
 mod tinybvh;
+mod tlas_bench;
 mod verbose;
 
+use bvh4::{Bvh4, Bvh4Scene};
+use chunking::chunk_objects;
+use instancing::{detect_instances, identity_instances};
+use node_layout::{layout_bvh2, layout_rays};
 use obj::Obj;
 #[cfg(feature = "embree")]
 use obvhs_embree::{
-    embree_managed::{embree_attach_geometry, EmbreeSceneAndObjects},
+    embree_managed::{
+        embree_attach_geometry, embree_build_instanced_scene, EmbreeInstancedSceneAndObjects,
+        EmbreeSceneAndObjects,
+    },
     new_embree_device,
 };
+use params_file::ParamsFile;
 use ron::de::from_reader;
-use rt_cpu::Bvh2Scene;
+use rt_cpu::{
+    heatmap::{heatmap, TraversalCountStats},
+    Bvh2Scene,
+};
 use rt_gpu::cwbvh_gpu_runner;
 use rt_gpu::rt_gpu_hardware;
+use sweep_sah::{build_sweep_sah_bvh2, SweepSahParams};
 
 use serde::{Deserialize, Serialize};
 use structopt::StructOpt;
@@ -62,7 +94,7 @@ use crate::verbose::setup_subscriber;
 
 use crate::rt_cpu::cwbvh_cpu_runner;
 
-#[derive(StructOpt, Clone)]
+#[derive(StructOpt, Clone, Debug)]
 #[structopt(name = "example-runner-wgpu")]
 pub struct Options {
     #[structopt(
@@ -81,8 +113,21 @@ pub struct Options {
         help = "Stop rendering the current scene after n seconds."
     )]
     render_time: f32,
-    #[structopt(long, default_value = "ploc_cwbvh", help = "Specify BVH builder", possible_values  = &["ploc_cwbvh", "ploc_bvh2", "embree_cwbvh", "embree_bvh2_cwbvh", "embree_managed", "svenstaro_bvh2", "parry_ploc", "parry_binned",  "tinybvh_bvh2", "tinybvh_cwbvh", "tinybvh_cwbvh_hq"])]
+    #[structopt(long, default_value = "ploc_cwbvh", help = "Specify BVH builder", possible_values  = &["ploc_cwbvh", "ploc_bvh2", "sweep_sah_bvh2", "sweep_sah_cwbvh", "sbvh_bvh2", "sbvh_cwbvh", "ploc_bvh4", "embree_cwbvh", "embree_bvh2_cwbvh", "embree_managed", "svenstaro_bvh2", "parry_ploc", "parry_binned",  "tinybvh_bvh2", "tinybvh_cwbvh", "tinybvh_cwbvh_hq"])]
     build: String,
+    #[structopt(
+        long,
+        default_value = "sse2",
+        possible_values = &["sse2", "sse4.2", "avx", "avx2", "avx512", "native"],
+        help = "Instruction set used by Embree. Defaults to sse2 since OBVHS does not yet have AVX support. `native` lets Embree pick the best one supported by the CPU."
+    )]
+    embree_isa: String,
+    #[structopt(
+        long,
+        default_value = "",
+        help = "Extra comma separated Embree device config options (eg. `frequency_level=simd256`)."
+    )]
+    embree_config: String,
     #[structopt(
         long,
         default_value = "14",
@@ -129,6 +174,12 @@ pub struct Options {
         help = "Overrides BVH build options.")
     ]
     preset: String,
+    #[structopt(
+        long,
+        default_value = "",
+        help = "RON file with the BvhBuildParams to use instead of the individual build options, like the ones --auto-tune writes for its Pareto front. The --build, --cpu and --rt-* options in the file are used too."
+    )]
+    params_file: String,
     #[structopt(long, help = "Prints misc info about BVH (depth, node count, etc..)")]
     verbose: bool,
     #[structopt(long, default_value = "1920", help = "Render resolution width.")]
@@ -144,6 +195,61 @@ pub struct Options {
         help = "Bypass model cache (eg. if not all models will fit in memory at once)"
     )]
     disable_auto_tune_model_cache: bool,
+    #[structopt(
+        long,
+        default_value = "assets/tuning/default.ron",
+        help = "RON file with the --auto-tune search space: the builders, the targets (cpu and/or gpu) and a list or range of values for each build parameter."
+    )]
+    tune_spec: String,
+    #[structopt(
+        long,
+        default_value = "grid",
+        possible_values = &["grid", "random", "coordinate", "halving", "tpe"],
+        help = "How --auto-tune searches the --tune-spec space. grid evaluates every permutation, random samples without repeats, coordinate tunes one parameter at a time until nothing improves, halving runs --tune-halving-configs random configurations with a fraction of --render-time and keeps the best third for each longer round, tpe is a Tree-structured Parzen Estimator."
+    )]
+    tune_strategy: String,
+    #[structopt(
+        long,
+        default_value = "0",
+        help = "Stop --auto-tune after this many evaluations. The shorter evaluations of --tune-strategy halving count with their fraction of --render-time. 0 for no limit."
+    )]
+    tune_max_evals: usize,
+    #[structopt(
+        long,
+        default_value = "0.0",
+        help = "Stop --auto-tune after this many seconds. The current evaluation is finished first. 0 for no limit."
+    )]
+    tune_max_time: f32,
+    #[structopt(
+        long,
+        default_value = "traversal=1",
+        help = "Weights of the --auto-tune objectives, like traversal=1,memory=0.5. Objectives are traversal, blas_build, tlas_build and memory, left out ones get 0. Results are ranked by the weighted sum of the log of each objective normalized per scene, and the coordinate, halving and tpe --tune-strategy minimize it."
+    )]
+    tune_weights: String,
+    #[structopt(
+        long,
+        default_value = "traversal,blas_build,tlas_build,memory",
+        help = "Objectives of the --auto-tune Pareto front."
+    )]
+    tune_pareto: String,
+    #[structopt(
+        long,
+        default_value = "27",
+        help = "Number of random configurations the first round of --tune-strategy halving starts with."
+    )]
+    tune_halving_configs: usize,
+    #[structopt(
+        long,
+        default_value = "0",
+        help = "Seed for the random, halving and tpe --tune-strategy."
+    )]
+    tune_seed: u64,
+    #[structopt(
+        long,
+        default_value = "",
+        help = "Continue an --auto-tune run from its tune_checkpoint_*.csv. Evaluations in the file are not rendered again and new ones are appended to it. The other options (except --tune-max-evals and --tune-max-time) and the --tune-spec file have to match the interrupted run."
+    )]
+    tune_resume: String,
     #[structopt(
         long,
         help = "Save a png of the rendered frame. (Currently only cpu mode)"
@@ -168,13 +274,221 @@ pub struct Options {
         help = "How many times to run the full benchmark. Reported times will be averaged."
     )]
     passes: usize,
+    #[structopt(
+        long,
+        default_value = "0",
+        help = "Animate the scene for n frames and compare BVH refit against rebuild instead of rendering. Uses the obvhs PLOC builders, other --build values are rejected. 0 to disable."
+    )]
+    dynamic_frames: usize,
+    #[structopt(
+        long,
+        default_value = "displace",
+        possible_values = &["displace", "instances"],
+        help = "Animation used with --dynamic-frames. `displace` procedurally displaces vertices, `instances` moves objects with the transforms from --animation-sequence."
+    )]
+    animation: String,
+    #[structopt(
+        long,
+        default_value = "",
+        help = "RON file with a list of object transforms for each frame. Used with `--animation instances`."
+    )]
+    animation_sequence: String,
+    #[structopt(
+        long,
+        default_value = "0.01",
+        help = "Vertex displacement amplitude relative to the scene size for `--animation displace`."
+    )]
+    displacement: f32,
+    #[structopt(
+        long,
+        help = "Move instances every frame and measure the TLAS rebuild and upload cost instead of rendering."
+    )]
+    tlas_bench: bool,
+    #[structopt(
+        long,
+        default_value = "1000,10000,100000",
+        help = "Comma separated instance counts for --tlas-bench."
+    )]
+    tlas_bench_instances: String,
+    #[structopt(
+        long,
+        default_value = "100",
+        help = "Frames per instance count for --tlas-bench. The first 10 are not included in the averages."
+    )]
+    tlas_bench_frames: usize,
+    #[structopt(
+        long,
+        default_value = "",
+        help = "Directory for caching built BVHs. BVHs are keyed by a hash of the triangles, builder and build params, and loaded instead of rebuilt when they match. Build times are 0 for cached BVHs."
+    )]
+    bvh_cache: String,
+    #[structopt(
+        long,
+        default_value = "target/shader_cache",
+        help = "Directory for the SPIR-V compiled by dxc. Files are keyed by a hash of the shader sources, includes and defines, so variants are kept side by side and only recompiled when they change."
+    )]
+    shader_cache: String,
+    #[structopt(
+        long,
+        help = "Compute BVH quality metrics (SAH cost, EPO, depth and leaf statistics) and print them next to the traversal times. EPO can take a while on large scenes. Not available for embree_managed, tinybvh_cwbvh on the CPU, hardware RT and --detect-instances."
+    )]
+    metrics: bool,
+    #[structopt(
+        long,
+        default_value = "1.0",
+        help = "Node traversal cost used for SAH cost and EPO in --metrics and the dynamic benchmark."
+    )]
+    sah_traversal_cost: f32,
+    #[structopt(
+        long,
+        default_value = "1.0",
+        help = "Primitive intersection cost used for SAH cost and EPO in --metrics and the dynamic benchmark."
+    )]
+    sah_intersection_cost: f32,
+    #[structopt(
+        long,
+        help = "Build trees with a range of --max-prims-per-leaf and --collapse-traversal-cost with --build, render them on the --calibrate-targets and fit the cost of a node visit and of a primitive test by regression. Prints the fitted --sah-traversal-cost per target. Needs a builder with --metrics support."
+    )]
+    calibrate_costs: bool,
+    #[structopt(
+        long,
+        default_value = "cpu,gpu",
+        help = "Targets for --calibrate-costs, cpu and/or gpu (software traversal)."
+    )]
+    calibrate_targets: String,
+    #[structopt(
+        long,
+        default_value = "",
+        help = "Write the node bounds of the BVH to this .obj or .ply file as a wireframe. The scene name is appended when there are multiple inputs."
+    )]
+    export_bvh: String,
+    #[structopt(
+        long,
+        default_value = "0",
+        help = "Only export nodes at or below this depth with --export-bvh."
+    )]
+    export_min_depth: usize,
+    #[structopt(
+        long,
+        help = "Only export nodes at or above this depth with --export-bvh."
+    )]
+    export_max_depth: Option<usize>,
+    #[structopt(
+        long,
+        default_value = "",
+        help = "Only export the subtree at this comma separated path of child slots from the root with --export-bvh, e.g. `1,0`. Empty slots are skipped when counting."
+    )]
+    export_subtree: String,
+    #[structopt(long, help = "Only export leaves with --export-bvh.")]
+    export_leaves_only: bool,
+    #[structopt(
+        long,
+        default_value = "depth",
+        possible_values = &["depth", "sah"],
+        help = "Colour exported nodes by depth or by their contribution to the SAH cost."
+    )]
+    export_color: String,
+    #[structopt(
+        long,
+        help = "After rendering, trace primary rays counting bounds and primitive tests and save them as heatmap PNGs with a CSV histogram. The counts are approximate, they come from a plain stack traversal of the BVH rather than the obvhs one. CPU only, for the ploc, sweep_sah and sbvh BVH2 builders and the CWBVH builders."
+    )]
+    heatmap: bool,
+    #[structopt(
+        long,
+        default_value = "500",
+        help = "Bounds tests per ray shown as the hottest colour in the --heatmap image."
+    )]
+    heatmap_aabb_scale: f32,
+    #[structopt(
+        long,
+        default_value = "100",
+        help = "Primitive tests per ray shown as the hottest colour in the --heatmap image."
+    )]
+    heatmap_tri_scale: f32,
+    #[structopt(
+        long,
+        help = "Compile the GPU software RT shader with PROFILE_RT. Shows the traversal heatmap, and on exit reads back the per pixel counts of the primary and the AO ray and saves and summarizes them per ray like --heatmap. Timings are not representative in this mode."
+    )]
+    profile_rt: bool,
+    #[structopt(
+        long,
+        help = "Use the WGSL version of the GPU software RT shaders instead of compiling the HLSL with dxc. Doesn't need dxc or SPIR-V passthrough and renders the same image."
+    )]
+    wgsl: bool,
+    #[structopt(
+        long,
+        default_value = "5",
+        help = "Traversal stack entries kept in registers by the GPU software RT shaders (RT_VGPR_STACK_SIZE). The rest of the stack is in groupshared memory."
+    )]
+    rt_vgpr_stack_size: u32,
+    #[structopt(
+        long,
+        default_value = "4",
+        help = "Traversal stack entries per thread in groupshared memory (LDS) for the GPU software RT shaders (RT_LDS_STACK_SIZE). The sum with --rt-vgpr-stack-size must be at least 9, the shaders don't check for stack overflow."
+    )]
+    rt_lds_stack_size: u32,
+    #[structopt(
+        long,
+        default_value = "8",
+        help = "Workgroup width in pixels of the GPU software RT shaders"
+    )]
+    rt_group_width: u32,
+    #[structopt(
+        long,
+        default_value = "8",
+        help = "Workgroup height in pixels of the GPU software RT shaders"
+    )]
+    rt_group_height: u32,
+    #[structopt(
+        long,
+        default_value = "rt",
+        possible_values = &["rt", "compressed"],
+        help = "Triangle storage for the --cpu CWBVH builders. rt is RtTriangle, compressed is the smaller RtCompressedTriangle (f16 edges) the GPU software path uses. The memory used by each is printed."
+    )]
+    cpu_tris: String,
+    #[structopt(
+        long,
+        default_value = "builder",
+        possible_values = &["builder", "dfs", "bfs", "veb", "hot"],
+        help = "Reorder the BVH nodes after building. builder keeps the order the builder wrote, veb is a van Emde Boas (cache oblivious) layout, hot writes the nodes most visited by a subset of the camera rays first. For the CWBVH builders and the ploc, sweep_sah and sbvh BVH2 builders."
+    )]
+    node_layout: String,
+    #[structopt(
+        long,
+        default_value = "0",
+        help = "With --tlas, split all the triangles of the scene spatially into this many objects, each with its own BLAS. 0 keeps the objects from the file."
+    )]
+    chunks: usize,
+    #[structopt(
+        long,
+        default_value = "morton",
+        possible_values = &["morton", "kmeans"],
+        help = "How --chunks partitions the triangles. morton cuts the Morton ordered centroids into equal ranges, kmeans clusters the centroids."
+    )]
+    chunk_method: String,
+    #[structopt(
+        long,
+        help = "Build the BLAS of all objects concurrently. The BLAS build time is then the wall time of all of them together (including loading from --bvh-cache). Not supported with the Embree builders."
+    )]
+    parallel_blas: bool,
+    #[structopt(
+        long,
+        help = "With --tlas, find objects that are copies of another object moved by a rigid transform and build them as instances of one BLAS. Prints the memory saved. Not supported with --chunks or --hardware."
+    )]
+    detect_instances: bool,
 }
 
 pub fn main() {
     //std::env::set_var("WGPU_POWER_PREF", "low");
 
     let mut event_loop = winit::event_loop::EventLoop::new().unwrap();
-    let init_options: Options = Options::from_args();
+    let mut init_options: Options = Options::from_args();
+    if !init_options.params_file.is_empty() {
+        if !init_options.preset.is_empty() {
+            panic!("--params-file and --preset can't be used together");
+        }
+        ParamsFile::load(&init_options.params_file).apply(&mut init_options);
+    }
     if init_options.build.contains("cwbvh") && init_options.max_prims_per_leaf > 3 {
         panic!("CWBVH only supports a maximum of 3 primitives per leaf.")
     }
@@ -182,7 +496,13 @@ pub fn main() {
         setup_subscriber();
     }
 
-    if !init_options.auto_tune {
+    if init_options.dynamic_frames > 0 {
+        dynamic_benchmark(&init_options);
+    } else if init_options.tlas_bench {
+        tlas_rebuild_benchmark(&init_options);
+    } else if init_options.calibrate_costs {
+        calibrate_costs(init_options, event_loop);
+    } else if !init_options.auto_tune {
         let mut passes_stats = vec![vec![]; init_options.passes];
         let passes = init_options.passes as f32;
         for stats in &mut passes_stats {
@@ -192,9 +512,14 @@ pub fn main() {
         for stat_n in 0..passes_stats[0].len() {
             let mut avg_stat = Stats {
                 name: passes_stats[0][stat_n].name.clone(),
+                build: passes_stats[0][stat_n].build.clone(),
                 traversal_ms: 0.0,
                 blas_build_time_s: 0.0,
                 tlas_build_time_ms: 0.0,
+                bvh_memory_mb: passes_stats[0][stat_n].bvh_memory_mb,
+                ray_counts: passes_stats[0][stat_n].ray_counts,
+                traversal_counts: passes_stats[0][stat_n].traversal_counts,
+                metrics: passes_stats[0][stat_n].metrics.clone(),
             };
             for pass_n in 0..init_options.passes {
                 let stat = &passes_stats[pass_n][stat_n];
@@ -204,12 +529,60 @@ pub fn main() {
             }
             avg_stats.push(avg_stat);
         }
-        println!("{}", Table::new(avg_stats).with(Style::blank()));
+        println!("{}", Table::new(&avg_stats).with(Style::blank()));
+        if init_options.metrics {
+            print_metrics(&avg_stats, &init_options);
+        }
+        print_traversal_counts(&avg_stats);
     } else {
         tune(init_options, event_loop);
     }
 }
 
+/// Every `--build` as (name, has a CPU path, has a GPU software path, supports `--tlas`). Has to match the builders
+/// `render_from_options` dispatches on, it's used to reject unsupported combinations up front and by auto-tune to skip
+/// them.
+const BUILDERS: [(&str, bool, bool, bool); 16] = [
+    ("ploc_cwbvh", true, true, true),
+    ("ploc_bvh2", true, false, false),
+    ("sweep_sah_bvh2", true, false, false),
+    ("sweep_sah_cwbvh", true, true, true),
+    ("sbvh_bvh2", true, false, false),
+    ("sbvh_cwbvh", true, true, true),
+    ("ploc_bvh4", true, false, false),
+    ("embree_cwbvh", true, true, true),
+    ("embree_bvh2_cwbvh", true, true, false),
+    ("embree_managed", true, false, true),
+    ("svenstaro_bvh2", true, false, false),
+    ("parry_ploc", true, false, false),
+    ("parry_binned", true, false, false),
+    ("tinybvh_bvh2", true, false, false),
+    ("tinybvh_cwbvh", true, true, false),
+    ("tinybvh_cwbvh_hq", false, true, false),
+];
+
+fn builder(build: &str) -> (bool, bool, bool) {
+    BUILDERS
+        .iter()
+        .find(|(name, ..)| *name == build)
+        .map(|(_, cpu, gpu, tlas)| (*cpu, *gpu, *tlas))
+        .unwrap_or_else(|| panic!("Unknown builder {}", build))
+}
+
+/// If `build` can be traversed on the CPU (`cpu`) or with the GPU software path.
+pub fn build_has_target(build: &str, cpu: bool) -> bool {
+    let (has_cpu, has_gpu, _) = builder(build);
+    if cpu {
+        has_cpu
+    } else {
+        has_gpu
+    }
+}
+
+pub fn build_supports_tlas(build: &str) -> bool {
+    builder(build).2
+}
+
 fn render_from_options(
     options: &Options,
     event_loop: &mut winit::event_loop::EventLoop<()>,
@@ -220,82 +593,19 @@ fn render_from_options(
         println!("Note --benchmark runs additional dispatches to try to further normalize time stamp queries. Frame times seen by external programs will be much higher.")
     }
 
-    #[cfg(feature = "parallel_build")]
-    #[allow(unused_variables)]
-    let threads = std::thread::available_parallelism().unwrap().get();
-    #[cfg(not(feature = "parallel_build"))]
-    #[allow(unused_variables)]
-    let threads = 1;
-
     // Don't use raw_device after embree_device is dropped
     #[cfg(feature = "embree")]
     let embree_device = match options.build.as_str() {
         "embree_bvh2_cwbvh" | "embree_cwbvh" | "embree_managed" => {
-            Some(new_embree_device(threads, options.verbose, true))
+            Some(embree_device_from_options(options))
         }
         _ => None,
     };
 
     let inputs = options.input.split(",").collect::<Vec<_>>();
     for input in &inputs {
-        let file_name;
-        let mut scene: Scene;
-
-        let mut objects = if *input == "demoscene" {
-            // TODO use tlas
-            file_name = "demoscene";
-            scene = Scene {
-                model_path: String::new(),
-                camera: Camera {
-                    eye: vec3(0.0, 0.0, 1.35),
-                    fov: 17.0,
-                    look_at: vec3(0.0, 0.16, 0.35),
-                    exposure: 0.0,
-                },
-                sun_direction: vec3(0.35, -0.1, 0.19).into(),
-            };
-            vec![demoscene(2048, 0)]
-        } else {
-            let f = File::open(&input).expect("Failed opening file");
-
-            scene = match from_reader(f) {
-                Ok(x) => x,
-                Err(e) => {
-                    println!("Failed to load config: {}", e);
-
-                    std::process::exit(1);
-                }
-            };
-            scene.sun_direction = scene.sun_direction.normalize_or_zero();
-
-            let scene_path = Path::new(&input);
-            let mut model_path = Path::new(&scene.model_path).to_path_buf();
-            if scene_path.is_relative() && model_path.is_relative() {
-                // Cursed
-                // If we got a relative path to both the scene and the model, assume the path to the model is relative to the path to the scene
-                model_path = scene_path
-                    .parent()
-                    .unwrap()
-                    .parent()
-                    .unwrap()
-                    .parent()
-                    .unwrap()
-                    .join(model_path);
-            }
-
-            file_name = scene_path.file_stem().unwrap().to_str().unwrap();
-            if let Some(model_cache) = model_cache {
-                if let Some(objects) = model_cache.get(&model_path) {
-                    objects.clone()
-                } else {
-                    let objects = load_meshs(&model_path);
-                    model_cache.insert(model_path.clone(), objects.clone());
-                    objects
-                }
-            } else {
-                load_meshs(&model_path)
-            }
-        };
+        let (file_name, scene, mut objects) = load_scene(input, model_cache);
+        let file_name = file_name.as_str();
 
         if !options.tlas || options.flatten_blas {
             // Flatten tris into first object.
@@ -307,6 +617,26 @@ fn render_from_options(
                 .collect::<Vec<_>>()];
         }
 
+        if options.chunks > 0 {
+            if options.tlas && !options.flatten_blas {
+                objects = chunk_objects(objects, options);
+            } else {
+                println!("--chunks needs --tlas without --flatten-blas");
+            }
+        }
+
+        // One instance per object unless --detect-instances finds copies
+        let mut instances = identity_instances(objects.len());
+        if options.detect_instances {
+            if options.tlas && !options.flatten_blas && options.chunks == 0 && !options.hardware {
+                (objects, instances) = detect_instances(objects, options);
+            } else {
+                println!(
+                    "--detect-instances needs --tlas without --flatten-blas, --chunks or --hardware"
+                );
+            }
+        }
+
         if options.verbose {
             println!("{} objects {:?}", objects.len(), file_name);
             if !options.tlas {
@@ -314,14 +644,29 @@ fn render_from_options(
             }
         }
 
+        let camera = scene.camera.clone();
         let frame_time;
         let mut blas_build_time = Duration::ZERO;
         let mut tlas_build_time = Duration::ZERO;
+        // Not measured for the external and hardware builders
+        let mut bvh_memory = 0;
+        let mut metrics_tree = None;
+        let mut traversal_counts = None;
 
         if options.hardware {
             frame_time =
                 rt_gpu_hardware::start(event_loop, &options, &scene, &objects, options.render_time);
         } else {
+            if !build_has_target(&options.build, options.cpu) {
+                panic!(
+                    "{} is {} only",
+                    options.build,
+                    if options.cpu { "GPU" } else { "--cpu" }
+                );
+            }
+            if options.tlas && !build_supports_tlas(&options.build) {
+                panic!("{} doesn't support --tlas", options.build);
+            }
             frame_time = if options.cpu {
                 let build = options.build.as_str();
                 match build {
@@ -329,21 +674,7 @@ fn render_from_options(
                         #[cfg(feature = "embree")]
                         {
                             let device = embree_device.as_ref().unwrap();
-                            let embree_scene =
-                                embree4_rs::Scene::try_new(&device, Default::default()).unwrap();
-                            embree_scene
-                                .set_build_quality(embree4_sys::RTCBuildQuality::HIGH)
-                                .unwrap();
-                            embree_attach_geometry(
-                                &objects,
-                                device,
-                                &embree_scene,
-                                &mut blas_build_time,
-                            );
-                            let start_time = std::time::Instant::now();
-                            let committed_scene = embree_scene.commit().unwrap();
-                            blas_build_time += start_time.elapsed();
-                            let objects = objects
+                            let scene_tris = objects
                                 .iter()
                                 .map(|mesh| {
                                     mesh.iter()
@@ -351,41 +682,133 @@ fn render_from_options(
                                         .collect::<Vec<_>>()
                                 })
                                 .collect::<Vec<_>>();
-                            rt_cpu::rt_cpu::start(
-                                file_name,
-                                &options,
-                                &scene,
-                                &EmbreeSceneAndObjects {
-                                    scene: &committed_scene,
-                                    objects: &objects,
-                                },
-                            )
+                            if options.tlas {
+                                // Each object gets its own Embree scene and is attached to the top level scene as
+                                // an instance, so Embree's two-level traversal is used.
+                                let instanced_scene = embree_build_instanced_scene(
+                                    &objects,
+                                    &instances,
+                                    device,
+                                    &mut blas_build_time,
+                                    &mut tlas_build_time,
+                                );
+                                rt_cpu::rt_cpu::start(
+                                    file_name,
+                                    &options,
+                                    &scene,
+                                    &EmbreeInstancedSceneAndObjects {
+                                        scene: &instanced_scene,
+                                        objects: &scene_tris,
+                                    },
+                                )
+                            } else {
+                                let embree_scene =
+                                    embree4_rs::Scene::try_new(&device, Default::default())
+                                        .unwrap();
+                                embree_scene
+                                    .set_build_quality(embree4_sys::RTCBuildQuality::HIGH)
+                                    .unwrap();
+                                embree_attach_geometry(
+                                    &objects,
+                                    device,
+                                    &embree_scene,
+                                    &mut blas_build_time,
+                                );
+                                let start_time = std::time::Instant::now();
+                                let committed_scene = embree_scene.commit().unwrap();
+                                blas_build_time += start_time.elapsed();
+                                rt_cpu::rt_cpu::start(
+                                    file_name,
+                                    &options,
+                                    &scene,
+                                    &EmbreeSceneAndObjects {
+                                        scene: &committed_scene,
+                                        objects: &scene_tris,
+                                    },
+                                )
+                            }
                         }
                         #[cfg(not(feature = "embree"))]
                         panic!("Need to enable embree feature")
                     }
-                    "ploc_bvh2" => {
+                    "ploc_bvh2" | "sweep_sah_bvh2" | "sbvh_bvh2" => {
                         if options.tlas {
-                            todo!("ploc bvh2 TLAS not yet implemented")
+                            todo!("{} TLAS not yet implemented", build)
                         }
-                        let bvh = build_bvh2_from_tris(
-                            &objects[0],
-                            build_params_from_options(&options),
-                            &mut blas_build_time,
-                        );
+                        let mut bvh = cached_bvh2(&objects[0], options, || {
+                            if build == "ploc_bvh2" {
+                                build_bvh2_from_tris(
+                                    &objects[0],
+                                    build_params_from_options(&options),
+                                    &mut blas_build_time,
+                                )
+                            } else {
+                                build_sweep_sah_bvh2(
+                                    &objects[0],
+                                    SweepSahParams::from_options(options),
+                                    &mut blas_build_time,
+                                )
+                            }
+                        });
                         if options.verbose {
-                            println!("{}", bvh.validate(&objects[0], options.split, false));
+                            println!(
+                                "{}",
+                                bvh.validate(
+                                    &objects[0],
+                                    options.split || bvh.uses_spatial_splits,
+                                    false
+                                )
+                            );
                         }
+                        layout_bvh2(
+                            &mut bvh,
+                            &objects[0],
+                            options,
+                            &layout_rays(options, &scene),
+                        );
+                        metrics_tree =
+                            metrics_tree_from_options(options, || MetricsTree::from_bvh2(&bvh));
                         let rt_triangles = bvh
                             .primitive_indices
                             .iter()
                             .map(|i| SceneRtTri((&objects[0][*i as usize]).into()))
                             .collect::<Vec<SceneRtTri>>();
+                        bvh_memory = size_of_val(bvh.nodes.as_slice())
+                            + size_of_val(rt_triangles.as_slice());
+                        let bvh2_scene = Bvh2Scene {
+                            bvh: &bvh,
+                            tris: rt_triangles.as_slice(),
+                        };
+                        let frame_time =
+                            rt_cpu::rt_cpu::start(file_name, &options, &scene, &bvh2_scene);
+                        traversal_counts = options
+                            .heatmap
+                            .then(|| heatmap(file_name, &options, &scene, &bvh2_scene));
+                        frame_time
+                    }
+                    "ploc_bvh4" => {
+                        let bvh2 = cached_bvh2(&objects[0], options, || {
+                            build_bvh2_from_tris(
+                                &objects[0],
+                                build_params_from_options(&options),
+                                &mut blas_build_time,
+                            )
+                        });
+                        let bvh = Bvh4::from_bvh2(&bvh2, &mut blas_build_time);
+                        metrics_tree =
+                            metrics_tree_from_options(options, || MetricsTree::from_bvh4(&bvh));
+                        let rt_triangles = bvh
+                            .primitive_indices
+                            .iter()
+                            .map(|i| SceneRtTri((&objects[0][*i as usize]).into()))
+                            .collect::<Vec<SceneRtTri>>();
+                        bvh_memory = size_of_val(bvh.nodes.as_slice())
+                            + size_of_val(rt_triangles.as_slice());
                         rt_cpu::rt_cpu::start(
                             file_name,
                             &options,
                             &scene,
-                            &Bvh2Scene {
+                            &Bvh4Scene {
                                 bvh: &bvh,
                                 tris: rt_triangles.as_slice(),
                             },
@@ -396,6 +819,9 @@ fn render_from_options(
                             todo!("svenstaro bvh2 TLAS not implemented")
                         }
                         let svenstaro_scene = build_svenstaro_scene(&objects, &mut blas_build_time);
+                        metrics_tree = metrics_tree_from_options(options, || {
+                            MetricsTree::from_svenstaro(&svenstaro_scene.bvh, &objects[0])
+                        });
                         rt_cpu::rt_cpu::start(file_name, &options, &scene, &svenstaro_scene)
                     }
                     "parry_ploc" | "parry_binned" => {
@@ -409,6 +835,9 @@ fn render_from_options(
                         };
                         let parry_scene =
                             ParryScene::new(&objects[0], build_strat, &mut blas_build_time);
+                        metrics_tree = metrics_tree_from_options(options, || {
+                            MetricsTree::from_parry(&parry_scene.bvh)
+                        });
                         rt_cpu::rt_cpu::start(file_name, &options, &scene, &parry_scene)
                     }
                     "tinybvh_bvh2" => {
@@ -419,6 +848,9 @@ fn render_from_options(
                             }
                             let tinybvh_scene =
                                 tinybvh::TinyBvhScene::new(&objects[0], &mut blas_build_time);
+                            metrics_tree = metrics_tree_from_options(options, || {
+                                MetricsTree::from_tinybvh(&tinybvh_scene.bvh)
+                            });
                             rt_cpu::rt_cpu::start(file_name, &options, &scene, &tinybvh_scene)
                         }
                         #[cfg(not(feature = "tinybvh"))]
@@ -441,11 +873,16 @@ fn render_from_options(
                         #[cfg(not(feature = "tinybvh"))]
                         panic!("Need to enable tinybvh feature")
                     }
-                    "embree_cwbvh" | "embree_bvh2_cwbvh" | "ploc_cwbvh" => cwbvh_cpu_runner(
+                    "embree_cwbvh" | "embree_bvh2_cwbvh" | "ploc_cwbvh" | "sweep_sah_cwbvh"
+                    | "sbvh_cwbvh" => cwbvh_cpu_runner(
                         &objects,
+                        &instances,
                         options,
                         &mut blas_build_time,
                         &mut tlas_build_time,
+                        &mut bvh_memory,
+                        &mut metrics_tree,
+                        &mut traversal_counts,
                         file_name,
                         scene,
                         #[cfg(feature = "embree")]
@@ -454,42 +891,177 @@ fn render_from_options(
                     _ => panic!("No builder specified"),
                 }
             } else {
-                if options.build == "embree_managed" || options.build == "ploc_bvh2" {
-                    panic!("{} is --cpu only", options.build);
-                }
                 cwbvh_gpu_runner(
                     event_loop,
                     &objects,
+                    &instances,
                     options,
                     &mut blas_build_time,
                     &mut tlas_build_time,
+                    &mut bvh_memory,
+                    &mut metrics_tree,
+                    &mut traversal_counts,
+                    file_name,
                     scene,
                     #[cfg(feature = "embree")]
                     embree_device.as_ref(),
                 )
             };
         }
+        if options.profile_rt && (options.cpu || options.hardware) {
+            println!("--profile-rt is only available for the GPU software path");
+        }
+        if options.heatmap
+            && !(options.cpu
+                && !options.hardware
+                && [
+                    "ploc_bvh2",
+                    "sweep_sah_bvh2",
+                    "sbvh_bvh2",
+                    "ploc_cwbvh",
+                    "sweep_sah_cwbvh",
+                    "sbvh_cwbvh",
+                    "embree_cwbvh",
+                    "embree_bvh2_cwbvh",
+                ]
+                .contains(&options.build.as_str()))
+        {
+            println!("--heatmap is not available for {}", build_label(options));
+        }
+        if metrics_tree.is_none() && !options.export_bvh.is_empty() {
+            println!("BVH export is not available for {}", build_label(options));
+        }
+        let ray_counts = options
+            .calibrate_costs
+            .then(|| {
+                metrics_tree
+                    .as_ref()
+                    .map(|tree| count_rays(tree, &objects.concat(), &camera, options))
+            })
+            .flatten();
+        let metrics = metrics_tree.and_then(|tree| {
+            if !options.export_bvh.is_empty() {
+                export_bvh_wireframe(&tree, options, file_name);
+            }
+            options.metrics.then(|| {
+                BvhMetrics::new(&tree, &objects.concat(), &SahCosts::from_options(options))
+            })
+        });
         stats.push(Stats {
             name: file_name.to_string(),
+            build: build_label(options),
             traversal_ms: frame_time,
             blas_build_time_s: blas_build_time.as_secs_f32(),
             tlas_build_time_ms: (tlas_build_time).as_secs_f32() * 1000.0, // Convert to ms
+            bvh_memory_mb: bvh_memory as f32 / (1024.0 * 1024.0),
+            metrics,
+            ray_counts,
+            traversal_counts,
         });
     }
     let len = stats.len() as f32;
     let avg_traversal = stats.iter().map(|s| s.traversal_ms).sum::<f32>() / len;
     let avg_blas_build = stats.iter().map(|s| s.blas_build_time_s).sum::<f32>() / len;
     let avg_tlas_build = stats.iter().map(|s| s.tlas_build_time_ms).sum::<f32>() / len;
+    let avg_bvh_memory = stats.iter().map(|s| s.bvh_memory_mb).sum::<f32>() / len;
     stats.push(Stats {
         name: String::from("Avg"),
+        build: build_label(options),
         traversal_ms: avg_traversal,
         blas_build_time_s: avg_blas_build,
         tlas_build_time_ms: avg_tlas_build,
+        bvh_memory_mb: avg_bvh_memory,
+        metrics: None,
+        ray_counts: None,
+        traversal_counts: None,
     });
 
     (avg_traversal, avg_blas_build, avg_tlas_build)
 }
 
+/// Loads the scene config and its objects. Objects are cached by model path if a `model_cache` is given.
+fn load_scene(
+    input: &str,
+    model_cache: &mut Option<HashMap<PathBuf, Vec<Vec<Triangle>>>>,
+) -> (String, Scene, Vec<Vec<Triangle>>) {
+    let file_name;
+    let mut scene: Scene;
+
+    let objects = if input == "demoscene" {
+        // TODO use tlas
+        file_name = "demoscene";
+        scene = Scene {
+            model_path: String::new(),
+            camera: Camera {
+                eye: vec3(0.0, 0.0, 1.35),
+                fov: 17.0,
+                look_at: vec3(0.0, 0.16, 0.35),
+                exposure: 0.0,
+            },
+            sun_direction: vec3(0.35, -0.1, 0.19).into(),
+        };
+        vec![demoscene(2048, 0)]
+    } else {
+        let f = File::open(&input).expect("Failed opening file");
+
+        scene = match from_reader(f) {
+            Ok(x) => x,
+            Err(e) => {
+                println!("Failed to load config: {}", e);
+
+                std::process::exit(1);
+            }
+        };
+        scene.sun_direction = scene.sun_direction.normalize_or_zero();
+
+        let scene_path = Path::new(&input);
+        let mut model_path = Path::new(&scene.model_path).to_path_buf();
+        if scene_path.is_relative() && model_path.is_relative() {
+            // Cursed
+            // If we got a relative path to both the scene and the model, assume the path to the model is relative to the path to the scene
+            model_path = scene_path
+                .parent()
+                .unwrap()
+                .parent()
+                .unwrap()
+                .parent()
+                .unwrap()
+                .join(model_path);
+        }
+
+        file_name = scene_path.file_stem().unwrap().to_str().unwrap();
+        if let Some(model_cache) = model_cache {
+            if let Some(objects) = model_cache.get(&model_path) {
+                objects.clone()
+            } else {
+                let objects = load_meshs(&model_path);
+                model_cache.insert(model_path.clone(), objects.clone());
+                objects
+            }
+        } else {
+            load_meshs(&model_path)
+        }
+    };
+
+    (file_name.to_string(), scene, objects)
+}
+
+/// Embree device using all available threads if built with `parallel_build`.
+#[cfg(feature = "embree")]
+fn embree_device_from_options(options: &Options) -> embree4_rs::Device {
+    #[cfg(feature = "parallel_build")]
+    let threads = std::thread::available_parallelism().unwrap().get();
+    #[cfg(not(feature = "parallel_build"))]
+    let threads = 1;
+
+    new_embree_device(
+        threads,
+        options.verbose,
+        (options.embree_isa != "native").then_some(options.embree_isa.as_str()),
+        &options.embree_config,
+    )
+}
+
 #[profiling::function]
 fn load_meshs(model_path: &Path) -> Vec<Vec<Triangle>> {
     if model_path
@@ -560,6 +1132,45 @@ fn load_meshs(model_path: &Path) -> Vec<Vec<Triangle>> {
     }
 }
 
+/// Builder name as reported in the results. Embree builders include the ISA and device config so runs with different
+/// ISAs can sit in the same table.
+fn build_label(options: &Options) -> String {
+    let mut label = builder_label(options);
+    if options.node_layout != "builder" && !options.hardware {
+        label += &format!(" {} layout", options.node_layout);
+    }
+    if options.chunks > 0 && options.tlas && !options.flatten_blas {
+        label += &format!(" {} {} chunks", options.chunks, options.chunk_method);
+    }
+    if options.parallel_blas {
+        label += " parallel blas";
+    }
+    if options.wgsl && !options.cpu && !options.hardware {
+        label += " wgsl";
+    }
+    if options.detect_instances && options.tlas && !options.flatten_blas && options.chunks == 0 {
+        label += " instanced";
+    }
+    label
+}
+
+fn builder_label(options: &Options) -> String {
+    if options.hardware {
+        String::from("hardware")
+    } else if options.build.starts_with("embree") && options.embree_config.is_empty() {
+        format!("{} ({})", options.build, options.embree_isa)
+    } else if options.build.starts_with("embree") {
+        format!(
+            "{} ({}, {})",
+            options.build, options.embree_isa, options.embree_config
+        )
+    } else if options.cpu && options.cpu_tris != "rt" && options.build.ends_with("cwbvh") {
+        format!("{} ({} tris)", options.build, options.cpu_tris)
+    } else {
+        options.build.clone()
+    }
+}
+
 fn build_params_from_options(options: &Options) -> BvhBuildParams {
     match options.preset.as_str() {
         "fastest_build" => BvhBuildParams::fastest_build(),
@@ -634,9 +1245,62 @@ pub struct Scene {
 #[derive(Tabled, Clone)]
 struct Stats {
     name: String,
+    build: String,
     traversal_ms: f32,
     blas_build_time_s: f32,
     tlas_build_time_ms: f32,
+    /// Nodes and triangles as used during traversal, 0 if not measured for the builder
+    bvh_memory_mb: f32,
+    #[tabled(skip)]
+    metrics: Option<BvhMetrics>,
+    /// Only counted for --calibrate-costs
+    #[tabled(skip)]
+    ray_counts: Option<RayCounts>,
+    /// Bounds and primitive tests per ray of --heatmap and --profile-rt
+    #[tabled(skip)]
+    traversal_counts: Option<TraversalCountStats>,
+}
+
+/// Prints the BVH quality metrics next to the traversal times, and the depth and leaf size histograms.
+fn print_metrics(stats: &[Stats], options: &Options) {
+    let mut metrics_stats = Vec::new();
+    for stat in stats.iter().filter(|s| s.name != "Avg") {
+        match &stat.metrics {
+            Some(metrics) => {
+                metrics_stats.push(metrics.stats(&stat.name, &stat.build, stat.traversal_ms));
+                if options.verbose {
+                    println!(
+                        "{} leaves per depth: {:?}",
+                        stat.name, metrics.depth_histogram
+                    );
+                    println!(
+                        "{} leaves per primitive count: {:?}",
+                        stat.name, metrics.leaf_size_histogram
+                    );
+                }
+            }
+            None => println!(
+                "BVH metrics are not available for {} ({})",
+                stat.build, stat.name
+            ),
+        }
+    }
+    println!("{}", Table::new(metrics_stats).with(Style::blank()));
+}
+
+/// Prints the mean, percentiles and max of the bounds and primitive tests per ray of --heatmap and --profile-rt.
+fn print_traversal_counts(stats: &[Stats]) {
+    let counts_stats = stats
+        .iter()
+        .filter_map(|s| {
+            s.traversal_counts
+                .map(|counts| counts.stats(&s.name, &s.build))
+        })
+        .flatten()
+        .collect::<Vec<_>>();
+    if !counts_stats.is_empty() {
+        println!("{}", Table::new(counts_stats).with(Style::blank()));
+    }
 }
 
 fn seconds_to_hh_mm_ss(seconds: f32) -> String {
@@ -0,0 +1,592 @@
+use glam::Vec3A;
+use obvhs::{aabb::Aabb, bvh2::Bvh2, cwbvh::CwBvh, triangle::Triangle};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use tabled::Tabled;
+
+use crate::{bvh4::Bvh4, cwbvh::CwBvhNodeExt, Options};
+
+/// Constants for the SAH cost and EPO.
+#[derive(Clone, Copy, Debug)]
+pub struct SahCosts {
+    /// Cost of visiting a node, relative to `intersection`.
+    pub traversal: f32,
+    /// Cost of intersecting a single primitive.
+    pub intersection: f32,
+}
+
+impl SahCosts {
+    pub fn from_options(options: &Options) -> Self {
+        SahCosts {
+            traversal: options.sah_traversal_cost,
+            intersection: options.sah_intersection_cost,
+        }
+    }
+}
+
+/// Builder independent view of a BVH with any branching factor, so the same metrics can be computed for the output of
+/// every builder. Nodes are stored children first, `root` is the last one pushed.
+#[derive(Default)]
+pub struct MetricsTree {
+    pub nodes: Vec<MetricsNode>,
+    /// Child node indices of inner nodes.
+    pub children: Vec<u32>,
+    /// Triangle indices of leaves. Triangles referenced by multiple leaves (from spatial splits) show up more than
+    /// once.
+    pub primitives: Vec<u32>,
+    pub root: u32,
+}
+
+#[derive(Clone, Copy)]
+pub struct MetricsNode {
+    pub aabb: Aabb,
+    /// Range into `children` for inner nodes, into `primitives` for leaves.
+    pub first: u32,
+    pub count: u32,
+    pub is_leaf: bool,
+}
+
+impl MetricsTree {
+    pub fn node_children(&self, node: &MetricsNode) -> &[u32] {
+        &self.children[node.first as usize..(node.first + node.count) as usize]
+    }
+
+    pub fn node_primitives(&self, node: &MetricsNode) -> &[u32] {
+        &self.primitives[node.first as usize..(node.first + node.count) as usize]
+    }
+
+    fn push_leaf(&mut self, aabb: Aabb, primitives: impl Iterator<Item = u32>) -> u32 {
+        let first = self.primitives.len() as u32;
+        self.primitives.extend(primitives);
+        self.nodes.push(MetricsNode {
+            aabb,
+            first,
+            count: self.primitives.len() as u32 - first,
+            is_leaf: true,
+        });
+        self.nodes.len() as u32 - 1
+    }
+
+    fn push_inner(&mut self, children: &[u32]) -> u32 {
+        let aabb = children.iter().fold(Aabb::INVALID, |aabb, child| {
+            aabb.union(&self.nodes[*child as usize].aabb)
+        });
+        let first = self.children.len() as u32;
+        self.children.extend_from_slice(children);
+        self.nodes.push(MetricsNode {
+            aabb,
+            first,
+            count: children.len() as u32,
+            is_leaf: false,
+        });
+        self.nodes.len() as u32 - 1
+    }
+
+    pub fn from_bvh2(bvh: &Bvh2) -> Self {
+        let mut tree = MetricsTree::default();
+        if !bvh.nodes.is_empty() {
+            tree.root = tree.push_bvh2_node(bvh, 0);
+        }
+        tree
+    }
+
+    fn push_bvh2_node(&mut self, bvh: &Bvh2, node_index: usize) -> u32 {
+        let node = &bvh.nodes[node_index];
+        if node.is_leaf() {
+            let first = node.first_index as usize;
+            let primitives = &bvh.primitive_indices[first..first + node.prim_count as usize];
+            self.push_leaf(node.aabb, primitives.iter().copied())
+        } else {
+            let left = self.push_bvh2_node(bvh, node.first_index as usize);
+            let right = self.push_bvh2_node(bvh, node.first_index as usize + 1);
+            self.push_inner(&[left, right])
+        }
+    }
+
+    pub fn from_bvh4(bvh: &Bvh4) -> Self {
+        let mut tree = MetricsTree::default();
+        if !bvh.nodes.is_empty() {
+            tree.root = tree.push_bvh4_node(bvh, 0);
+        }
+        tree
+    }
+
+    fn push_bvh4_node(&mut self, bvh: &Bvh4, node_index: usize) -> u32 {
+        let node = &bvh.nodes[node_index];
+        let mut children = Vec::with_capacity(4);
+        for lane in 0..4 {
+            if node.child_is_empty(lane) {
+                continue;
+            }
+            let child = if node.child_is_inner(lane) {
+                self.push_bvh4_node(bvh, node.child[lane] as usize)
+            } else {
+                let first = node.child[lane] as usize;
+                let primitives =
+                    &bvh.primitive_indices[first..first + node.prim_count[lane] as usize];
+                self.push_leaf(node.child_aabb(lane), primitives.iter().copied())
+            };
+            children.push(child);
+        }
+        self.push_inner(&children)
+    }
+
+    /// Uses the quantized child bounds since those are what traversal actually tests against. Leaf children become
+    /// their own leaf nodes.
+    pub fn from_cwbvh(bvh: &CwBvh) -> Self {
+        let mut tree = MetricsTree::default();
+        if !bvh.nodes.is_empty() {
+            tree.root = tree.push_cwbvh_node(bvh, 0, 0);
+        }
+        tree
+    }
+
+    /// Two level tree where the TLAS leaves point to the BLAS roots. `blas_primitive_offsets` is added to the
+    /// primitives of each BLAS so they index into the concatenated triangles of all objects.
+    pub fn from_cwbvh_tlas(tlas: &CwBvh, blas: &[CwBvh], blas_primitive_offsets: &[u32]) -> Self {
+        let mut tree = MetricsTree::default();
+        let blas_roots = blas
+            .iter()
+            .zip(blas_primitive_offsets)
+            .map(|(blas, offset)| tree.push_cwbvh_node(blas, 0, *offset))
+            .collect::<Vec<_>>();
+        tree.root = tree.push_tlas_node(tlas, 0, &blas_roots);
+        tree
+    }
+
+    fn push_cwbvh_node(&mut self, bvh: &CwBvh, node_index: usize, primitive_offset: u32) -> u32 {
+        let node = bvh.nodes[node_index];
+        let mut children = Vec::with_capacity(8);
+        for ch in 0..8 {
+            if node.child_is_empty(ch) {
+                continue;
+            }
+            let child = if node.child_is_inner(ch) {
+                self.push_cwbvh_node(bvh, node.child_node_index(ch), primitive_offset)
+            } else {
+                let primitives = &bvh.primitive_indices[node.child_primitives(ch)];
+                self.push_leaf(
+                    node.child_aabb(ch),
+                    primitives.iter().map(|i| i + primitive_offset),
+                )
+            };
+            children.push(child);
+        }
+        self.push_inner(&children)
+    }
+
+    fn push_tlas_node(&mut self, tlas: &CwBvh, node_index: usize, blas_roots: &[u32]) -> u32 {
+        let node = tlas.nodes[node_index];
+        let mut children = Vec::with_capacity(8);
+        for ch in 0..8 {
+            if node.child_is_empty(ch) {
+                continue;
+            }
+            if node.child_is_inner(ch) {
+                children.push(self.push_tlas_node(tlas, node.child_node_index(ch), blas_roots));
+            } else {
+                let instances = &tlas.primitive_indices[node.child_primitives(ch)];
+                children.extend(instances.iter().map(|i| blas_roots[*i as usize]));
+            }
+        }
+        self.push_inner(&children)
+    }
+
+    /// `tris` are the triangles the shapes were made from, for the leaf bounds.
+    pub fn from_svenstaro(bvh: &svenstaro::bvh::Bvh<f32, 3>, tris: &[Triangle]) -> Self {
+        let mut tree = MetricsTree::default();
+        if !bvh.nodes.is_empty() {
+            tree.root = tree.push_svenstaro_node(bvh, 0, tris);
+        }
+        tree
+    }
+
+    fn push_svenstaro_node(
+        &mut self,
+        bvh: &svenstaro::bvh::Bvh<f32, 3>,
+        node_index: usize,
+        tris: &[Triangle],
+    ) -> u32 {
+        match &bvh.nodes[node_index] {
+            svenstaro::bvh::BvhNode::Leaf { shape_index, .. } => {
+                self.push_leaf(tris[*shape_index].aabb(), [*shape_index as u32].into_iter())
+            }
+            svenstaro::bvh::BvhNode::Node {
+                child_l_index,
+                child_r_index,
+                ..
+            } => {
+                let left = self.push_svenstaro_node(bvh, *child_l_index, tris);
+                let right = self.push_svenstaro_node(bvh, *child_r_index, tris);
+                self.push_inner(&[left, right])
+            }
+        }
+    }
+
+    /// parry doesn't expose its node indices, so the tree is rebuilt from the order `Bvh::traverse` visits the nodes
+    /// in: both children of a node together, then the subtree of the left child before the one of the right child.
+    pub fn from_parry(bvh: &parry3d::partitioning::Bvh) -> Self {
+        // Bounds and leaf primitive of each visited node
+        let mut visited = Vec::new();
+        bvh.traverse(|node| {
+            let aabb = node.aabb();
+            visited.push((
+                Aabb::new(
+                    Vec3A::from_array(aabb.mins.into()),
+                    Vec3A::from_array(aabb.maxs.into()),
+                ),
+                node.leaf_data(),
+            ));
+            parry3d::partitioning::TraversalAction::Continue
+        });
+        // Index in `visited` of the first of the two children of each inner node, mirroring the traversal stack
+        let mut first_child = vec![0; visited.len()];
+        let mut stack = Vec::new();
+        let mut parent = None;
+        for first in (0..visited.len().saturating_sub(1)).step_by(2) {
+            if let Some(parent) = parent {
+                first_child[parent] = first;
+            }
+            let left_inner = visited[first].1.is_none();
+            let right_inner = visited[first + 1].1.is_none();
+            parent = match (left_inner, right_inner) {
+                (true, true) => {
+                    stack.push(first + 1);
+                    Some(first)
+                }
+                (true, false) => Some(first),
+                (false, true) => Some(first + 1),
+                (false, false) => stack.pop(),
+            };
+        }
+
+        let mut tree = MetricsTree::default();
+        if visited.len() >= 2 {
+            let left = tree.push_parry_node(&visited, &first_child, 0);
+            let right = tree.push_parry_node(&visited, &first_child, 1);
+            tree.root = tree.push_inner(&[left, right]);
+        }
+        tree
+    }
+
+    fn push_parry_node(
+        &mut self,
+        visited: &[(Aabb, Option<u32>)],
+        first_child: &[usize],
+        index: usize,
+    ) -> u32 {
+        match visited[index] {
+            (aabb, Some(primitive)) => self.push_leaf(aabb, [primitive].into_iter()),
+            (_, None) => {
+                let first = first_child[index];
+                let left = self.push_parry_node(visited, first_child, first);
+                let right = self.push_parry_node(visited, first_child, first + 1);
+                self.push_inner(&[left, right])
+            }
+        }
+    }
+
+    #[cfg(feature = "tinybvh")]
+    pub fn from_tinybvh(bvh: &tinybvh_rs::bvh::BVH) -> Self {
+        let mut tree = MetricsTree::default();
+        if !bvh.nodes().is_empty() {
+            tree.root = tree.push_tinybvh_node(bvh, 0);
+        }
+        tree
+    }
+
+    #[cfg(feature = "tinybvh")]
+    fn push_tinybvh_node(&mut self, bvh: &tinybvh_rs::bvh::BVH, node_index: usize) -> u32 {
+        let node = &bvh.nodes()[node_index];
+        if node.tri_count > 0 {
+            let first = node.left_first as usize;
+            let primitives = &bvh.indices()[first..first + node.tri_count as usize];
+            let aabb = Aabb::new(node.min.into(), node.max.into());
+            self.push_leaf(aabb, primitives.iter().copied())
+        } else {
+            let left = self.push_tinybvh_node(bvh, node.left_first as usize);
+            let right = self.push_tinybvh_node(bvh, node.left_first as usize + 1);
+            self.push_inner(&[left, right])
+        }
+    }
+
+    /// Surface area heuristic cost of the whole tree, normalized by the area of the root.
+    pub fn sah_cost(&self, costs: &SahCosts) -> f32 {
+        if self.nodes.is_empty() {
+            return 0.0;
+        }
+        let root_area = self.nodes[self.root as usize].aabb.half_area();
+        self.nodes
+            .iter()
+            .map(|node| node.aabb.half_area() / root_area * node_cost(node, costs))
+            .sum()
+    }
+}
+
+#[inline(always)]
+pub fn node_cost(node: &MetricsNode, costs: &SahCosts) -> f32 {
+    if node.is_leaf {
+        node.count as f32 * costs.intersection
+    } else {
+        costs.traversal
+    }
+}
+
+/// See `MetricsTree::sah_cost`.
+pub fn bvh2_sah_cost(bvh: &Bvh2, costs: &SahCosts) -> f32 {
+    MetricsTree::from_bvh2(bvh).sah_cost(costs)
+}
+
+/// See `MetricsTree::sah_cost`.
+pub fn cwbvh_sah_cost(bvh: &CwBvh, costs: &SahCosts) -> f32 {
+    MetricsTree::from_cwbvh(bvh).sah_cost(costs)
+}
+
+/// SAH cost of a two level BVH: the TLAS cost plus the cost of each instance's BLAS, weighted by the area of the
+/// instance bounds relative to the TLAS root.
+pub fn tlas_sah_cost(
+    tlas: &CwBvh,
+    blas_costs: &[f32],
+    instance_aabbs: &[Aabb],
+    costs: &SahCosts,
+) -> f32 {
+    let root_area = tlas.total_aabb.half_area();
+    cwbvh_sah_cost(tlas, costs)
+        + blas_costs
+            .iter()
+            .zip(instance_aabbs)
+            .map(|(cost, aabb)| aabb.half_area() / root_area * cost)
+            .sum::<f32>()
+}
+
+#[derive(Clone, Default)]
+pub struct BvhMetrics {
+    pub sah_cost: f32,
+    /// End-Point Overlap (Aila et al. 2013): area of the triangles inside nodes that don't reference them, weighted
+    /// by node cost and relative to the total triangle area.
+    pub epo: f32,
+    pub inner_nodes: usize,
+    pub leaves: usize,
+    pub max_depth: usize,
+    pub avg_leaf_depth: f32,
+    pub avg_prims_per_leaf: f32,
+    /// Overlapping area of each pair of siblings, relative to the area of the root.
+    pub sibling_overlap: f32,
+    /// Leaf references beyond the first for each triangle, from spatial splits.
+    pub duplicate_refs: usize,
+    /// Number of leaves at each depth.
+    pub depth_histogram: Vec<usize>,
+    /// Number of leaves with each primitive count.
+    pub leaf_size_histogram: Vec<usize>,
+}
+
+#[derive(Tabled)]
+pub struct MetricsStats {
+    pub name: String,
+    pub build: String,
+    pub traversal_ms: f32,
+    pub sah_cost: f32,
+    pub epo: f32,
+    pub inner_nodes: usize,
+    pub leaves: usize,
+    pub max_depth: usize,
+    pub avg_leaf_depth: f32,
+    pub avg_prims_per_leaf: f32,
+    pub sibling_overlap: f32,
+    pub duplicate_refs: usize,
+}
+
+impl BvhMetrics {
+    /// `tris` are the triangles the primitives of the tree index into.
+    pub fn new(tree: &MetricsTree, tris: &[Triangle], costs: &SahCosts) -> Self {
+        let mut metrics = BvhMetrics {
+            sah_cost: tree.sah_cost(costs),
+            ..Default::default()
+        };
+        if tree.nodes.is_empty() {
+            return metrics;
+        }
+        let root_area = tree.nodes[tree.root as usize].aabb.half_area();
+
+        // Preorder index and end of the subtree of each node, to check if a triangle is referenced below a node.
+        let mut preorder = vec![0u32; tree.nodes.len()];
+        let mut subtree_end = vec![0u32; tree.nodes.len()];
+        // Preorder indices of the leaves referencing each triangle
+        let mut tri_leaves = vec![Vec::new(); tris.len()];
+        let mut counter = 0;
+        let mut leaf_depth_sum = 0;
+        let mut stack = vec![(tree.root, 0, false)];
+        while let Some((node_index, depth, visited)) = stack.pop() {
+            let node = &tree.nodes[node_index as usize];
+            if visited {
+                subtree_end[node_index as usize] = counter;
+                continue;
+            }
+            preorder[node_index as usize] = counter;
+            counter += 1;
+            stack.push((node_index, depth, true));
+            metrics.max_depth = metrics.max_depth.max(depth);
+            if node.is_leaf {
+                metrics.leaves += 1;
+                leaf_depth_sum += depth;
+                increment_bucket(&mut metrics.depth_histogram, depth);
+                increment_bucket(&mut metrics.leaf_size_histogram, node.count as usize);
+                for tri in tree.node_primitives(node) {
+                    tri_leaves[*tri as usize].push(preorder[node_index as usize]);
+                }
+            } else {
+                metrics.inner_nodes += 1;
+                let children = tree.node_children(node);
+                for (i, a) in children.iter().enumerate() {
+                    for b in &children[i + 1..] {
+                        let a = &tree.nodes[*a as usize].aabb;
+                        let b = &tree.nodes[*b as usize].aabb;
+                        metrics.sibling_overlap += overlap_half_area(a, b).max(0.0) / root_area;
+                    }
+                }
+                for child in children.iter().rev() {
+                    stack.push((*child, depth + 1, false));
+                }
+            }
+        }
+        metrics.avg_leaf_depth = leaf_depth_sum as f32 / metrics.leaves.max(1) as f32;
+        metrics.avg_prims_per_leaf = tree.primitives.len() as f32 / metrics.leaves.max(1) as f32;
+        let referenced = tri_leaves.iter().filter(|l| !l.is_empty()).count();
+        metrics.duplicate_refs = tree.primitives.len() - referenced;
+
+        let (overlap, total_area) = (0..tris.len())
+            .into_par_iter()
+            .map(|tri_index| {
+                let tri = &tris[tri_index];
+                let tri_aabb = tri.aabb();
+                let leaves = &tri_leaves[tri_index];
+                let mut overlap = 0.0;
+                let mut stack = vec![tree.root];
+                while let Some(node_index) = stack.pop() {
+                    let node = &tree.nodes[node_index as usize];
+                    if overlap_half_area(&node.aabb, &tri_aabb) < 0.0 {
+                        continue;
+                    }
+                    let subtree = preorder[node_index as usize]..subtree_end[node_index as usize];
+                    if !leaves.iter().any(|leaf| subtree.contains(leaf)) {
+                        overlap += clipped_triangle_area(tri, &node.aabb) * node_cost(node, costs);
+                    }
+                    if !node.is_leaf {
+                        stack.extend_from_slice(tree.node_children(node));
+                    }
+                }
+                (overlap, triangle_area(tri))
+            })
+            .reduce(|| (0.0, 0.0), |a, b| (a.0 + b.0, a.1 + b.1));
+        metrics.epo = overlap / total_area.max(f32::MIN_POSITIVE);
+
+        metrics
+    }
+
+    pub fn stats(&self, name: &str, build: &str, traversal_ms: f32) -> MetricsStats {
+        MetricsStats {
+            name: name.to_string(),
+            build: build.to_string(),
+            traversal_ms,
+            sah_cost: self.sah_cost,
+            epo: self.epo,
+            inner_nodes: self.inner_nodes,
+            leaves: self.leaves,
+            max_depth: self.max_depth,
+            avg_leaf_depth: self.avg_leaf_depth,
+            avg_prims_per_leaf: self.avg_prims_per_leaf,
+            sibling_overlap: self.sibling_overlap,
+            duplicate_refs: self.duplicate_refs,
+        }
+    }
+}
+
+fn increment_bucket(histogram: &mut Vec<usize>, bucket: usize) {
+    if histogram.len() <= bucket {
+        histogram.resize(bucket + 1, 0);
+    }
+    histogram[bucket] += 1;
+}
+
+/// Builds the tree view if anything needs it (`--metrics` or `--export-bvh`).
+pub fn metrics_tree_from_options(
+    options: &Options,
+    tree: impl FnOnce() -> MetricsTree,
+) -> Option<MetricsTree> {
+    (options.metrics || !options.export_bvh.is_empty() || options.calibrate_costs).then(tree)
+}
+
+/// Tree view of the BLAS from `cwbvh_from_tris` or, if there is a TLAS, of the whole two level BVH with primitives
+/// indexing into the concatenated objects. The BLAS primitive indices need to still be relative to their own object.
+pub fn cwbvh_metrics_tree_from_options(
+    options: &Options,
+    objects: &[Vec<Triangle>],
+    blas: &[CwBvh],
+    tlas: Option<&CwBvh>,
+) -> Option<MetricsTree> {
+    metrics_tree_from_options(options, || match tlas {
+        Some(tlas) => {
+            let offsets = objects
+                .iter()
+                .scan(0, |offset, tris| {
+                    let object_offset = *offset;
+                    *offset += tris.len() as u32;
+                    Some(object_offset)
+                })
+                .collect::<Vec<_>>();
+            MetricsTree::from_cwbvh_tlas(tlas, blas, &offsets)
+        }
+        None => MetricsTree::from_cwbvh(&blas[0]),
+    })
+}
+
+/// Half area of the intersection of `a` and `b`. Negative if they don't overlap, 0 if they only touch.
+fn overlap_half_area(a: &Aabb, b: &Aabb) -> f32 {
+    let d = a.max.min(b.max) - a.min.max(b.min);
+    if d.cmplt(Vec3A::ZERO).any() {
+        return -1.0;
+    }
+    d.x * d.y + d.y * d.z + d.z * d.x
+}
+
+fn triangle_area(tri: &Triangle) -> f32 {
+    (tri.v1 - tri.v0).cross(tri.v2 - tri.v0).length() * 0.5
+}
+
+/// Area of the part of the triangle inside the box.
+fn clipped_triangle_area(tri: &Triangle, aabb: &Aabb) -> f32 {
+    let poly = clip_triangle(tri, aabb);
+    let mut area = Vec3A::ZERO;
+    for i in 1..poly.len().max(1) - 1 {
+        area += (poly[i] - poly[0]).cross(poly[i + 1] - poly[0]);
+    }
+    area.length() * 0.5
+}
+
+/// Polygon of the part of the triangle inside the box, clipping it against each of the 6 planes
+/// (Sutherland-Hodgman). Empty if they don't overlap.
+pub fn clip_triangle(tri: &Triangle, aabb: &Aabb) -> Vec<Vec3A> {
+    let mut poly = vec![tri.v0, tri.v1, tri.v2];
+    let mut clipped = Vec::with_capacity(9);
+    for axis in 0..3 {
+        for (plane, sign) in [(aabb.min[axis], 1.0), (aabb.max[axis], -1.0)] {
+            clipped.clear();
+            for i in 0..poly.len() {
+                let a = poly[i];
+                let b = poly[(i + 1) % poly.len()];
+                let da = (a[axis] - plane) * sign;
+                let db = (b[axis] - plane) * sign;
+                if da >= 0.0 {
+                    clipped.push(a);
+                }
+                if (da >= 0.0) != (db >= 0.0) {
+                    clipped.push(a + (b - a) * (da / (da - db)));
+                }
+            }
+            std::mem::swap(&mut poly, &mut clipped);
+            if poly.len() < 3 {
+                return Vec::new();
+            }
+        }
+    }
+    poly
+}
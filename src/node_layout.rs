@@ -0,0 +1,361 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, VecDeque},
+    ops::Range,
+    time::Instant,
+};
+
+use glam::{uvec2, Affine3A, Vec2};
+use obvhs::{
+    bvh2::Bvh2,
+    cwbvh::{node::CwBvhNode, CwBvh},
+    ray::{Ray, RayHit},
+    triangle::Triangle,
+};
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+
+use crate::{
+    cwbvh::CwBvhNodeExt,
+    rt_cpu::{
+        heatmap::{bvh2_traverse_counted, cwbvh_traverse_counted, TraversalCounts},
+        rt_cpu::primary_ray,
+    },
+    Options, Scene, ViewUniform,
+};
+
+/// Every nth pixel in x and y is traced to measure node visits for the `hot` layout.
+const HOT_RAY_STRIDE: u32 = 4;
+
+/// Nodes that have to stay next to each other: the two children of a Bvh2 node, or the inner children of a CwBvh
+/// node. `len` nodes starting at `first` in the original order.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Group {
+    first: u32,
+    len: u32,
+}
+
+impl Group {
+    fn nodes(&self) -> Range<usize> {
+        self.first as usize..(self.first + self.len) as usize
+    }
+}
+
+/// Camera rays used to measure node visit frequency for `--node-layout hot`. Empty for the other layouts.
+pub fn layout_rays(options: &Options, scene: &Scene) -> Vec<Ray> {
+    if options.node_layout != "hot" {
+        return Vec::new();
+    }
+    let cam = ViewUniform::from_camera(
+        &scene.camera,
+        options.width as f32,
+        options.height as f32,
+        0,
+    );
+    let target_size = Vec2::new(options.width as f32, options.height as f32);
+    let mut rays = Vec::new();
+    for y in (0..options.height).step_by(HOT_RAY_STRIDE as usize) {
+        for x in (0..options.width).step_by(HOT_RAY_STRIDE as usize) {
+            rays.push(primary_ray(&cam, uvec2(x, y), target_size));
+        }
+    }
+    rays
+}
+
+/// Reorders the nodes and primitive indices of a BLAS with `--node-layout`. `tris` are in the original order.
+pub fn layout_blas(bvh: &mut CwBvh, tris: &[Triangle], options: &Options, rays: &[Ray]) {
+    if options.node_layout == "builder" || bvh.nodes.is_empty() {
+        return;
+    }
+    let start_time = Instant::now();
+    let visits = (options.node_layout == "hot").then(|| {
+        cwbvh_visits(bvh, rays, |ray, id| {
+            tris[bvh.primitive_indices[id] as usize].intersect(ray)
+        })
+    });
+    reorder_cwbvh(bvh, &options.node_layout, visits.as_deref());
+    print_layout_time(options, start_time);
+}
+
+/// Same as `layout_blas` for a TLAS over `instances` of `blas`. The BLAS can already be reordered.
+pub fn layout_tlas(
+    tlas: &mut CwBvh,
+    blas: &[CwBvh],
+    objects: &[Vec<Triangle>],
+    instances: &[(u32, Affine3A)],
+    options: &Options,
+    rays: &[Ray],
+) {
+    if options.node_layout == "builder" || tlas.nodes.is_empty() {
+        return;
+    }
+    let start_time = Instant::now();
+    let visits = (options.node_layout == "hot").then(|| {
+        let world_to_object = instances
+            .iter()
+            .map(|(_, t)| t.inverse())
+            .collect::<Vec<_>>();
+        cwbvh_visits(tlas, rays, |ray, id| {
+            let instance = tlas.primitive_indices[id] as usize;
+            let mesh = instances[instance].0 as usize;
+            let (bvh, tris) = (&blas[mesh], &objects[mesh]);
+            let to_object = &world_to_object[instance];
+            let object_ray = Ray::new(
+                to_object.transform_point3a(ray.origin),
+                to_object.transform_vector3a(ray.direction),
+                ray.tmin,
+                ray.tmax,
+            );
+            let mut hit = RayHit::none();
+            bvh.ray_traverse(object_ray, &mut hit, |ray, id| {
+                tris[bvh.primitive_indices[id] as usize].intersect(ray)
+            });
+            hit.t
+        })
+    });
+    reorder_cwbvh(tlas, &options.node_layout, visits.as_deref());
+    print_layout_time(options, start_time);
+}
+
+/// Same as `layout_blas` for a Bvh2.
+pub fn layout_bvh2(bvh: &mut Bvh2, tris: &[Triangle], options: &Options, rays: &[Ray]) {
+    if options.node_layout == "builder" || bvh.nodes.is_empty() {
+        return;
+    }
+    let start_time = Instant::now();
+    let visits = (options.node_layout == "hot").then(|| {
+        count_visits(bvh.nodes.len(), rays, |ray, visit| {
+            bvh2_traverse_counted(
+                bvh,
+                ray,
+                &mut TraversalCounts::default(),
+                visit,
+                |ray, id, _| {
+                    let t = tris[bvh.primitive_indices[id] as usize].intersect(ray);
+                    ray.tmax = ray.tmax.min(t);
+                },
+            )
+        })
+    });
+    reorder_bvh2(bvh, &options.node_layout, visits.as_deref());
+    print_layout_time(options, start_time);
+}
+
+fn print_layout_time(options: &Options, start_time: Instant) {
+    if options.verbose {
+        println!(
+            "{} node layout took {:.2}ms",
+            options.node_layout,
+            start_time.elapsed().as_secs_f32() * 1000.0
+        );
+    }
+}
+
+fn cwbvh_visits(
+    bvh: &CwBvh,
+    rays: &[Ray],
+    intersect: impl Fn(&Ray, usize) -> f32 + Sync,
+) -> Vec<u32> {
+    count_visits(bvh.nodes.len(), rays, |ray, visit| {
+        cwbvh_traverse_counted(
+            bvh,
+            ray,
+            &mut TraversalCounts::default(),
+            visit,
+            |ray, id, _| {
+                ray.tmax = ray.tmax.min(intersect(ray, id));
+            },
+        )
+    })
+}
+
+/// Number of rays that visit each node. `traverse` is called for every ray with a callback for each visited node.
+fn count_visits(
+    node_count: usize,
+    rays: &[Ray],
+    traverse: impl Fn(&mut Ray, &mut dyn FnMut(usize)) + Sync,
+) -> Vec<u32> {
+    rays.par_iter()
+        .fold(
+            || vec![0u32; node_count],
+            |mut visits, ray| {
+                let mut ray = *ray;
+                traverse(&mut ray, &mut |node_index| visits[node_index] += 1);
+                visits
+            },
+        )
+        .reduce(
+            || vec![0u32; node_count],
+            |mut a, b| {
+                a.iter_mut().zip(b).for_each(|(a, b)| *a += b);
+                a
+            },
+        )
+}
+
+fn cwbvh_child_group(node: &CwBvhNode) -> Option<Group> {
+    (node.imask != 0).then(|| Group {
+        first: node.child_base_idx,
+        len: node.imask.count_ones(),
+    })
+}
+
+/// Range of `primitive_indices` used by the leaf children of a node.
+fn cwbvh_node_primitives(node: &CwBvhNode) -> Range<usize> {
+    let end = (0..8)
+        .filter(|ch| !node.child_is_empty(*ch) && !node.child_is_inner(*ch))
+        .map(|ch| node.child_primitives(ch).end)
+        .max();
+    let start = node.primitive_base_idx as usize;
+    start..end.unwrap_or(start)
+}
+
+fn reorder_cwbvh(bvh: &mut CwBvh, layout: &str, visits: Option<&[u32]>) {
+    let order = group_order(
+        |node_index| cwbvh_child_group(&bvh.nodes[node_index]),
+        layout,
+        visits,
+    );
+    let new_index = new_node_indices(&order, bvh.nodes.len());
+
+    let mut nodes = Vec::with_capacity(bvh.nodes.len());
+    let mut primitive_indices = Vec::with_capacity(bvh.primitive_indices.len());
+    for old_index in order.iter().flat_map(|g| g.nodes()) {
+        let mut node = bvh.nodes[old_index];
+        if node.imask != 0 {
+            node.child_base_idx = new_index[node.child_base_idx as usize];
+        }
+        // Leaf children are relative to primitive_base_idx so the whole range moves together
+        let primitives = cwbvh_node_primitives(&node);
+        node.primitive_base_idx = primitive_indices.len() as u32;
+        primitive_indices.extend_from_slice(&bvh.primitive_indices[primitives]);
+        nodes.push(node);
+    }
+    if let Some(exact_node_aabbs) = &mut bvh.exact_node_aabbs {
+        *exact_node_aabbs = order
+            .iter()
+            .flat_map(|g| g.nodes())
+            .map(|old_index| exact_node_aabbs[old_index])
+            .collect();
+    }
+    bvh.nodes = nodes;
+    bvh.primitive_indices = primitive_indices;
+}
+
+fn reorder_bvh2(bvh: &mut Bvh2, layout: &str, visits: Option<&[u32]>) {
+    let order = group_order(
+        |node_index| {
+            let node = &bvh.nodes[node_index];
+            (!node.is_leaf()).then_some(Group {
+                first: node.first_index,
+                len: 2,
+            })
+        },
+        layout,
+        visits,
+    );
+    let new_index = new_node_indices(&order, bvh.nodes.len());
+
+    let mut nodes = Vec::with_capacity(bvh.nodes.len());
+    let mut primitive_indices = Vec::with_capacity(bvh.primitive_indices.len());
+    for old_index in order.iter().flat_map(|g| g.nodes()) {
+        let mut node = bvh.nodes[old_index];
+        if node.is_leaf() {
+            let first = node.first_index as usize;
+            node.first_index = primitive_indices.len() as u32;
+            primitive_indices
+                .extend_from_slice(&bvh.primitive_indices[first..first + node.prim_count as usize]);
+        } else {
+            node.first_index = new_index[node.first_index as usize];
+        }
+        nodes.push(node);
+    }
+    bvh.nodes = nodes;
+    bvh.primitive_indices = primitive_indices;
+}
+
+fn new_node_indices(order: &[Group], node_count: usize) -> Vec<u32> {
+    let mut new_index = vec![u32::MAX; node_count];
+    for (new, old) in order.iter().flat_map(|g| g.nodes()).enumerate() {
+        new_index[old] = new as u32;
+    }
+    new_index
+}
+
+/// Order the groups are written in, starting with the root. `child_group` returns the group of children of a node, if
+/// it has inner children.
+fn group_order(
+    child_group: impl Fn(usize) -> Option<Group>,
+    layout: &str,
+    visits: Option<&[u32]>,
+) -> Vec<Group> {
+    let children = |group: &Group| group.nodes().filter_map(&child_group).collect::<Vec<_>>();
+    let root = Group { first: 0, len: 1 };
+    let mut order = Vec::new();
+    match layout {
+        "dfs" => {
+            let mut stack = vec![root];
+            while let Some(group) = stack.pop() {
+                order.push(group);
+                stack.extend(children(&group).into_iter().rev());
+            }
+        }
+        "bfs" => {
+            let mut queue = VecDeque::from([root]);
+            while let Some(group) = queue.pop_front() {
+                order.push(group);
+                queue.extend(children(&group));
+            }
+        }
+        "veb" => {
+            let height = group_height(&root, &children);
+            veb_order(root, height, &children, &mut order);
+        }
+        "hot" => {
+            // Greedily write the most visited group whose parent has already been written
+            let visits = visits.expect("hot layout needs visit counts");
+            let weight = |group: &Group| group.nodes().map(|i| visits[i] as u64).sum::<u64>();
+            let mut heap = BinaryHeap::from([(weight(&root), Reverse(root))]);
+            while let Some((_, Reverse(group))) = heap.pop() {
+                order.push(group);
+                heap.extend(
+                    children(&group)
+                        .into_iter()
+                        .map(|g| (weight(&g), Reverse(g))),
+                );
+            }
+        }
+        _ => panic!("Unknown node layout {}", layout),
+    }
+    order
+}
+
+fn group_height(group: &Group, children: &impl Fn(&Group) -> Vec<Group>) -> usize {
+    1 + children(group)
+        .iter()
+        .map(|child| group_height(child, children))
+        .max()
+        .unwrap_or(0)
+}
+
+/// van Emde Boas layout: the top half of the levels is written first, then each of the subtrees below it, recursively.
+/// Subtrees of any size end up in a contiguous block so they share cache lines and pages.
+fn veb_order(
+    group: Group,
+    levels: usize,
+    children: &impl Fn(&Group) -> Vec<Group>,
+    order: &mut Vec<Group>,
+) {
+    if levels <= 1 {
+        order.push(group);
+        return;
+    }
+    let top = levels / 2;
+    veb_order(group, top, children, order);
+    let mut frontier = vec![group];
+    for _ in 0..top {
+        frontier = frontier.iter().flat_map(children).collect();
+    }
+    for subtree in frontier {
+        veb_order(subtree, levels - top, children, order);
+    }
+}
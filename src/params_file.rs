@@ -0,0 +1,103 @@
+use std::fs::File;
+
+use ron::{de::from_reader, ser::PrettyConfig};
+use serde::{Deserialize, Serialize};
+
+use crate::Options;
+
+/// `BvhBuildParams` as a RON file for `--params-file`. `--auto-tune` writes one for each result on its Pareto front.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ParamsFile {
+    /// `--build`, the command line one is used if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build: Option<String>,
+    /// `--cpu`, the command line one is used if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<bool>,
+    /// The `--rt-*` options of the GPU software traversal, the command line ones are used if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gpu_kernel: Option<GpuKernelParams>,
+    pub pre_split: bool,
+    pub ploc_search_distance: u32,
+    pub search_depth_threshold: usize,
+    pub reinsertion_batch_ratio: f32,
+    /// 64 or 128
+    pub sort_precision: u8,
+    pub max_prims_per_leaf: u32,
+    pub post_collapse_reinsertion_batch_ratio_multiplier: f32,
+    pub collapse_traversal_cost: f32,
+    /// What auto-tune measured with these params. Not used when loading.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tuned: Option<TunedScores>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct GpuKernelParams {
+    pub rt_vgpr_stack_size: u32,
+    pub rt_lds_stack_size: u32,
+    pub rt_group_width: u32,
+    pub rt_group_height: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TunedScores {
+    /// The `-i` scenes
+    pub scenes: String,
+    pub avg_traversal_ms: f32,
+    pub avg_blas_build_s: f32,
+    pub avg_tlas_build_ms: f32,
+    pub avg_bvh_memory_mb: f32,
+    /// Geometric mean over the scenes of the ratio to the best result of the run, see `--tune-weights`
+    pub norm_traversal: f32,
+    pub norm_blas_build: f32,
+    pub norm_tlas_build: f32,
+    pub norm_bvh_memory: f32,
+    pub score: f32,
+}
+
+impl ParamsFile {
+    pub fn load(path: &str) -> Self {
+        let f = File::open(path).expect("Failed opening params file");
+        match from_reader(f) {
+            Ok(params) => params,
+            Err(e) => panic!("Failed to load params file {}: {}", path, e),
+        }
+    }
+
+    pub fn save(&self, path: &str) {
+        let ron = ron::ser::to_string_pretty(self, PrettyConfig::default())
+            .expect("Failed serializing params file");
+        std::fs::write(path, ron).unwrap_or_else(|e| panic!("Failed writing {}: {}", path, e));
+    }
+
+    /// Sets the build parameter options, which `build_params_from_options` turns into `BvhBuildParams`, and the
+    /// builder, target and GPU kernel options the file has.
+    pub fn apply(&self, options: &mut Options) {
+        if ![64, 128].contains(&self.sort_precision) {
+            panic!("Unsupported sort precision {}", self.sort_precision);
+        }
+        if let Some(build) = &self.build {
+            options.build = build.clone();
+        }
+        if let Some(cpu) = self.cpu {
+            options.cpu = cpu;
+        }
+        if let Some(kernel) = self.gpu_kernel {
+            options.rt_vgpr_stack_size = kernel.rt_vgpr_stack_size;
+            options.rt_lds_stack_size = kernel.rt_lds_stack_size;
+            options.rt_group_width = kernel.rt_group_width;
+            options.rt_group_height = kernel.rt_group_height;
+        }
+        options.split = self.pre_split;
+        options.search_distance = self.ploc_search_distance;
+        options.search_depth_threshold = self.search_depth_threshold;
+        options.reinsertion_batch_ratio = self.reinsertion_batch_ratio;
+        options.sort_precision = self.sort_precision;
+        options.max_prims_per_leaf = self.max_prims_per_leaf;
+        options.post_collapse_reinsertion_batch_ratio_multiplier =
+            self.post_collapse_reinsertion_batch_ratio_multiplier;
+        options.collapse_traversal_cost = self.collapse_traversal_cost;
+    }
+}
@@ -0,0 +1,64 @@
+use obvhs::{aabb::Aabb, bvh2::Bvh2, cwbvh::CwBvh, triangle::Triangle};
+
+use crate::cwbvh::{quantize_cwbvh_node, CwBvhNodeExt};
+
+/// Recomputes the node bounds of `bvh` bottom up after the triangles have moved. The topology is kept, so the tree
+/// quality degrades as the geometry moves further away from the pose it was built for.
+/// If the BVH was built with pre-splits the refitted bounds use the whole triangle, which is conservative but valid.
+pub fn refit_bvh2(bvh: &mut Bvh2, tris: &[Triangle]) {
+    if !bvh.nodes.is_empty() {
+        refit_bvh2_node(bvh, tris, 0);
+    }
+}
+
+fn refit_bvh2_node(bvh: &mut Bvh2, tris: &[Triangle], node_index: usize) -> Aabb {
+    let node = bvh.nodes[node_index];
+    let aabb = if node.is_leaf() {
+        let first = node.first_index as usize;
+        bvh.primitive_indices[first..first + node.prim_count as usize]
+            .iter()
+            .fold(Aabb::INVALID, |aabb, i| {
+                aabb.union(&tris[*i as usize].aabb())
+            })
+    } else {
+        let left = refit_bvh2_node(bvh, tris, node.first_index as usize);
+        let right = refit_bvh2_node(bvh, tris, node.first_index as usize + 1);
+        left.union(&right)
+    };
+    bvh.nodes[node_index].aabb = aabb;
+    aabb
+}
+
+/// Refits a CwBvh in place by recomputing the exact child bounds bottom up and re-quantizing every node. Child slot
+/// order is not updated, so the octant based traversal order also degrades over time.
+pub fn refit_cwbvh(bvh: &mut CwBvh, tris: &[Triangle]) {
+    if !bvh.nodes.is_empty() {
+        bvh.total_aabb = refit_cwbvh_node(bvh, tris, 0);
+    }
+}
+
+fn refit_cwbvh_node(bvh: &mut CwBvh, tris: &[Triangle], node_index: usize) -> Aabb {
+    let node = bvh.nodes[node_index];
+    let mut child_aabbs = [Aabb::INVALID; 8];
+    let mut node_aabb = Aabb::INVALID;
+    for (ch, child_aabb) in child_aabbs.iter_mut().enumerate() {
+        if node.child_is_empty(ch) {
+            continue;
+        }
+        *child_aabb = if node.child_is_inner(ch) {
+            refit_cwbvh_node(bvh, tris, node.child_node_index(ch))
+        } else {
+            bvh.primitive_indices[node.child_primitives(ch)]
+                .iter()
+                .fold(Aabb::INVALID, |aabb, i| {
+                    aabb.union(&tris[*i as usize].aabb())
+                })
+        };
+        node_aabb = node_aabb.union(child_aabb);
+    }
+    quantize_cwbvh_node(&mut bvh.nodes[node_index], &node_aabb, &child_aabbs);
+    if let Some(exact_node_aabbs) = &mut bvh.exact_node_aabbs {
+        exact_node_aabbs[node_index] = node_aabb;
+    }
+    node_aabb
+}
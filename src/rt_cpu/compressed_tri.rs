@@ -0,0 +1,72 @@
+use glam::{Vec2, Vec3A};
+use half::f16;
+use obvhs::{ray::Ray, rt_triangle::RtCompressedTriangle};
+use traversable::Intersectable;
+
+/// The 24 byte `RtCompressedTriangle` used by the GPU software path, with the edges stored as f16. Decoded the same
+/// way as `unpack_triangle` in the software RT shaders so the CPU and GPU see the same geometry.
+#[derive(Clone, Copy)]
+pub struct SceneRtCompressedTri(pub RtCompressedTriangle);
+
+impl SceneRtCompressedTri {
+    /// Returns v0 and the two edges. Edge 1 is in the high and edge 2 in the low 16 bits.
+    #[inline(always)]
+    fn unpack(&self) -> (Vec3A, Vec3A, Vec3A) {
+        let high = |u: u32| f16::from_bits((u >> 16) as u16).to_f32();
+        let low = |u: u32| f16::from_bits(u as u16).to_f32();
+        let [x, y, z] = self.0.e1_e2;
+        (
+            Vec3A::from(self.0.v0),
+            Vec3A::new(high(x), high(y), high(z)),
+            Vec3A::new(low(x), low(y), low(z)),
+        )
+    }
+
+    /// Port of `intersect_ray_tri` from the shaders. Returns t and the barycentric uv, t is infinite on a miss.
+    #[inline(always)]
+    fn intersect_uv(&self, ray: &Ray) -> (f32, Vec2) {
+        let (v0, e1, e2) = self.unpack();
+        let e1 = -e1;
+        let ng = e1.cross(e2);
+
+        let c = v0 - ray.origin;
+        let r = ray.direction.cross(c);
+        let inv_det = 1.0 / ng.dot(ray.direction);
+
+        let u = r.dot(e2) * inv_det;
+        let v = r.dot(e1) * inv_det;
+        let w = 1.0 - u - v;
+
+        // Any negative barycentric sets the sign bit
+        let hit = u.to_bits() | v.to_bits() | w.to_bits();
+        if inv_det != 0.0 && hit & 0x8000_0000 == 0 {
+            let t = ng.dot(c) * inv_det;
+            if t >= ray.tmin && t <= ray.tmax {
+                return (t, Vec2::new(u, v));
+            }
+        }
+        (f32::INFINITY, Vec2::ZERO)
+    }
+}
+
+impl From<&obvhs::triangle::Triangle> for SceneRtCompressedTri {
+    fn from(tri: &obvhs::triangle::Triangle) -> Self {
+        SceneRtCompressedTri(tri.into())
+    }
+}
+
+impl Intersectable for SceneRtCompressedTri {
+    #[inline(always)]
+    fn intersect(&self, ray: &Ray) -> f32 {
+        self.intersect_uv(ray).0
+    }
+    #[inline(always)]
+    fn compute_normal(&self, _ray: &Ray) -> Vec3A {
+        let (_, e1, e2) = self.unpack();
+        e1.cross(e2).normalize_or_zero()
+    }
+    #[inline(always)]
+    fn compute_barycentric(&self, ray: &Ray) -> Vec2 {
+        self.intersect_uv(ray).1
+    }
+}
@@ -0,0 +1,458 @@
+use std::{error::Error, fs::File};
+
+use glam::{uvec2, Vec2};
+use image::{ImageBuffer, Rgba};
+use obvhs::{
+    bvh2::Bvh2,
+    cwbvh::CwBvh,
+    ray::{Ray, RayHit},
+};
+use rayon::{
+    iter::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator},
+    slice::ParallelSliceMut,
+};
+use tabled::Tabled;
+use traversable::{Intersectable, Traversable};
+
+use crate::{
+    cwbvh::{CwBvhInstancedTlasScene, CwBvhNodeExt, CwBvhScene, CwBvhTlasScene},
+    rt_cpu::{
+        rt_cpu::{primary_ray, temperature},
+        Bvh2Scene,
+    },
+    Options, Scene, ViewUniform,
+};
+
+#[derive(Default, Clone, Copy)]
+pub struct TraversalCounts {
+    /// Node or child bounds tested against the ray.
+    pub aabb_tests: u32,
+    /// Primitives tested against the ray.
+    pub tri_tests: u32,
+}
+
+pub trait CountedTraversable: Traversable {
+    /// Same result as `traverse`, but also counts the bounds and primitive tests. Uses a plain stack traversal instead
+    /// of the optimized obvhs one, so it's only meant for visualization.
+    fn traverse_counted(&self, ray: Ray, counts: &mut TraversalCounts) -> RayHit;
+}
+
+impl CountedTraversable for Bvh2Scene<'_> {
+    fn traverse_counted(&self, mut ray: Ray, counts: &mut TraversalCounts) -> RayHit {
+        let mut hit = RayHit::none();
+        bvh2_traverse_counted(
+            self.bvh,
+            &mut ray,
+            counts,
+            |_| {},
+            |ray, id, counts| {
+                counts.tri_tests += 1;
+                let t = self.tris[id].intersect(ray);
+                if t < ray.tmax {
+                    ray.tmax = t;
+                    hit.primitive_id = id as u32;
+                    hit.t = t;
+                }
+            },
+        );
+        hit
+    }
+}
+
+impl<T: Intersectable> CountedTraversable for CwBvhScene<'_, T> {
+    fn traverse_counted(&self, mut ray: Ray, counts: &mut TraversalCounts) -> RayHit {
+        let mut hit = RayHit::none();
+        cwbvh_traverse_counted(
+            self.bvh,
+            &mut ray,
+            counts,
+            |_| {},
+            |ray, id, counts| {
+                counts.tri_tests += 1;
+                let t = self.tris[id].intersect(ray);
+                if t < ray.tmax {
+                    ray.tmax = t;
+                    hit.primitive_id = id as u32;
+                    hit.t = t;
+                }
+            },
+        );
+        hit
+    }
+}
+
+impl<T: Intersectable> CountedTraversable for CwBvhTlasScene<T> {
+    fn traverse_counted(&self, mut ray: Ray, counts: &mut TraversalCounts) -> RayHit {
+        let mut hit = RayHit::none();
+        cwbvh_traverse_counted(
+            &self.tlas,
+            &mut ray,
+            counts,
+            |_| {},
+            |ray, id, counts| {
+                let blas_index = self.tlas.primitive_indices[id] as usize;
+                let mesh = &self.meshes[blas_index];
+                cwbvh_traverse_counted(
+                    &self.blas[blas_index],
+                    ray,
+                    counts,
+                    |_| {},
+                    |ray, id, counts| {
+                        counts.tri_tests += 1;
+                        let t = mesh[id].intersect(ray);
+                        if t < ray.tmax {
+                            ray.tmax = t;
+                            hit.primitive_id = id as u32;
+                            hit.geometry_id = blas_index as u32;
+                            hit.t = t;
+                        }
+                    },
+                );
+            },
+        );
+        hit
+    }
+}
+
+impl<T: Intersectable> CountedTraversable for CwBvhInstancedTlasScene<T> {
+    fn traverse_counted(&self, mut ray: Ray, counts: &mut TraversalCounts) -> RayHit {
+        let mut hit = RayHit::none();
+        cwbvh_traverse_counted(
+            &self.tlas,
+            &mut ray,
+            counts,
+            |_| {},
+            |ray, id, counts| {
+                let (instance, mut object_ray) = self.object_ray(ray, id);
+                let mesh_index = self.instances[instance].0 as usize;
+                let mesh = &self.meshes[mesh_index];
+                cwbvh_traverse_counted(
+                    &self.blas[mesh_index],
+                    &mut object_ray,
+                    counts,
+                    |_| {},
+                    |object_ray, id, counts| {
+                        counts.tri_tests += 1;
+                        let t = mesh[id].intersect(object_ray);
+                        if t < object_ray.tmax {
+                            object_ray.tmax = t;
+                            hit.primitive_id = id as u32;
+                            hit.geometry_id = mesh_index as u32;
+                            hit.instance_id = instance as u32;
+                            hit.t = t;
+                        }
+                    },
+                );
+                ray.tmax = object_ray.tmax;
+            },
+        );
+        hit
+    }
+}
+
+/// Calls `visit` with the index of each node the ray reaches and `leaf` with the index into `primitive_indices` of each
+/// primitive in the leaves it reaches. `leaf` is expected to shorten `ray.tmax` on hits.
+pub fn bvh2_traverse_counted(
+    bvh: &Bvh2,
+    ray: &mut Ray,
+    counts: &mut TraversalCounts,
+    mut visit: impl FnMut(usize),
+    mut leaf: impl FnMut(&mut Ray, usize, &mut TraversalCounts),
+) {
+    if bvh.nodes.is_empty() {
+        return;
+    }
+    counts.aabb_tests += 1;
+    let t = bvh.nodes[0].aabb.intersect_ray(ray);
+    let mut stack = vec![(0, t)];
+    while let Some((node_index, t)) = stack.pop() {
+        if t >= ray.tmax {
+            continue;
+        }
+        visit(node_index);
+        let node = &bvh.nodes[node_index];
+        if node.is_leaf() {
+            let first = node.first_index as usize;
+            for id in first..first + node.prim_count as usize {
+                leaf(ray, id, counts);
+            }
+            continue;
+        }
+        let left = node.first_index as usize;
+        let right = left + 1;
+        counts.aabb_tests += 2;
+        let t_left = bvh.nodes[left].aabb.intersect_ray(ray);
+        let t_right = bvh.nodes[right].aabb.intersect_ray(ray);
+        // Push the far child first so the near one is visited first
+        let (near, far) = if t_left <= t_right {
+            ((left, t_left), (right, t_right))
+        } else {
+            ((right, t_right), (left, t_left))
+        };
+        stack.extend([far, near].into_iter().filter(|(_, t)| *t < ray.tmax));
+    }
+}
+
+/// Same as `bvh2_traverse_counted` for CwBvh. Each non-empty child of a visited node counts as one bounds test.
+pub fn cwbvh_traverse_counted(
+    bvh: &CwBvh,
+    ray: &mut Ray,
+    counts: &mut TraversalCounts,
+    mut visit: impl FnMut(usize),
+    mut leaf: impl FnMut(&mut Ray, usize, &mut TraversalCounts),
+) {
+    if bvh.nodes.is_empty() {
+        return;
+    }
+    let mut stack = vec![(0, 0.0)];
+    let mut inner_hits = Vec::with_capacity(8);
+    while let Some((node_index, t)) = stack.pop() {
+        if t >= ray.tmax {
+            continue;
+        }
+        visit(node_index);
+        let node = &bvh.nodes[node_index];
+        inner_hits.clear();
+        for ch in 0..8 {
+            if node.child_is_empty(ch) {
+                continue;
+            }
+            counts.aabb_tests += 1;
+            let t = node.child_aabb(ch).intersect_ray(ray);
+            if t >= ray.tmax {
+                continue;
+            }
+            if node.child_is_inner(ch) {
+                inner_hits.push((node.child_node_index(ch), t));
+            } else {
+                for id in node.child_primitives(ch) {
+                    leaf(ray, id, counts);
+                }
+            }
+        }
+        // Farthest first so the nearest child is visited first
+        inner_hits.sort_by(|a, b| b.1.total_cmp(&a.1));
+        stack.extend_from_slice(&inner_hits);
+    }
+}
+
+/// Summary of a per ray count.
+#[derive(Default, Clone, Copy)]
+pub struct CountSummary {
+    pub mean: f32,
+    pub p50: u32,
+    pub p90: u32,
+    pub p99: u32,
+    pub max: u32,
+}
+
+impl CountSummary {
+    pub fn new(counts: impl Iterator<Item = u32>) -> Self {
+        let mut counts = counts.collect::<Vec<_>>();
+        if counts.is_empty() {
+            return CountSummary::default();
+        }
+        counts.par_sort_unstable();
+        let percentile = |p: f32| counts[((counts.len() - 1) as f32 * p).round() as usize];
+        CountSummary {
+            mean: counts.iter().map(|c| *c as f64).sum::<f64>() as f32 / counts.len() as f32,
+            p50: percentile(0.5),
+            p90: percentile(0.9),
+            p99: percentile(0.99),
+            max: *counts.last().unwrap(),
+        }
+    }
+}
+
+/// Summaries of the bounds and primitive tests per ray.
+#[derive(Default, Clone, Copy)]
+pub struct RayTestSummary {
+    pub aabb_tests: CountSummary,
+    pub tri_tests: CountSummary,
+}
+
+impl RayTestSummary {
+    fn new(counts: &[TraversalCounts]) -> Self {
+        RayTestSummary {
+            aabb_tests: CountSummary::new(counts.iter().map(|c| c.aabb_tests)),
+            tri_tests: CountSummary::new(counts.iter().map(|c| c.tri_tests)),
+        }
+    }
+}
+
+/// Per ray counts of a `--heatmap` or `--profile-rt` frame. `ao` only covers the pixels that traced an AO ray and is
+/// `None` for `--heatmap`, which only traces primary rays.
+#[derive(Clone, Copy)]
+pub struct TraversalCountStats {
+    pub primary: RayTestSummary,
+    pub ao: Option<RayTestSummary>,
+}
+
+#[derive(Tabled)]
+pub struct HeatmapStats {
+    name: String,
+    build: String,
+    ray: &'static str,
+    counter: &'static str,
+    mean: f32,
+    p50: u32,
+    p90: u32,
+    p99: u32,
+    max: u32,
+}
+
+impl TraversalCountStats {
+    pub fn stats(&self, name: &str, build: &str) -> Vec<HeatmapStats> {
+        [("primary", Some(self.primary)), ("ao", self.ao)]
+            .into_iter()
+            .filter_map(|(ray, summary)| summary.map(|summary| (ray, summary)))
+            .flat_map(|(ray, summary)| {
+                [
+                    ("aabb_tests", summary.aabb_tests),
+                    ("tri_tests", summary.tri_tests),
+                ]
+                .map(|(counter, summary)| HeatmapStats {
+                    name: name.to_string(),
+                    build: build.to_string(),
+                    ray,
+                    counter,
+                    mean: summary.mean,
+                    p50: summary.p50,
+                    p90: summary.p90,
+                    p99: summary.p99,
+                    max: summary.max,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Traces one primary ray per pixel with `traverse_counted` and reports the counts with `report_traversal_counts`.
+/// Colours use the same scale for every builder (`--heatmap-aabb-scale`, `--heatmap-tri-scale`) so the images can be
+/// compared side by side.
+pub fn heatmap<T>(
+    file_name: &str,
+    options: &Options,
+    scene: &Scene,
+    bvh_and_prims: &T,
+) -> TraversalCountStats
+where
+    T: CountedTraversable + Sync,
+{
+    let cam = ViewUniform::from_camera(
+        &scene.camera,
+        options.width as f32,
+        options.height as f32,
+        0,
+    );
+    let target_size = Vec2::new(options.width as f32, options.height as f32);
+    let counts = (0..options.width * options.height)
+        .into_par_iter()
+        .map(|i| {
+            let frag_coord = uvec2(i % options.width, i / options.width);
+            let mut counts = TraversalCounts::default();
+            bvh_and_prims.traverse_counted(primary_ray(&cam, frag_coord, target_size), &mut counts);
+            counts
+        })
+        .collect::<Vec<_>>();
+
+    report_traversal_counts(file_name, options, "heat", &counts, &[])
+}
+
+/// Writes heatmaps of the bounds and primitive tests (`<scene>_<prefix>_aabb.png`, `<scene>_<prefix>_tri.png`) and a
+/// CSV histogram of both (`<scene>_<prefix>_hist.csv`) of the primary rays, and the same with `<prefix>_ao` for the AO
+/// rays, and returns the mean, percentiles and max per ray. `primary` has one entry per pixel, `ao` is either empty or
+/// has one per pixel, `None` where no AO ray was traced.
+pub fn report_traversal_counts(
+    file_name: &str,
+    options: &Options,
+    prefix: &str,
+    primary: &[TraversalCounts],
+    ao: &[Option<TraversalCounts>],
+) -> TraversalCountStats {
+    save_traversal_counts(
+        &format!("{}_{}", file_name, prefix),
+        options,
+        primary,
+        primary,
+    );
+    let ao = (!ao.is_empty()).then(|| {
+        let per_pixel = ao.iter().map(|c| c.unwrap_or_default()).collect::<Vec<_>>();
+        let traced = ao.iter().flatten().copied().collect::<Vec<_>>();
+        save_traversal_counts(
+            &format!("{}_{}_ao", file_name, prefix),
+            options,
+            &per_pixel,
+            &traced,
+        );
+        RayTestSummary::new(&traced)
+    });
+    TraversalCountStats {
+        primary: RayTestSummary::new(primary),
+        ao,
+    }
+}
+
+/// Heatmaps of `per_pixel` and the histogram of `rays`.
+fn save_traversal_counts(
+    path_prefix: &str,
+    options: &Options,
+    per_pixel: &[TraversalCounts],
+    rays: &[TraversalCounts],
+) {
+    save_heatmap(
+        &format!("{}_aabb.png", path_prefix),
+        options,
+        per_pixel,
+        |c| c.aabb_tests as f32 / options.heatmap_aabb_scale,
+    );
+    save_heatmap(
+        &format!("{}_tri.png", path_prefix),
+        options,
+        per_pixel,
+        |c| c.tri_tests as f32 / options.heatmap_tri_scale,
+    );
+    save_histogram(&format!("{}_hist.csv", path_prefix), rays)
+        .expect("Failed to save traversal count histogram");
+}
+
+fn save_heatmap(
+    path: &str,
+    options: &Options,
+    counts: &[TraversalCounts],
+    value: impl Fn(&TraversalCounts) -> f32 + Sync,
+) {
+    let mut img: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::new(options.width, options.height);
+    let pixels = img.as_mut();
+    pixels.par_chunks_mut(4).enumerate().for_each(|(i, chunk)| {
+        let c = (temperature(value(&counts[i])) * 255.0).as_uvec3();
+        chunk.copy_from_slice(&[c.x as u8, c.y as u8, c.z as u8, 255]);
+    });
+    img.save(path).expect("Failed to save heatmap");
+}
+
+/// Number of rays for each count, one row per count up to the max of either counter.
+fn save_histogram(path: &str, counts: &[TraversalCounts]) -> Result<(), Box<dyn Error>> {
+    let max = counts
+        .iter()
+        .map(|c| c.aabb_tests.max(c.tri_tests))
+        .max()
+        .unwrap_or(0) as usize;
+    let mut aabb_histogram = vec![0usize; max + 1];
+    let mut tri_histogram = vec![0usize; max + 1];
+    for c in counts {
+        aabb_histogram[c.aabb_tests as usize] += 1;
+        tri_histogram[c.tri_tests as usize] += 1;
+    }
+    let mut wtr = csv::Writer::from_writer(File::create(path)?);
+    wtr.write_record(&["count", "aabb_tests_rays", "tri_tests_rays"])?;
+    for count in 0..=max {
+        wtr.write_record(&[
+            count.to_string(),
+            aabb_histogram[count].to_string(),
+            tri_histogram[count].to_string(),
+        ])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
@@ -1,14 +1,27 @@
+pub mod compressed_tri;
+pub mod heatmap;
 pub mod rt_cpu;
 
 use std::time::Duration;
 
 use crate::{
-    cwbvh::{cwbvh_from_tris, tlas_from_blas, CwBvhScene, CwBvhTlasScene},
+    cwbvh::{
+        build_blas, cwbvh_memory, tlas_from_blas, CwBvhInstancedTlasScene, CwBvhScene,
+        CwBvhTlasScene,
+    },
+    instancing::instances_are_objects,
+    metrics::{cwbvh_metrics_tree_from_options, MetricsTree},
+    node_layout::{layout_rays, layout_tlas},
+    rt_cpu::{
+        compressed_tri::SceneRtCompressedTri,
+        heatmap::{heatmap, CountedTraversable, TraversalCountStats},
+    },
     Options, Scene,
 };
-use glam::Mat4;
+use glam::{Affine3A, Mat4};
 use obvhs::{
     bvh2::Bvh2,
+    cwbvh::CwBvh,
     ray::{Ray, RayHit},
     triangle::Triangle,
 };
@@ -16,63 +29,173 @@ use traversable::{Intersectable, SceneRtTri, Traversable};
 
 pub fn cwbvh_cpu_runner(
     objects: &Vec<Vec<Triangle>>,
+    instances: &[(u32, Affine3A)],
     options: &Options,
     blas_build_time: &mut Duration,
     tlas_build_time: &mut Duration,
+    bvh_memory: &mut usize,
+    metrics_tree: &mut Option<MetricsTree>,
+    traversal_counts: &mut Option<TraversalCountStats>,
     file_name: &str,
     scene: Scene,
     #[cfg(feature = "embree")] embree_device: Option<&embree4_rs::Device>,
 ) -> f32 {
-    let mut rt_meshes = Vec::with_capacity(objects.len());
-    let mut blas = Vec::with_capacity(objects.len());
+    let layout_rays = layout_rays(options, &scene);
+    let blas = build_blas(
+        objects,
+        options,
+        blas_build_time,
+        &layout_rays,
+        #[cfg(feature = "embree")]
+        embree_device,
+    );
 
-    // Build BLAS
-    for tris in objects {
-        let bvh = cwbvh_from_tris(
-            &tris,
-            &options,
-            blas_build_time,
-            #[cfg(feature = "embree")]
-            embree_device,
-        );
-        // map tris to match indices order in bvh to avoid extra indirection during traversal
-        let tris = bvh
-            .primitive_indices
-            .iter()
-            .map(|i| SceneRtTri((&tris[*i as usize]).into()))
-            .collect::<Vec<SceneRtTri>>();
-        rt_meshes.push(tris);
-        blas.push(bvh);
-    }
-
-    if options.tlas {
-        // Build TLAS
-        let tlas_bvh = tlas_from_blas(
+    let tlas = options.tlas.then(|| {
+        let mut tlas = tlas_from_blas(
             &blas,
+            instances,
             options,
             tlas_build_time,
             #[cfg(feature = "embree")]
             embree_device,
         );
-        let cwbvh_scene = CwBvhTlasScene {
-            blas,
-            meshes: rt_meshes,
-            tlas: tlas_bvh,
-        };
-        rt_cpu::start(file_name, &options, &scene, &cwbvh_scene)
+        layout_tlas(&mut tlas, &blas, objects, instances, options, &layout_rays);
+        tlas
+    });
+    if instances_are_objects(instances) {
+        *metrics_tree = cwbvh_metrics_tree_from_options(options, objects, &blas, tlas.as_ref());
+    } else if options.metrics {
+        println!("--metrics is not available with --detect-instances");
+    }
+
+    match options.cpu_tris.as_str() {
+        "compressed" => {
+            let rt_meshes = rt_meshes::<SceneRtCompressedTri>(objects, &blas);
+            print_tri_memory::<SceneRtCompressedTri>(&rt_meshes);
+            *bvh_memory = cwbvh_scene_memory(&blas, tlas.as_ref(), instances, &rt_meshes);
+            let (frame_time, counts) =
+                run_cwbvh_scene(file_name, options, &scene, blas, tlas, instances, rt_meshes);
+            *traversal_counts = counts;
+            frame_time
+        }
+        _ => {
+            let rt_meshes = rt_meshes::<SceneRtTri>(objects, &blas);
+            if options.verbose {
+                print_tri_memory::<SceneRtTri>(&rt_meshes);
+            }
+            *bvh_memory = cwbvh_scene_memory(&blas, tlas.as_ref(), instances, &rt_meshes);
+            let (frame_time, counts) =
+                run_cwbvh_scene(file_name, options, &scene, blas, tlas, instances, rt_meshes);
+            *traversal_counts = counts;
+            frame_time
+        }
+    }
+}
+
+/// Maps tris to match the indices order in each BLAS to avoid extra indirection during traversal.
+fn rt_meshes<T>(objects: &[Vec<Triangle>], blas: &[CwBvh]) -> Vec<Vec<T>>
+where
+    T: for<'a> From<&'a Triangle>,
+{
+    objects
+        .iter()
+        .zip(blas)
+        .map(|(tris, bvh)| {
+            bvh.primitive_indices
+                .iter()
+                .map(|i| T::from(&tris[*i as usize]))
+                .collect()
+        })
+        .collect()
+}
+
+/// Nodes, triangles and, if instanced, the instances used during traversal.
+fn cwbvh_scene_memory<T>(
+    blas: &[CwBvh],
+    tlas: Option<&CwBvh>,
+    instances: &[(u32, Affine3A)],
+    rt_meshes: &[Vec<T>],
+) -> usize {
+    let instance_memory = if tlas.is_some() && !instances_are_objects(instances) {
+        size_of_val(instances)
     } else {
-        rt_cpu::start(
-            file_name,
-            &options,
-            &scene,
-            &CwBvhScene {
+        0
+    };
+    cwbvh_memory(blas, tlas)
+        + rt_meshes
+            .iter()
+            .map(|m| size_of_val(m.as_slice()))
+            .sum::<usize>()
+        + instance_memory
+}
+
+/// Prints the memory used by the CPU triangles, next to what the same triangles would take as `RtTriangle`.
+fn print_tri_memory<T>(rt_meshes: &[Vec<T>]) {
+    let count = rt_meshes.iter().map(|m| m.len()).sum::<usize>();
+    let mb = |size: usize| (count * size) as f32 / (1024.0 * 1024.0);
+    println!(
+        "CPU triangles: {} x {} bytes = {:.2} MB (RtTriangle: {} bytes, {:.2} MB)",
+        count,
+        size_of::<T>(),
+        mb(size_of::<T>()),
+        size_of::<SceneRtTri>(),
+        mb(size_of::<SceneRtTri>()),
+    );
+}
+
+fn run_cwbvh_scene<T>(
+    file_name: &str,
+    options: &Options,
+    scene: &Scene,
+    blas: Vec<CwBvh>,
+    tlas: Option<CwBvh>,
+    instances: &[(u32, Affine3A)],
+    rt_meshes: Vec<Vec<T>>,
+) -> (f32, Option<TraversalCountStats>)
+where
+    T: Intersectable + Sync,
+{
+    match tlas {
+        Some(tlas) if instances_are_objects(instances) => {
+            let cwbvh_scene = CwBvhTlasScene {
+                blas,
+                meshes: rt_meshes,
+                tlas,
+            };
+            render_cwbvh_scene(file_name, options, scene, &cwbvh_scene)
+        }
+        Some(tlas) => {
+            let cwbvh_scene =
+                CwBvhInstancedTlasScene::new(blas, rt_meshes, tlas, instances.to_vec());
+            render_cwbvh_scene(file_name, options, scene, &cwbvh_scene)
+        }
+        None => {
+            let cwbvh_scene = CwBvhScene {
                 bvh: &blas[0],
                 tris: &rt_meshes[0],
-            },
-        )
+            };
+            render_cwbvh_scene(file_name, options, scene, &cwbvh_scene)
+        }
     }
 }
 
+/// Renders `cwbvh_scene`, and with `--heatmap` also the traversal counts.
+fn render_cwbvh_scene<S>(
+    file_name: &str,
+    options: &Options,
+    scene: &Scene,
+    cwbvh_scene: &S,
+) -> (f32, Option<TraversalCountStats>)
+where
+    S: CountedTraversable + Sync,
+{
+    let frame_time = rt_cpu::start(file_name, &options, &scene, cwbvh_scene);
+    let traversal_counts = options
+        .heatmap
+        .then(|| heatmap(file_name, &options, &scene, cwbvh_scene));
+    (frame_time, traversal_counts)
+}
+
 pub struct Bvh2Scene<'a> {
     pub bvh: &'a Bvh2,
     pub tris: &'a [SceneRtTri],
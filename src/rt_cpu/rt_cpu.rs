@@ -1,6 +1,6 @@
 use std::time::Instant;
 
-use glam::{uvec2, vec2, vec4, Vec2, Vec3, Vec3A, Vec4Swizzles};
+use glam::{uvec2, vec2, vec3, vec4, UVec2, Vec2, Vec3, Vec3A, Vec4Swizzles};
 use image::{ImageBuffer, Rgba};
 use obvhs::{
     ray::Ray,
@@ -10,7 +10,7 @@ use rayon::{
     iter::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator},
     slice::ParallelSliceMut,
 };
-use traversable::{Intersectable, Traversable};
+use traversable::Traversable;
 
 use crate::{Options, Scene, ViewUniform};
 
@@ -39,41 +39,17 @@ where
                     (i as u32 % options.width) as u32,
                     (i as u32 / options.width) as u32,
                 );
-                let mut screen_uv = frag_coord.as_vec2() / target_size;
-                screen_uv.y = 1.0 - screen_uv.y;
-                let ndc = screen_uv * 2.0 - Vec2::ONE;
-                let clip_pos = vec4(ndc.x, ndc.y, 1.0, 1.0);
-
-                let mut vs = cam.proj_inv * clip_pos;
-                vs /= vs.w;
-                let eye: Vec3A = cam.eye.into();
-                let ray = Ray::new(
-                    eye,
-                    (Vec3A::from((cam.view_inv * vs).xyz()) - eye).normalize(),
-                    0.0,
-                    f32::MAX,
-                );
+                let ray = primary_ray(&cam, frag_coord, target_size);
 
                 let hit = bvh_and_prims.traverse(ray);
 
                 let mut col = Vec3::splat(1.0 / hit.t);
 
                 if hit.t < f32::MAX {
-                    let mut n = bvh_and_prims
-                        .get_primitive(hit.geometry_id, hit.primitive_id)
-                        .compute_normal(&ray);
+                    let mut n = bvh_and_prims.hit_normal(&hit, &ray);
                     n *= n.dot(-ray.direction).signum(); //Double sided
 
-                    let ao_ray_origin = eye + ray.direction * hit.t - ray.direction * 0.01;
-
-                    let tangent_to_world = build_orthonormal_basis(n);
-                    let mut ao_ray_dir = cosine_sample_hemisphere(vec2(
-                        hash_noise(frag_coord, frame_count),
-                        hash_noise(frag_coord, frame_count + 1024),
-                    ));
-                    ao_ray_dir = (tangent_to_world * ao_ray_dir).normalize();
-
-                    let ao_ray = Ray::new(ao_ray_origin, ao_ray_dir, 0.0, f32::MAX);
+                    let ao_ray = ao_ray(&ray, hit.t, n, frag_coord, frame_count);
 
                     // Actual AO could use a faster anyhit query.
                     // Just using a normal closest query here for simplicity and to create a bit more work for the benchmark.
@@ -119,3 +95,71 @@ where
     }
     avg_render_time_ms
 }
+
+/// Camera ray through the given pixel.
+#[inline(always)]
+pub fn primary_ray(cam: &ViewUniform, frag_coord: UVec2, target_size: Vec2) -> Ray {
+    let mut screen_uv = frag_coord.as_vec2() / target_size;
+    screen_uv.y = 1.0 - screen_uv.y;
+    let ndc = screen_uv * 2.0 - Vec2::ONE;
+    let clip_pos = vec4(ndc.x, ndc.y, 1.0, 1.0);
+
+    let mut vs = cam.proj_inv * clip_pos;
+    vs /= vs.w;
+    let eye: Vec3A = cam.eye.into();
+    Ray::new(
+        eye,
+        (Vec3A::from((cam.view_inv * vs).xyz()) - eye).normalize(),
+        0.0,
+        f32::MAX,
+    )
+}
+
+/// Cosine distributed AO ray from the hit of `ray` at `t`, with the double sided normal `n` facing the ray.
+#[inline(always)]
+pub fn ao_ray(ray: &Ray, t: f32, n: Vec3A, frag_coord: UVec2, frame_count: u32) -> Ray {
+    let ao_ray_origin = ray.origin + ray.direction * t - ray.direction * 0.01;
+
+    let tangent_to_world = build_orthonormal_basis(n);
+    let mut ao_ray_dir = cosine_sample_hemisphere(vec2(
+        hash_noise(frag_coord, frame_count),
+        hash_noise(frag_coord, frame_count + 1024),
+    ));
+    ao_ray_dir = (tangent_to_world * ao_ray_dir).normalize();
+
+    Ray::new(ao_ray_origin, ao_ray_dir, 0.0, f32::MAX)
+}
+
+/// Blue to red colour ramp for visualizing costs, `t` in 0..1. Same as `temperature()` in sampling.hlsl.
+pub fn temperature(t: f32) -> Vec3 {
+    const C: [Vec3; 10] = [
+        vec3(0.0 / 255.0, 2.0 / 255.0, 91.0 / 255.0),
+        vec3(0.0 / 255.0, 108.0 / 255.0, 251.0 / 255.0),
+        vec3(0.0 / 255.0, 221.0 / 255.0, 221.0 / 255.0),
+        vec3(51.0 / 255.0, 221.0 / 255.0, 0.0 / 255.0),
+        vec3(1.0, 252.0 / 255.0, 0.0 / 255.0),
+        vec3(1.0, 180.0 / 255.0, 0.0 / 255.0),
+        vec3(1.0, 104.0 / 255.0, 0.0 / 255.0),
+        vec3(226.0 / 255.0, 22.0 / 255.0, 0.0 / 255.0),
+        vec3(191.0 / 255.0, 0.0 / 255.0, 83.0 / 255.0),
+        vec3(145.0 / 255.0, 0.0 / 255.0, 65.0 / 255.0),
+    ];
+    let smoothstep = |e0: f32, e1: f32, x: f32| {
+        let t = ((x - e0) / (e1 - e0)).clamp(0.0, 1.0);
+        t * t * (3.0 - 2.0 * t)
+    };
+
+    let s = t * 10.0;
+    let cur = (s.max(0.0) as usize).min(9);
+    let prv = cur.saturating_sub(1);
+    let nxt = (cur + 1).min(9);
+
+    let blur = 0.8;
+    let curf = cur as f32;
+    let wc = smoothstep(curf - blur, curf + blur, s)
+        * (1.0 - smoothstep(curf + 1.0 - blur, curf + 1.0 + blur, s));
+    let wp = 1.0 - smoothstep(curf - blur, curf + blur, s);
+    let wn = smoothstep(curf + 1.0 - blur, curf + 1.0 + blur, s);
+
+    (wc * C[cur] + wp * C[prv] + wn * C[nxt]).clamp(Vec3::ZERO, Vec3::ONE)
+}
@@ -1,4 +1,5 @@
 mod acceleration_structure_instance;
+pub mod profile_counts;
 pub mod rt_gpu_hardware;
 pub mod rt_gpu_software;
 pub mod shader_utils;
@@ -6,59 +7,114 @@ pub mod shader_utils;
 use std::time::Duration;
 
 use crate::{
-    cwbvh::{cwbvh_from_tris, tlas_from_blas},
+    cwbvh::{build_blas, tlas_from_blas},
+    instancing::instances_are_objects,
+    metrics::{cwbvh_metrics_tree_from_options, MetricsTree},
+    node_layout::{layout_rays, layout_tlas},
+    rt_cpu::heatmap::TraversalCountStats,
+    rt_gpu::acceleration_structure_instance::AccelerationStructureInstance,
     Options, Scene,
 };
 
+use bytemuck::{Pod, Zeroable};
+use glam::Affine3A;
 use obvhs::{rt_triangle::RtCompressedTriangle, triangle::Triangle};
 use winit::event_loop::EventLoop;
 
+/// `Instance` in rt_gpu_software_query_tlas.hlsl. The transforms are row major 3x4 matrices.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SoftwareInstance {
+    /// Index of the BLAS root node in the BVH buffer.
+    pub blas_offset: u32,
+    pub _padding: [u32; 3],
+    pub world_to_object: [f32; 12],
+    pub object_to_world: [f32; 12],
+}
+
+unsafe impl Pod for SoftwareInstance {}
+unsafe impl Zeroable for SoftwareInstance {}
+
+impl SoftwareInstance {
+    pub fn new(blas_offset: u32, object_to_world: &Affine3A) -> Self {
+        SoftwareInstance {
+            blas_offset,
+            _padding: [0; 3],
+            world_to_object: AccelerationStructureInstance::affine_to_rows(
+                &object_to_world.inverse(),
+            ),
+            object_to_world: AccelerationStructureInstance::affine_to_rows(object_to_world),
+        }
+    }
+}
+
 pub fn cwbvh_gpu_runner(
     event_loop: &mut EventLoop<()>,
     objects: &Vec<Vec<Triangle>>,
+    instances: &[(u32, Affine3A)],
     options: &Options,
     blas_build_time: &mut Duration,
     tlas_build_time: &mut Duration,
+    bvh_memory: &mut usize,
+    metrics_tree: &mut Option<MetricsTree>,
+    traversal_counts: &mut Option<TraversalCountStats>,
+    file_name: &str,
     scene: Scene,
     #[cfg(feature = "embree")] embree_device: Option<&embree4_rs::Device>,
 ) -> f32 {
-    let mut rt_meshes = Vec::with_capacity(objects.len());
-    let mut blas = Vec::with_capacity(objects.len());
-
-    // Build BLAS
-    let mut tri_offset = 0;
-    for tris in objects {
-        let mut bvh = cwbvh_from_tris(
-            &tris,
-            &options,
-            blas_build_time,
-            #[cfg(feature = "embree")]
-            embree_device,
-        );
-        // map tris to match indices order in bvh to avoid extra indirection during traversal
-        let tris: Vec<RtCompressedTriangle> = bvh
-            .primitive_indices
-            .iter()
-            .map(|i| (&tris[*i as usize]).into())
-            .collect::<Vec<_>>();
-        // Remap the tri index in to bvh so that it maps correctly into the tri buffer on the gpu
-        bvh.nodes
-            .iter_mut()
-            .for_each(|n| n.primitive_base_idx += tri_offset);
-        tri_offset += tris.len() as u32;
-        rt_meshes.push(tris);
-        blas.push(bvh);
-    }
+    let layout_rays = layout_rays(options, &scene);
+    let mut blas = build_blas(
+        objects,
+        options,
+        blas_build_time,
+        &layout_rays,
+        #[cfg(feature = "embree")]
+        embree_device,
+    );
+    // map tris to match indices order in bvh to avoid extra indirection during traversal
+    let rt_meshes = blas
+        .iter()
+        .zip(objects)
+        .map(|(bvh, tris)| {
+            bvh.primitive_indices
+                .iter()
+                .map(|i| (&tris[*i as usize]).into())
+                .collect::<Vec<RtCompressedTriangle>>()
+        })
+        .collect::<Vec<_>>();
 
     if options.tlas {
         // Build TLAS
-        let tlas_bvh = tlas_from_blas(
+        let mut tlas_bvh = tlas_from_blas(
             &blas,
+            instances,
             options,
             tlas_build_time,
             #[cfg(feature = "embree")]
             embree_device,
         );
+        layout_tlas(
+            &mut tlas_bvh,
+            &blas,
+            objects,
+            instances,
+            options,
+            &layout_rays,
+        );
+        if instances_are_objects(instances) {
+            *metrics_tree =
+                cwbvh_metrics_tree_from_options(options, objects, &blas, Some(&tlas_bvh));
+        } else if options.metrics {
+            println!("--metrics is not available with --detect-instances");
+        }
+        // Remap the tri index in to bvh so that it maps correctly into the tri buffer on the gpu
+        let mut tri_offset = 0;
+        for (bvh, tris) in blas.iter_mut().zip(&rt_meshes) {
+            bvh.nodes
+                .iter_mut()
+                .for_each(|n| n.primitive_base_idx += tri_offset);
+            tri_offset += tris.len() as u32;
+        }
         let mut bvh_bytes: Vec<u8> = Vec::new();
         let mut blas_mapping = Vec::new(); // Mapping from blas index to offset
         let mut blas_len = 0;
@@ -69,13 +125,18 @@ pub fn cwbvh_gpu_runner(
         }
         assert_eq!(bvh_bytes.len() as u32, blas_len * 5 * 4 * 4); // [uint4; 5]
 
-        let mut instance_bytes = Vec::new();
-        for prim_idx in tlas_bvh.primitive_indices {
-            // The tlas bvh has the indices in a specific order.
-            // Need to have the offsets in this order so the primitive index in the tlas can look up directly into this buffer
-            // Typically this buffer would also have transforms, and other instance related data.
-            instance_bytes.append(&mut blas_mapping[prim_idx as usize].to_le_bytes().to_vec());
-        }
+        // The tlas bvh has the indices in a specific order.
+        // Need to have the instances in this order so the primitive index in the tlas can look up directly into this buffer
+        let gpu_instances = tlas_bvh
+            .primitive_indices
+            .iter()
+            .map(|i| {
+                let (mesh, transform) = &instances[*i as usize];
+                SoftwareInstance::new(blas_mapping[*mesh as usize], transform)
+            })
+            .collect::<Vec<_>>();
+        let instance_bytes: &[u8] = bytemuck::cast_slice(&gpu_instances);
+        assert_eq!(instance_bytes.len(), gpu_instances.len() * 4 * 4 * 7); // [uint4; 7]
 
         let mut tri_bytes: Vec<u8> = Vec::new();
         let mut tris_count = 0;
@@ -89,24 +150,37 @@ pub fn cwbvh_gpu_runner(
         // Put the tlas at the end of the blas. Seems like this layout should be feasible
         // since the tlas would typically be written every frame.
         bvh_bytes.append(&mut tlas_bytes.to_vec());
+        *bvh_memory = bvh_bytes.len() + instance_bytes.len() + tri_bytes.len();
         rt_gpu_software::start(
             event_loop,
+            file_name,
             &options,
             &scene,
             &bvh_bytes,
-            &instance_bytes,
+            instance_bytes,
             &tri_bytes,
             blas_len,
+            traversal_counts,
         )
     } else {
+        *metrics_tree = cwbvh_metrics_tree_from_options(options, objects, &blas, None);
         let bvh = &blas[0];
         let tris = &rt_meshes[0];
         let blas_bytes = bytemuck::cast_slice(&bvh.nodes);
         assert_eq!(blas_bytes.len(), bvh.nodes.len() * 5 * 4 * 4); // [uint4; 5]
         let tri_bytes = bytemuck::cast_slice(&tris);
         assert_eq!(tri_bytes.len(), tris.len() * 2 * 3 * 4); //(float3, uint3)
+        *bvh_memory = blas_bytes.len() + tri_bytes.len();
         rt_gpu_software::start(
-            event_loop, &options, &scene, blas_bytes, &[0; 16], tri_bytes, 0,
+            event_loop,
+            file_name,
+            &options,
+            &scene,
+            blas_bytes,
+            &[0; 16],
+            tri_bytes,
+            0,
+            traversal_counts,
         )
     }
 }
@@ -0,0 +1,66 @@
+use wgpu::*;
+
+use crate::rt_cpu::heatmap::TraversalCounts;
+
+/// AO counts of the pixels where the primary ray missed, see rt_gpu_software.hlsl.
+const NO_AO_RAY: u32 = u32::MAX;
+
+/// Per pixel traversal counters written by the software RT shaders when compiled with `PROFILE_RT`.
+pub struct ProfileCounts {
+    pub buffer: Buffer,
+    readback: Buffer,
+}
+
+impl ProfileCounts {
+    pub fn new(device: &Device, pixel_count: u32) -> Self {
+        // uint4 (aabb_hit_count, tri_hit_count) of the primary and the AO ray per pixel
+        let size = pixel_count as u64 * 16;
+        let buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("Profile counts buffer"),
+            size,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let readback = device.create_buffer(&BufferDescriptor {
+            label: None,
+            size,
+            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        ProfileCounts { buffer, readback }
+    }
+
+    /// Copies the counters of the last dispatch back to the CPU. Returns the primary and the AO ray counts per pixel,
+    /// the AO ones are `None` where the primary ray missed.
+    pub fn read(
+        &self,
+        device: &Device,
+        queue: &Queue,
+    ) -> (Vec<TraversalCounts>, Vec<Option<TraversalCounts>>) {
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+        encoder.copy_buffer_to_buffer(&self.buffer, 0, &self.readback, 0, self.buffer.size());
+        queue.submit(Some(encoder.finish()));
+
+        let slice = self.readback.slice(..);
+        slice.map_async(MapMode::Read, |r| r.unwrap());
+        device.poll(PollType::Wait).unwrap();
+        let data = slice.get_mapped_range();
+        let counts = bytemuck::cast_slice::<u8, [u32; 4]>(&data)
+            .iter()
+            .map(|c| {
+                let primary = TraversalCounts {
+                    aabb_tests: c[0],
+                    tri_tests: c[1],
+                };
+                let ao = (c[2] != NO_AO_RAY).then_some(TraversalCounts {
+                    aabb_tests: c[2],
+                    tri_tests: c[3],
+                });
+                (primary, ao)
+            })
+            .unzip();
+        drop(data);
+        self.readback.unmap();
+        counts
+    }
+}
@@ -31,11 +31,9 @@ pub fn start(
 ) -> f32 {
     let src_dir = PathBuf::from(std::env::current_dir().unwrap()).join("src/rt_gpu");
     let src_path = src_dir.join("rt_gpu_hardware.hlsl");
-    let dst_path = src_dir.with_extension("spv");
+    let dst_path = compile_to_spirv(&src_path, "cs_6_1", &[], &options.shader_cache);
     let dst_string = dst_path.to_string_lossy();
 
-    compile_to_spirv(&src_path.to_string_lossy(), &dst_string, "cs_6_1");
-
     let slang_spv = load_shader_module(&dst_path);
 
     let avg_ms = futures::executor::block_on(start_internal(
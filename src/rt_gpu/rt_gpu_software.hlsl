@@ -1,16 +1,25 @@
+// The stack and group sizes are passed as -D defines from the --rt-* options, the defaults here are the same.
 // If both RT_VGPR_STACK_SIZE and RT_LDS_STACK_SIZE are > 0, the traversal stack will be split between LDS and VGPRs
 // Set RT_VGPR_STACK_SIZE to 0 to only use LDS (Also will need to increase LDS stack size)
+#ifndef RT_VGPR_STACK_SIZE
 #define RT_VGPR_STACK_SIZE 5
+#endif
 // Set RT_LDS_STACK_SIZE to 0 to only use VGPR (Also will need to increase VGPR stack size)
+#ifndef RT_LDS_STACK_SIZE
 #define RT_LDS_STACK_SIZE 4
+#endif
 // For the LDS to work, the group size (flattened to a single uint) must be defined as `RT_LDS_STACK_GROUP_SIZE`,
 // and the `g_thread_index_within_group` global must be set to the index of the thread within the group (also flattened).
 
+#ifndef RT_GROUP_SIZE_X
+#define RT_GROUP_SIZE_X 8
+#define RT_GROUP_SIZE_Y 8
 #define RT_LDS_STACK_GROUP_SIZE 64 // Total group size numthreads(8, 8, 1): 8*8*1
+#endif
 #define USE_TRIANGLE_POSTPONING 0  // Unimplemented
 #define BLAS_NODES_BINDING 3
 #define TRIS_BINDING 6
-// #define PROFILE_RT
+// #define PROFILE_RT // Or use --profile-rt
 
 #include "sampling.hlsl"
 
@@ -44,8 +53,15 @@ RWTexture2D<float4> output_texture;
 [[vk::binding(7, 0)]]
 RWStructuredBuffer<uint> NextTaskIndex;
 
-[numthreads(8, 8, 1)]
-//[numthreads(16, 16, 1)]
+#ifdef PROFILE_RT
+// Per pixel (aabb_hit_count, tri_hit_count) of the primary ray followed by the same for the AO ray, read back with
+// --profile-rt. The AO counts are NO_AO_RAY if the primary ray missed.
+#define NO_AO_RAY 0xffffffff
+[[vk::binding(8, 0)]]
+RWStructuredBuffer<uint4> profile_counts;
+#endif
+
+[numthreads(RT_GROUP_SIZE_X, RT_GROUP_SIZE_Y, 1)]
 void main(uint3 invocation_id: SV_DispatchThreadID, uint idx_within_group: SV_GroupIndex)
 {
 
@@ -67,6 +83,11 @@ void main(uint3 invocation_id: SV_DispatchThreadID, uint idx_within_group: SV_Gr
         //        uint2 frag_coord = uint2(taskId % target_size.x, taskId / target_size.x);
 
         uint2 frag_coord = invocation_id.xy;
+        // The last groups can be partly outside of the image
+        if (frag_coord.x >= target_size.x || frag_coord.y >= target_size.y)
+        {
+            return;
+        }
         float2 screen_uv = frag_coord / float2(target_size);
         screen_uv.y = 1.0f - screen_uv.y;
         float2 ndc = screen_uv * 2.0f - 1.0f;
@@ -88,19 +109,12 @@ void main(uint3 invocation_id: SV_DispatchThreadID, uint idx_within_group: SV_Gr
 
         bool did_hit = traverse_bvh(ray, hit);
 
-        float3 col = (1.0 / hit.t).xxx;
-
 #ifdef PROFILE_RT
+        uint2 counts = uint2(hit.aabb_hit_count, hit.tri_hit_count);
+        uint2 ao_counts = uint2(NO_AO_RAY, NO_AO_RAY);
+#endif
 
-        col = temperature(hit.aabb_hit_count * 0.002); // lt blue is 100, green is 200, orange is 300, red is 400
-
-        // if (distance(ray.direction.y, 0.0) < 0.001)
-        //{
-        //     col = 1.0.xxx;
-        // }
-
-        // col = temperature(hit.tri_hit_count * 0.01); // lt blue 10, green 25, yellow 50, orange 70, purp 100
-#else
+        float3 col = (1.0 / hit.t).xxx;
 
         if (did_hit)
         {
@@ -122,11 +136,19 @@ void main(uint3 invocation_id: SV_DispatchThreadID, uint idx_within_group: SV_Gr
 
             RtOutput ao_hit;
             ao_hit.t = F32_MAX;
+#ifdef PROFILE_RT
+            ao_hit.aabb_hit_count = 0;
+            ao_hit.tri_hit_count = 0;
+#endif
 
             // Actual AO could use a faster anyhit query.
             // Just using a normal closest query here for simplicity and to create a bit more work for the benchmark.
             bool ao_did_hit = traverse_bvh(ao_ray, ao_hit);
 
+#ifdef PROFILE_RT
+            ao_counts = uint2(ao_hit.aabb_hit_count, ao_hit.tri_hit_count);
+#endif
+
             if (ao_did_hit)
             {
                 float3 ao = ao_hit.t / (1.0 + ao_hit.t);
@@ -138,6 +160,20 @@ void main(uint3 invocation_id: SV_DispatchThreadID, uint idx_within_group: SV_Gr
             }
         }
         col = pow(col, 2.2);
+
+#ifdef PROFILE_RT
+        profile_counts[frag_coord.y * target_size.x + frag_coord.x] = uint4(counts, ao_counts);
+
+        // Primary and AO ray together
+        uint aabb_count = counts.x + (did_hit ? ao_counts.x : 0);
+        col = temperature(aabb_count * 0.002); // lt blue is 100, green is 200, orange is 300, red is 400
+
+        // if (distance(ray.direction.y, 0.0) < 0.001)
+        //{
+        //     col = 1.0.xxx;
+        // }
+
+        // col = temperature(counts.y * 0.01); // lt blue 10, green 25, yellow 50, orange 70, purp 100
 #endif
         output_texture[frag_coord] = float4(col, 1.0);
     }
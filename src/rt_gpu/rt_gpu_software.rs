@@ -3,13 +3,22 @@ use crate::{
         init_storage, rw_storage_buffer_layout, rwstorage_texture_layout, storage_buffer_layout,
         uniform_buffer, uniform_layout,
     },
-    rt_gpu::shader_utils::{compile_to_spirv, load_shader_module},
+    rt_cpu::heatmap::{report_traversal_counts, TraversalCountStats},
+    rt_gpu::{
+        profile_counts::ProfileCounts,
+        shader_utils::{compile_to_spirv, load_shader_module},
+    },
     timestamp::Timestamp,
     Options, Scene, ViewUniform,
 };
 
 use glam::*;
-use std::{mem, num::NonZeroU64, path::PathBuf, time::Instant};
+use std::{
+    mem,
+    num::NonZeroU64,
+    path::{Path, PathBuf},
+    time::Instant,
+};
 use wgpu::{
     util::{initialize_adapter_from_env_or_default, make_spirv_raw},
     wgt::CreateShaderModuleDescriptorPassthrough,
@@ -21,53 +30,198 @@ use winit::{
 
 const TEXTURE_FORMAT: TextureFormat = TextureFormat::Rgba8Unorm;
 
+/// Takes the place of rt_gpu_software_profile.wgsl without --profile-rt.
+const WGSL_NO_PROFILE: &str =
+    "const PROFILE_RT = false;\nfn store_profile_counts(index: u32, counts: vec4<u32>) {}\n";
+
+/// Limits `rt_kernel_error` checks the `--rt-*` options against without a device. Most desktop GPUs support these, the
+/// actual device limits are checked in `start_internal`.
+const MAX_RT_GROUP_SIZE: u32 = 1024;
+const MAX_RT_LDS_STACK_BYTES: u32 = 32768;
+/// The shaders don't check for stack overflow, a smaller stack would drop nodes and render a wrong image. This is the
+/// stack the shaders were written with (5 in VGPRs and 4 in LDS).
+const MIN_RT_STACK_SIZE: u32 = 9;
+
+enum KernelShader<'a> {
+    /// rt_gpu_software(_tlas).hlsl compiled by dxc
+    SpirV(ShaderModuleDescriptorSpirV<'a>),
+    /// The WGSL source from `wgsl_source`
+    Wgsl(String),
+}
+
+/// Why the `--rt-*` stack and workgroup sizes can't be used, if they can't.
+pub fn rt_kernel_error(
+    vgpr_stack_size: u32,
+    lds_stack_size: u32,
+    group_width: u32,
+    group_height: u32,
+) -> Option<String> {
+    let group_size = group_width
+        .checked_mul(group_height)
+        .filter(|size| (1..=MAX_RT_GROUP_SIZE).contains(size));
+    if vgpr_stack_size.saturating_add(lds_stack_size) < MIN_RT_STACK_SIZE {
+        Some(format!(
+            "--rt-vgpr-stack-size + --rt-lds-stack-size of {}+{} is below the minimum stack of {}",
+            vgpr_stack_size, lds_stack_size, MIN_RT_STACK_SIZE
+        ))
+    } else if let Some(group_size) = group_size {
+        lds_stack_bytes(lds_stack_size, group_size)
+            .filter(|bytes| *bytes <= MAX_RT_LDS_STACK_BYTES)
+            .is_none()
+            .then(|| {
+                format!(
+                    "LDS stack of {} entries for {} threads needs more than {} bytes",
+                    lds_stack_size, group_size, MAX_RT_LDS_STACK_BYTES
+                )
+            })
+    } else {
+        Some(format!(
+            "Workgroup of {}x{} is empty or larger than {} threads",
+            group_width, group_height, MAX_RT_GROUP_SIZE
+        ))
+    }
+}
+
+/// Groupshared memory of the traversal stack, a uint2 per entry. None if it doesn't fit in a u32.
+fn lds_stack_bytes(lds_stack_size: u32, group_size: u32) -> Option<u32> {
+    lds_stack_size.checked_mul(group_size)?.checked_mul(8)
+}
+
+/// The `--rt-*` options under the names the shaders use. Passed to dxc as `-D NAME=value` and put in front of the WGSL
+/// as constants. Err with the message from `rt_kernel_error` if they can't be used.
+fn kernel_constants(options: &Options) -> Result<[(&'static str, u32); 5], String> {
+    if let Some(e) = rt_kernel_error(
+        options.rt_vgpr_stack_size,
+        options.rt_lds_stack_size,
+        options.rt_group_width,
+        options.rt_group_height,
+    ) {
+        return Err(e);
+    }
+    let group_size = options
+        .rt_group_width
+        .checked_mul(options.rt_group_height)
+        .ok_or_else(|| String::from("Workgroup size overflows"))?;
+    Ok([
+        ("RT_VGPR_STACK_SIZE", options.rt_vgpr_stack_size),
+        ("RT_LDS_STACK_SIZE", options.rt_lds_stack_size),
+        ("RT_LDS_STACK_GROUP_SIZE", group_size),
+        ("RT_GROUP_SIZE_X", options.rt_group_width),
+        ("RT_GROUP_SIZE_Y", options.rt_group_height),
+    ])
+}
+
 pub fn start(
     event_loop: &mut EventLoop<()>,
+    file_name: &str,
     options: &Options,
     scene: &Scene,
     bvh_bytes: &[u8],
     instance_bytes: &[u8],
     tri_bytes: &[u8],
     tlas_start: u32,
+    traversal_counts: &mut Option<TraversalCountStats>,
 ) -> f32 {
+    let constants = match kernel_constants(options) {
+        Ok(constants) => constants,
+        Err(e) => {
+            println!("{}", e);
+            return f32::NAN;
+        }
+    };
     let src_dir = PathBuf::from(std::env::current_dir().unwrap()).join("src/rt_gpu");
+    if options.wgsl {
+        return futures::executor::block_on(start_internal(
+            event_loop,
+            file_name,
+            options,
+            scene,
+            KernelShader::Wgsl(wgsl_source(&src_dir, options, &constants)),
+            bvh_bytes,
+            instance_bytes,
+            tri_bytes,
+            tlas_start,
+            traversal_counts,
+        ));
+    }
     let shader_file = if options.tlas {
         "rt_gpu_software_tlas.hlsl"
     } else {
         "rt_gpu_software.hlsl"
     };
     let src_path = src_dir.join(shader_file);
-    let dst_path = src_dir.with_extension("spv");
-    let dst_string = dst_path.to_string_lossy();
 
-    compile_to_spirv(&src_path.to_string_lossy(), &dst_string, "cs_6_1");
+    let mut defines = constants
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<_>>();
+    if options.profile_rt {
+        defines.push(String::from("PROFILE_RT"));
+    }
+    let defines = defines.iter().map(String::as_str).collect::<Vec<_>>();
+    let dst_path = compile_to_spirv(&src_path, "cs_6_1", &defines, &options.shader_cache);
+    let dst_string = dst_path.to_string_lossy();
 
     let slang_spv = load_shader_module(&dst_path);
 
     futures::executor::block_on(start_internal(
         event_loop,
+        file_name,
         options,
         scene,
-        ShaderModuleDescriptorSpirV {
+        KernelShader::SpirV(ShaderModuleDescriptorSpirV {
             label: Some(&dst_string),
             source: make_spirv_raw(&slang_spv),
-        },
+        }),
         bvh_bytes,
         instance_bytes,
         tri_bytes,
         tlas_start,
+        traversal_counts,
     ))
 }
 
+/// WGSL has no includes, so the kernel module is the concatenation of the files rt_gpu_software.wgsl uses.
+fn wgsl_source(src_dir: &Path, options: &Options, constants: &[(&str, u32)]) -> String {
+    let query_file = if options.tlas {
+        "rt_gpu_software_query_tlas.wgsl"
+    } else {
+        "rt_gpu_software_query.wgsl"
+    };
+    let read = |file: &str| {
+        std::fs::read_to_string(src_dir.join(file))
+            .unwrap_or_else(|e| panic!("Failed reading {}: {}", file, e))
+    };
+    let profile = if options.profile_rt {
+        read("rt_gpu_software_profile.wgsl")
+    } else {
+        String::from(WGSL_NO_PROFILE)
+    };
+    let constants = constants
+        .iter()
+        .map(|(name, value)| format!("const {} = {}u;\n", name, value))
+        .collect::<String>();
+    [
+        constants,
+        read("sampling.wgsl"),
+        read(query_file),
+        read("rt_gpu_software.wgsl"),
+        profile,
+    ]
+    .join("\n")
+}
+
 async fn start_internal(
     event_loop: &mut EventLoop<()>,
+    file_name: &str,
     options: &Options,
     scene: &Scene,
-    shader_module: ShaderModuleDescriptorSpirV<'_>,
+    shader: KernelShader<'_>,
     bvh_bytes: &[u8],
     instance_bytes: &[u8],
     tri_bytes: &[u8],
     tlas_start: u32,
+    traversal_counts: &mut Option<TraversalCountStats>,
 ) -> f32 {
     let window = winit::window::WindowBuilder::new()
         .with_title("cwbvh-ray-traced-triangle")
@@ -98,19 +252,46 @@ async fn start_internal(
         .await
         .expect("Failed to find an appropriate adapter");
 
-    let required_features = Features::TIMESTAMP_QUERY
+    let mut required_features = Features::TIMESTAMP_QUERY
         | Features::TIMESTAMP_QUERY_INSIDE_PASSES
         | Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES
-        | Features::SPIRV_SHADER_PASSTHROUGH
         | Features::PUSH_CONSTANTS;
-    // before SPIRV_SHADER_PASSTHROUGH, was getting:
-    // UnsupportedInstruction(Function, AtomicIAdd)
-    // unsupported instruction AtomicIAdd at Function
+    if let KernelShader::SpirV(_) = shader {
+        // before SPIRV_SHADER_PASSTHROUGH, was getting:
+        // UnsupportedInstruction(Function, AtomicIAdd)
+        // unsupported instruction AtomicIAdd at Function
+        required_features |= Features::SPIRV_SHADER_PASSTHROUGH;
+    }
+
+    let adapter_limits = adapter.limits();
+    let group_size = options.rt_group_width * options.rt_group_height;
+    if options.rt_group_width > adapter_limits.max_compute_workgroup_size_x
+        || options.rt_group_height > adapter_limits.max_compute_workgroup_size_y
+        || group_size > adapter_limits.max_compute_invocations_per_workgroup
+    {
+        panic!(
+            "Workgroup of {}x{} is larger than the device supports",
+            options.rt_group_width, options.rt_group_height
+        );
+    }
+    // Both fit in a u32, `start` checked the options with `rt_kernel_error`
+    let lds_bytes = lds_stack_bytes(options.rt_lds_stack_size, group_size).unwrap();
+    if lds_bytes > adapter_limits.max_compute_workgroup_storage_size {
+        panic!(
+            "LDS stack needs {} bytes, the device supports {}",
+            lds_bytes, adapter_limits.max_compute_workgroup_storage_size
+        );
+    }
 
     let mut limits = Limits::default();
-    limits.max_storage_buffer_binding_size = adapter.limits().max_storage_buffer_binding_size;
-    limits.max_buffer_size = adapter.limits().max_buffer_size;
+    limits.max_storage_buffer_binding_size = adapter_limits.max_storage_buffer_binding_size;
+    limits.max_buffer_size = adapter_limits.max_buffer_size;
     limits.max_push_constant_size = 32;
+    limits.max_compute_workgroup_size_x = adapter_limits.max_compute_workgroup_size_x;
+    limits.max_compute_workgroup_size_y = adapter_limits.max_compute_workgroup_size_y;
+    limits.max_compute_invocations_per_workgroup =
+        adapter_limits.max_compute_invocations_per_workgroup;
+    limits.max_compute_workgroup_storage_size = adapter_limits.max_compute_workgroup_storage_size;
 
     let (device, queue) = adapter
         .request_device(&DeviceDescriptor {
@@ -135,10 +316,17 @@ async fn start_internal(
     drop(instance);
     drop(adapter);
 
-    let module = unsafe {
-        device.create_shader_module_passthrough(CreateShaderModuleDescriptorPassthrough::SpirV(
-            shader_module,
-        ))
+    let module = match shader {
+        KernelShader::SpirV(shader_module) => unsafe {
+            device.create_shader_module_passthrough(CreateShaderModuleDescriptorPassthrough::SpirV(
+                shader_module,
+            ))
+        },
+        // Validated by naga, errors panic with the diagnostic
+        KernelShader::Wgsl(source) => device.create_shader_module(ShaderModuleDescriptor {
+            label: Some("rt_gpu_software.wgsl"),
+            source: ShaderSource::Wgsl(source.into()),
+        }),
     };
     let output_texture = device.create_texture(&TextureDescriptor {
         label: Some("output_texture"),
@@ -159,19 +347,29 @@ async fn start_internal(
         view_formats: &[],
     });
 
+    let mut layout_entries = vec![
+        uniform_layout(
+            1,
+            NonZeroU64::new(mem::size_of::<ViewUniform>() as u64).unwrap(),
+        ),
+        rwstorage_texture_layout(2, TextureViewDimension::D2, output_texture.format()),
+        storage_buffer_layout(3, NonZeroU64::new(bvh_bytes.len() as u64).unwrap()),
+        storage_buffer_layout(5, NonZeroU64::new(instance_bytes.len() as u64).unwrap()),
+        storage_buffer_layout(6, NonZeroU64::new(tri_bytes.len() as u64).unwrap()),
+        rw_storage_buffer_layout(7, NonZeroU64::new(4).unwrap()),
+    ];
+    let profile_counts = options
+        .profile_rt
+        .then(|| ProfileCounts::new(&device, options.width * options.height));
+    if let Some(profile_counts) = &profile_counts {
+        layout_entries.push(rw_storage_buffer_layout(
+            8,
+            NonZeroU64::new(profile_counts.buffer.size()).unwrap(),
+        ));
+    }
     let bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
         label: None,
-        entries: &[
-            uniform_layout(
-                1,
-                NonZeroU64::new(mem::size_of::<ViewUniform>() as u64).unwrap(),
-            ),
-            rwstorage_texture_layout(2, TextureViewDimension::D2, output_texture.format()),
-            storage_buffer_layout(3, NonZeroU64::new(bvh_bytes.len() as u64).unwrap()),
-            storage_buffer_layout(5, NonZeroU64::new(instance_bytes.len() as u64).unwrap()),
-            storage_buffer_layout(6, NonZeroU64::new(tri_bytes.len() as u64).unwrap()),
-            rw_storage_buffer_layout(7, NonZeroU64::new(4).unwrap()),
-        ],
+        entries: &layout_entries,
     });
 
     let blas_buffer = init_storage("BLAS Buffer", &device, bvh_bytes);
@@ -192,37 +390,43 @@ async fn start_internal(
 
     let timestamp = Timestamp::new(&device, &queue);
 
+    let output_view = output_texture.create_view(&TextureViewDescriptor::default());
+    let mut bind_group_entries = vec![
+        BindGroupEntry {
+            binding: 1,
+            resource: camera_uniform.as_entire_binding(),
+        },
+        BindGroupEntry {
+            binding: 2,
+            resource: BindingResource::TextureView(&output_view),
+        },
+        BindGroupEntry {
+            binding: 3,
+            resource: blas_buffer.as_entire_binding(),
+        },
+        BindGroupEntry {
+            binding: 5,
+            resource: instance_buffer.as_entire_binding(),
+        },
+        BindGroupEntry {
+            binding: 6,
+            resource: tris_buffer.as_entire_binding(),
+        },
+        BindGroupEntry {
+            binding: 7,
+            resource: task_buffer.as_entire_binding(),
+        },
+    ];
+    if let Some(profile_counts) = &profile_counts {
+        bind_group_entries.push(BindGroupEntry {
+            binding: 8,
+            resource: profile_counts.buffer.as_entire_binding(),
+        });
+    }
     let bind_group = device.create_bind_group(&BindGroupDescriptor {
         label: None,
         layout: &bind_group_layout,
-        entries: &[
-            BindGroupEntry {
-                binding: 1,
-                resource: camera_uniform.as_entire_binding(),
-            },
-            BindGroupEntry {
-                binding: 2,
-                resource: BindingResource::TextureView(
-                    &output_texture.create_view(&TextureViewDescriptor::default()),
-                ),
-            },
-            BindGroupEntry {
-                binding: 3,
-                resource: blas_buffer.as_entire_binding(),
-            },
-            BindGroupEntry {
-                binding: 5,
-                resource: instance_buffer.as_entire_binding(),
-            },
-            BindGroupEntry {
-                binding: 6,
-                resource: tris_buffer.as_entire_binding(),
-            },
-            BindGroupEntry {
-                binding: 7,
-                resource: task_buffer.as_entire_binding(),
-            },
-        ],
+        entries: &bind_group_entries,
     });
 
     let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
@@ -243,6 +447,10 @@ async fn start_internal(
         cache: None,
     });
 
+    // The kernels skip the pixels outside of the image in the last groups
+    let groups_x = options.width.div_ceil(options.rt_group_width);
+    let groups_y = options.height.div_ceil(options.rt_group_height);
+
     let mut sum_ms = 0.0_f64;
     let mut min_ms = f32::MAX;
     let mut frame_count = 0_usize;
@@ -288,14 +496,10 @@ async fn start_internal(
                                 }
                                 if options.benchmark {
                                     // With this extra dispatch, the following timestamp will be much more consistent.
-                                    cpass.dispatch_workgroups(
-                                        options.width / 8,
-                                        options.height / 8,
-                                        1,
-                                    );
+                                    cpass.dispatch_workgroups(groups_x, groups_y, 1);
                                     timestamp.start(&mut cpass);
                                 }
-                                cpass.dispatch_workgroups(options.width / 8, options.height / 8, 1);
+                                cpass.dispatch_workgroups(groups_x, groups_y, 1);
                                 //cpass.dispatch_workgroups(784, 1, 1);
                                 if options.benchmark {
                                     timestamp.end(&mut cpass);
@@ -373,5 +577,11 @@ async fn start_internal(
             })
             .unwrap();
     }
+    if let Some(profile_counts) = &profile_counts {
+        let (primary, ao) = profile_counts.read(&device, &queue);
+        *traversal_counts = Some(report_traversal_counts(
+            file_name, options, "gpu_heat", &primary, &ao,
+        ));
+    }
     min_ms
 }
@@ -0,0 +1,105 @@
+// WGSL version of rt_gpu_software.hlsl and rt_gpu_software_tlas.hlsl, used with --wgsl. Doesn't need dxc or SPIR-V
+// passthrough. WGSL has no includes, so rt_gpu_software.rs puts the constants from the --rt-* options
+// (RT_VGPR_STACK_SIZE, RT_LDS_STACK_SIZE, RT_LDS_STACK_GROUP_SIZE, RT_GROUP_SIZE_X and RT_GROUP_SIZE_Y, see
+// rt_gpu_software.hlsl), sampling.wgsl, rt_gpu_software_query.wgsl (or rt_gpu_software_query_tlas.wgsl), this file and
+// the PROFILE_RT constant (with rt_gpu_software_profile.wgsl when it's true) into one module.
+
+struct PushData {
+    frame_count: u32,
+}
+
+var<push_constant> push_data: PushData;
+
+// AO counts stored with PROFILE_RT if the primary ray missed, see rt_gpu_software_profile.wgsl
+const NO_AO_RAY = 0xffffffffu;
+
+struct ViewUniform {
+    view_inv: mat4x4<f32>,
+    proj_inv: mat4x4<f32>,
+    cam_eye: vec3<f32>,
+    cam_exposure: f32,
+    // Only used with the TLAS
+    tlas_start: u32,
+}
+
+@group(0) @binding(1)
+var<uniform> view: ViewUniform;
+
+@group(0) @binding(2)
+var output_texture: texture_storage_2d<rgba8unorm, read_write>;
+
+@compute @workgroup_size(RT_GROUP_SIZE_X, RT_GROUP_SIZE_Y, 1)
+fn main(@builtin(global_invocation_id) invocation_id: vec3<u32>, @builtin(local_invocation_index) idx_within_group: u32) {
+    g_thread_index_within_group = idx_within_group;
+
+    let target_size = textureDimensions(output_texture);
+
+    let frag_coord = invocation_id.xy;
+    // The last groups can be partly outside of the image
+    if frag_coord.x >= target_size.x || frag_coord.y >= target_size.y {
+        return;
+    }
+    var screen_uv = vec2<f32>(frag_coord) / vec2<f32>(target_size);
+    screen_uv.y = 1.0 - screen_uv.y;
+    let ndc = screen_uv * 2.0 - 1.0;
+    let clip_pos = vec4(ndc, 1.0, 1.0);
+
+    var vs = view.proj_inv * clip_pos;
+    vs /= vs.w;
+
+    var ray: Ray;
+    ray.origin = view.cam_eye;
+    ray.direction = normalize((view.view_inv * vs).xyz - view.cam_eye);
+
+    var hit: RtOutput;
+    hit.t = F32_MAX;
+
+    let did_hit = traverse_bvh(ray, &hit);
+    let counts = vec2(hit.aabb_hit_count, hit.tri_hit_count);
+    var ao_counts = vec2(NO_AO_RAY);
+
+    var col = vec3(1.0 / hit.t);
+
+    if did_hit {
+        let tri = unpack_triangle(get_bvh_triangle(hit.primitive_id));
+
+        var N = hit_normal(hit, normalize(cross(tri.e1, tri.e2)));
+        N = N * sign(dot(-ray.direction, N)); // Double sided
+        col = N;
+
+        var ao_ray: Ray;
+        ao_ray.origin = view.cam_eye + ray.direction * hit.t - ray.direction * 0.0001; // maybe could be lower
+
+        let tangent_to_world = build_orthonormal_basis(N);
+        ao_ray.direction = cosine_sample_hemisphere(vec2(
+            hash_noise(frag_coord.xy, push_data.frame_count),
+            hash_noise(frag_coord.xy, push_data.frame_count + 1024u)
+        ));
+        ao_ray.direction = normalize(tangent_to_world * ao_ray.direction);
+
+        var ao_hit: RtOutput;
+        ao_hit.t = F32_MAX;
+
+        // Actual AO could use a faster anyhit query.
+        // Just using a normal closest query here for simplicity and to create a bit more work for the benchmark.
+        let ao_did_hit = traverse_bvh(ao_ray, &ao_hit);
+        ao_counts = vec2(ao_hit.aabb_hit_count, ao_hit.tri_hit_count);
+
+        if ao_did_hit {
+            let ao = ao_hit.t / (1.0 + ao_hit.t);
+            col = vec3(ao);
+        } else {
+            col = vec3(1.0);
+        }
+    }
+    col = pow(col, vec3(2.2));
+
+    if PROFILE_RT {
+        store_profile_counts(frag_coord.y * target_size.x + frag_coord.x, vec4(counts, ao_counts));
+
+        // Primary and AO ray together
+        let aabb_count = counts.x + select(0u, ao_counts.x, did_hit);
+        col = temperature(f32(aabb_count) * 0.002); // lt blue is 100, green is 200, orange is 300, red is 400
+    }
+    textureStore(output_texture, frag_coord, vec4(col, 1.0));
+}
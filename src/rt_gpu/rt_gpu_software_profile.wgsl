@@ -0,0 +1,13 @@
+// Added to rt_gpu_software.wgsl with --profile-rt. Without it PROFILE_RT is false and store_profile_counts is empty, so
+// binding 8 isn't used.
+
+const PROFILE_RT = true;
+
+// Per pixel (aabb_hit_count, tri_hit_count) of the primary ray followed by the same for the AO ray, read back with
+// --profile-rt. The AO counts are NO_AO_RAY if the primary ray missed.
+@group(0) @binding(8)
+var<storage, read_write> profile_counts: array<vec4<u32>>;
+
+fn store_profile_counts(index: u32, counts: vec4<u32>) {
+    profile_counts[index] = counts;
+}
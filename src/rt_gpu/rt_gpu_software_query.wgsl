@@ -0,0 +1,326 @@
+// WGSL version of rt_gpu_software_query.hlsl. Uses the RT_* stack constants and PROFILE_RT, see rt_gpu_software.wgsl.
+
+struct Ray {
+    direction: vec3<f32>,
+    origin: vec3<f32>,
+}
+
+// Used for LDS stack indexing. Must be set at the start of the kernel.
+var<private> g_thread_index_within_group: u32;
+
+struct PackedBlBvhNode {
+    data: array<vec4<u32>, 5>,
+}
+
+struct PackedTriangle {
+    v: array<f32, 3>,
+    e: array<u32, 3>,
+}
+
+@group(0) @binding(3)
+var<storage, read> rt_bl_bvh: array<PackedBlBvhNode>;
+fn get_bl_bvh_node(idx: u32) -> PackedBlBvhNode {
+    return rt_bl_bvh[idx];
+}
+
+@group(0) @binding(6)
+var<storage, read> rt_triangles: array<PackedTriangle>;
+fn get_bvh_triangle(idx: u32) -> PackedTriangle {
+    return rt_triangles[idx];
+}
+
+struct Triangle {
+    v: vec3<f32>,
+    e1: vec3<f32>,
+    e2: vec3<f32>,
+}
+
+fn unpack_triangle(tri: PackedTriangle) -> Triangle {
+    let ex = unpack2x16float(tri.e[0]);
+    let ey = unpack2x16float(tri.e[1]);
+    let ez = unpack2x16float(tri.e[2]);
+    return Triangle(vec3(tri.v[0], tri.v[1], tri.v[2]), vec3(ex.y, ey.y, ez.y), vec3(ex.x, ey.x, ez.x));
+}
+
+fn min4(a: f32, b: f32, c: f32, d: f32) -> f32 {
+    return min(min(min(a, b), c), d);
+}
+
+fn max4(a: f32, b: f32, c: f32, d: f32) -> f32 {
+    return max(max(max(a, b), c), d);
+}
+
+// Based on Fast Minimum Storage Ray Triangle Intersection by T. Möller and B. Trumbore
+// https://madmann91.github.io/2021/04/29/an-introduction-to-bvhs.html
+fn intersect_ray_tri(ray: Ray, tri: Triangle, t: ptr<function, f32>, barycentric: ptr<function, vec2<f32>>) -> bool {
+    let e1 = -tri.e1;
+    let e2 = tri.e2;
+    let ng = cross(e1, e2);
+
+    let c = tri.v - ray.origin;
+    let r = cross(ray.direction, c);
+    let inv_det = 1.0 / dot(ng, ray.direction);
+
+    let u = dot(r, e2) * inv_det;
+    let v = dot(r, e1) * inv_det;
+    let w = 1.0 - u - v;
+
+    // Note: differs in that if v == -0.0, for example will cause valid to be false
+    let hit = bitcast<u32>(u) | bitcast<u32>(v) | bitcast<u32>(w);
+    if inv_det != 0.0 && (hit & 0x80000000u) == 0u {
+        let tt = dot(ng, c) * inv_det;
+        if tt >= 0.0 && tt <= *t {
+            *t = tt;
+            *barycentric = vec2(u, v);
+            return true;
+        }
+    }
+
+    return false;
+}
+
+struct RtOutput {
+    primitive_id: u32,
+    t: f32,
+    // Only counted with PROFILE_RT
+    tri_hit_count: u32,
+    aabb_hit_count: u32,
+}
+
+// Never empty, WGSL doesn't have zero sized arrays. Unused when RT_LDS_STACK_SIZE is 0.
+var<workgroup> rt_lds_stack: array<vec2<u32>, max(RT_LDS_STACK_SIZE, 1u) * RT_LDS_STACK_GROUP_SIZE>;
+
+fn lds_stack_index(i: u32) -> u32 {
+    return g_thread_index_within_group + i * RT_LDS_STACK_GROUP_SIZE;
+}
+
+struct BvhStack {
+    items: array<vec2<u32>, max(RT_VGPR_STACK_SIZE, 1u)>,
+    size: u32,
+}
+
+fn stack_push(stack: ptr<function, BvhStack>, item: vec2<u32>) {
+    if RT_LDS_STACK_SIZE > 0u && RT_VGPR_STACK_SIZE > 0u {
+        if (*stack).size >= RT_VGPR_STACK_SIZE {
+            rt_lds_stack[lds_stack_index((*stack).size - RT_VGPR_STACK_SIZE)] = item;
+        } else {
+            (*stack).items[(*stack).size] = item;
+        }
+    } else if RT_VGPR_STACK_SIZE > 0u {
+        (*stack).items[(*stack).size] = item;
+    } else {
+        rt_lds_stack[lds_stack_index((*stack).size)] = item;
+    }
+    (*stack).size++;
+}
+
+fn stack_pop(stack: ptr<function, BvhStack>) -> vec2<u32> {
+    (*stack).size--;
+    if RT_LDS_STACK_SIZE > 0u && RT_VGPR_STACK_SIZE > 0u {
+        if (*stack).size >= RT_VGPR_STACK_SIZE {
+            return rt_lds_stack[lds_stack_index((*stack).size - RT_VGPR_STACK_SIZE)];
+        }
+        return (*stack).items[(*stack).size];
+    } else if RT_VGPR_STACK_SIZE > 0u {
+        return (*stack).items[(*stack).size];
+    } else {
+        return rt_lds_stack[lds_stack_index((*stack).size)];
+    }
+}
+
+fn extract_byte(x: u32, b: u32) -> u32 {
+    return (x >> (b * 8u)) & 0xffu;
+}
+
+// Based on <https://github.com/jan-van-bergen/GPU-Raytracer/blob/6559ae2241c8fdea0ddaec959fe1a47ec9b3ab0d/Src/CUDA/Raytracing/BVH8.h#L29>
+fn cwbvh_node_intersect(ray: Ray, oct_inv4: u32, max_distance: f32, node: PackedBlBvhNode) -> u32 {
+    let p = bitcast<vec3<f32>>(node.data[0].xyz);
+
+    let e_imask = node.data[0].w;
+    let e_x = extract_byte(e_imask, 0u);
+    let e_y = extract_byte(e_imask, 1u);
+    let e_z = extract_byte(e_imask, 2u);
+
+    // See rt_gpu_software_query.hlsl for why the inverse direction isn't precalculated.
+    let adjusted_ray_dir_inv = vec3(
+        bitcast<f32>(e_x << 23u),
+        bitcast<f32>(e_y << 23u),
+        bitcast<f32>(e_z << 23u)
+    ) / ray.direction;
+    let adjusted_ray_origin = (p - ray.origin) / ray.direction;
+
+    var hit_mask = 0u;
+
+    for (var i = 0; i < 2; i++) {
+        let meta4 = select(node.data[1].w, node.data[1].z, i == 0);
+
+        let is_inner4 = (meta4 & (meta4 << 1u)) & 0x10101010u;
+        let inner_mask4 = (is_inner4 >> 4u) * 0xffu;
+        let bit_index4 = (meta4 ^ (oct_inv4 & inner_mask4)) & 0x1f1f1f1fu;
+        let child_bits4 = (meta4 >> 5u) & 0x07070707u;
+
+        // Select near and far planes based on ray octant
+        let q_lo_x = select(node.data[2].y, node.data[2].x, i == 0);
+        let q_hi_x = select(node.data[2].w, node.data[2].z, i == 0);
+
+        let q_lo_y = select(node.data[3].y, node.data[3].x, i == 0);
+        let q_hi_y = select(node.data[3].w, node.data[3].z, i == 0);
+
+        let q_lo_z = select(node.data[4].y, node.data[4].x, i == 0);
+        let q_hi_z = select(node.data[4].w, node.data[4].z, i == 0);
+
+        let x_min = select(q_lo_x, q_hi_x, ray.direction.x < 0.0);
+        let x_max = select(q_hi_x, q_lo_x, ray.direction.x < 0.0);
+
+        let y_min = select(q_lo_y, q_hi_y, ray.direction.y < 0.0);
+        let y_max = select(q_hi_y, q_lo_y, ray.direction.y < 0.0);
+
+        let z_min = select(q_lo_z, q_hi_z, ray.direction.z < 0.0);
+        let z_max = select(q_hi_z, q_lo_z, ray.direction.z < 0.0);
+
+        let EPSILON = 0.0001;
+
+        for (var j = 0u; j < 4u; j++) {
+            // Extract j-th byte
+            var tmin3 = vec3(f32(extract_byte(x_min, j)), f32(extract_byte(y_min, j)), f32(extract_byte(z_min, j)));
+            var tmax3 = vec3(f32(extract_byte(x_max, j)), f32(extract_byte(y_max, j)), f32(extract_byte(z_max, j)));
+
+            // Account for grid origin and scale
+            tmin3 = tmin3 * adjusted_ray_dir_inv + adjusted_ray_origin;
+            tmax3 = tmax3 * adjusted_ray_dir_inv + adjusted_ray_origin;
+
+            let tmin = max4(tmin3.x, tmin3.y, tmin3.z, EPSILON);
+            let tmax = min4(tmax3.x, tmax3.y, tmax3.z, max_distance);
+
+            let intersected = tmin <= tmax;
+            if intersected {
+                let child_bits = extract_byte(child_bits4, j);
+                let bit_index = extract_byte(bit_index4, j);
+
+                hit_mask |= child_bits << bit_index;
+            }
+        }
+    }
+
+    return hit_mask;
+}
+
+struct CwBvhRayHit {
+    t: f32,
+    u: f32,
+    v: f32,
+    mesh_id: i32,
+    triangle_id: i32,
+}
+
+fn ray_get_octant_inv4(dir: vec3<f32>) -> u32 {
+    return select(0x04040404u, 0u, dir.x < 0.0) |
+           select(0x02020202u, 0u, dir.y < 0.0) |
+           select(0x01010101u, 0u, dir.z < 0.0);
+}
+
+// The normal of a hit in world space.
+fn hit_normal(hit: RtOutput, n: vec3<f32>) -> vec3<f32> {
+    return n;
+}
+
+fn traverse_bvh(in_ray: Ray, hit: ptr<function, RtOutput>) -> bool {
+    // The ray-aabb test in cwbvh_node_intersect divides by ray.direction.
+    // Needed to avoid NaN/INF which results in traversing nodes unnecessarily when ray.direction.y == 0.0, etc...
+    var ray = in_ray;
+    ray.direction = select(ray.direction, vec3(F32_EPSILON), ray.direction == vec3(0.0));
+
+    var stack: BvhStack;
+
+    var ray_hit: CwBvhRayHit;
+
+    let oct_inv4 = ray_get_octant_inv4(ray.direction);
+
+    var current_group = vec2(0u, 0x80000000u);
+
+    ray_hit.t = F32_MAX;
+    ray_hit.triangle_id = -1;
+
+    loop {
+        var triangle_group: vec2<u32>;
+
+        // If there's remaining nodes in the current group to check
+        if (current_group.y & 0xff000000u) != 0u {
+            let hits_imask = current_group.y;
+
+            let child_index_offset = firstLeadingBit(hits_imask);
+            let child_index_base = current_group.x;
+
+            // Remove node from current_group
+            current_group.y &= ~(1u << child_index_offset);
+
+            // If the node group is not yet empty, push it on the stack
+            if (current_group.y & 0xff000000u) != 0u {
+                stack_push(&stack, current_group);
+            }
+
+            let slot_index = (child_index_offset - 24u) ^ (oct_inv4 & 0xffu);
+            let relative_index = countOneBits(hits_imask & ~(0xffffffffu << slot_index));
+
+            let child_node_index = child_index_base + relative_index;
+
+            let node = get_bl_bvh_node(child_node_index);
+
+            if PROFILE_RT {
+                (*hit).aabb_hit_count += 8u;
+            }
+            let hitmask = cwbvh_node_intersect(ray, oct_inv4, ray_hit.t, node);
+            let imask = extract_byte(node.data[0].w, 3u);
+
+            current_group.x = node.data[1].x; // Child base offset
+            triangle_group.x = node.data[1].y; // Triangle base offset
+
+            current_group.y = (hitmask & 0xff000000u) | imask;
+            triangle_group.y = (hitmask & 0x00ffffffu);
+        } else {
+            // There's no nodes left in the current group
+            triangle_group = current_group; // For triangle postponing (not yet implemented)
+            current_group = vec2(0u, 0u);
+        }
+
+        // While the triangle group is not empty
+        while triangle_group.y != 0u {
+            let local_triangle_index = firstLeadingBit(triangle_group.y);
+
+            // Remove triangle from current_group
+            triangle_group.y &= ~(1u << local_triangle_index);
+
+            let global_triangle_index = triangle_group.x + local_triangle_index;
+            let tri = unpack_triangle(get_bvh_triangle(global_triangle_index));
+
+            var t = ray_hit.t;
+            var barycentric: vec2<f32>;
+            if PROFILE_RT {
+                (*hit).tri_hit_count += 1u;
+            }
+            if intersect_ray_tri(ray, tri, &t, &barycentric) {
+                ray_hit.t = t;
+                ray_hit.triangle_id = i32(global_triangle_index);
+            }
+        }
+
+        // If there's no remaining nodes in the current group to check, pop it off the stack.
+        if (current_group.y & 0xff000000u) == 0u {
+            // If the stack is empty, end traversal.
+            if stack.size == 0u {
+                current_group.y = 0u;
+                break;
+            }
+
+            current_group = stack_pop(&stack);
+        }
+    }
+
+    if ray_hit.triangle_id != -1 && ray_hit.t < (*hit).t {
+        (*hit).t = ray_hit.t;
+        (*hit).primitive_id = u32(ray_hit.triangle_id);
+        return true;
+    }
+
+    return false;
+}
@@ -58,8 +58,27 @@ PackedBlBvhNode get_bvh_node(uint idx)
     return rt_bvh[idx];
 }
 
+// SoftwareInstance in rt_gpu/mod.rs. Transforms are row major 3x4 matrices.
+struct Instance
+{
+    uint blas_offset;
+    uint pad0, pad1, pad2;
+    float4 world_to_object[3];
+    float4 object_to_world[3];
+};
+
 [[vk::binding(INSTANCES_BINDING, 0)]]
-StructuredBuffer<uint> blas_offsets;
+StructuredBuffer<Instance> instances;
+
+float3 transform_point(float4 rows[3], float3 p)
+{
+    return float3(dot(rows[0].xyz, p) + rows[0].w, dot(rows[1].xyz, p) + rows[1].w, dot(rows[2].xyz, p) + rows[2].w);
+}
+
+float3 transform_direction(float4 rows[3], float3 d)
+{
+    return float3(dot(rows[0].xyz, d), dot(rows[1].xyz, d), dot(rows[2].xyz, d));
+}
 
 [[vk::binding(TRIS_BINDING, 0)]]
 StructuredBuffer<PackedTriangle> rt_triangles;
@@ -134,6 +153,7 @@ bool intersect_ray_tri(Ray ray, Triangle tri, inout float t, inout float2 baryce
 struct RtOutput
 {
     uint primitive_id;
+    uint instance_id;
     float t;
 #ifdef PROFILE_RT
     uint tri_hit_count;
@@ -312,6 +332,7 @@ struct CwBvhRayHit
 
     int mesh_id;
     int triangle_id;
+    uint instance_id;
 };
 
 uint ray_get_octant_inv4(float3 dir)
@@ -345,7 +366,11 @@ bool traverse_bvh(Ray ray, inout RtOutput hit)
 
     CwBvhRayHit ray_hit = (CwBvhRayHit)0;
 
-    const uint oct_inv4 = ray_get_octant_inv4(ray.direction);
+    // The ray is moved into object space while traversing a BLAS, the world space ray is restored after.
+    const Ray world_ray = ray;
+    const uint world_oct_inv4 = ray_get_octant_inv4(ray.direction);
+    uint oct_inv4 = world_oct_inv4;
+    uint current_instance = 0;
 
     current_group = uint2(0, 0x80000000);
 
@@ -406,7 +431,7 @@ bool traverse_bvh(Ray ray, inout RtOutput hit)
 
             // https://github.com/jan-van-bergen/GPU-Raytracer/issues/24#issuecomment-1042746566
             // If tlas_stack_size is INVALID we are in the TLAS. This means use the triangle index as a mesh index.
-            // (TODO: The ray is transform according to the mesh transform and) traversal is continued at the root of the Mesh's BLAS.
+            // The ray is transformed according to the instance transform and traversal is continued at the root of the Mesh's BLAS.
             if (tlas_stack_size == INVALID)
             {
                 uint local_triangle_index = firstbithigh(triangle_group.y);
@@ -414,7 +439,7 @@ bool traverse_bvh(Ray ray, inout RtOutput hit)
                 // Remove triangle from current_group
                 triangle_group.y &= ~(1u << local_triangle_index);
 
-                // Mesh id or entitiy id. If entitiy, it would be necessary to look up mesh id from entity.
+                // Instance id, in TLAS primitive order.
                 uint global_triangle_index = triangle_group.x + local_triangle_index;
 
                 if (triangle_group.y != 0)
@@ -430,16 +455,21 @@ bool traverse_bvh(Ray ray, inout RtOutput hit)
                 // The value of tlas_stack_size is now set to the current size of the traversal stack.
                 tlas_stack_size = stack.size;
 
-                // TODO transform ray according to the mesh transform
                 // https://github.com/jan-van-bergen/GPU-Raytracer/blob/6559ae2241c8fdea0ddaec959fe1a47ec9b3ab0d/Src/CUDA/Raytracing/BVH8.h#L222
+                // The transforms are rigid, so t is the same in object and world space.
+                Instance instance = instances[global_triangle_index];
+                current_instance = global_triangle_index;
+                ray.origin = transform_point(instance.world_to_object, world_ray.origin);
+                ray.direction = transform_direction(instance.world_to_object, world_ray.direction);
+                ray.direction = select(ray.direction == 0.0, F32_EPSILON.xxx, ray.direction);
+                oct_inv4 = ray_get_octant_inv4(ray.direction);
 
                 // For triangles, we remap the tris to match the cwbvh indices layout. But for tlas
                 // it would not be typically reasonable to reorder the blas and mesh buffers. So we
-                // need to get the offset into the blas buffer using blas_offsets.
-                current_bvh_offset = blas_offsets[global_triangle_index];
+                // need to get the offset into the blas buffer from the instance.
+                current_bvh_offset = instance.blas_offset;
 
                 // since we assign current_bvh_offset above the index is just the first node at 0.
-                // Seems like this should be better given the blas_offsets mapping.
                 current_group = uint2(0, 0x80000000);
 
                 break;
@@ -462,6 +492,7 @@ bool traverse_bvh(Ray ray, inout RtOutput hit)
                 if (intersect_ray_tri(ray, tri, ray_hit.t, barycentric))
                 {
                     ray_hit.triangle_id = global_triangle_index;
+                    ray_hit.instance_id = current_instance;
                 }
             }
         }
@@ -481,8 +512,10 @@ bool traverse_bvh(Ray ray, inout RtOutput hit)
             {
                 tlas_stack_size = INVALID;
                 current_bvh_offset = tlas_start;
-                // TODO Reset Ray to untransformed version
+                // Reset Ray to untransformed version
                 // https://github.com/jan-van-bergen/GPU-Raytracer/blob/6559ae2241c8fdea0ddaec959fe1a47ec9b3ab0d/Src/CUDA/Raytracing/BVH8.h#L262
+                ray = world_ray;
+                oct_inv4 = world_oct_inv4;
             }
 
             current_group = stack.pop();
@@ -493,6 +526,7 @@ bool traverse_bvh(Ray ray, inout RtOutput hit)
     {
         hit.t = ray_hit.t;
         hit.primitive_id = ray_hit.triangle_id;
+        hit.instance_id = ray_hit.instance_id;
         return true;
     }
 
@@ -0,0 +1,77 @@
+// The parts of sampling.hlsl used by rt_gpu_software.wgsl
+
+const M_TAU = 6.28318530717958647692528676655900577;
+const F32_MAX = 3.402823466E+38;
+const F32_EPSILON = 1.1920929E-7;
+
+fn uhash(a: u32, b: u32) -> u32 {
+    var x = ((a * 1597334673u) ^ (b * 3812015801u));
+    // from https://nullprogram.com/blog/2018/07/31/
+    x = x ^ (x >> 16u);
+    x *= 0x7feb352du;
+    x = x ^ (x >> 15u);
+    x *= 0x846ca68bu;
+    x = x ^ (x >> 16u);
+    return x;
+}
+
+fn unormf(n: u32) -> f32 {
+    return f32(n) * (1.0 / f32(0xffffffffu));
+}
+
+fn hash_noise(ufrag_coord: vec2<u32>, frame: u32) -> f32 {
+    let urnd = uhash(ufrag_coord.x, (ufrag_coord.y << 11u) + frame);
+    return unormf(urnd);
+}
+
+fn cosine_sample_hemisphere(urand: vec2<f32>) -> vec3<f32> {
+    let r = sqrt(urand.x);
+    let theta = urand.y * M_TAU;
+
+    let x = r * cos(theta);
+    let y = r * sin(theta);
+
+    return vec3(x, y, sqrt(max(0.0, 1.0 - urand.x)));
+}
+
+// http://jcgt.org/published/0006/01/01/
+fn build_orthonormal_basis(n: vec3<f32>) -> mat3x3<f32> {
+    let sgn = select(-1.0, 1.0, n.z >= 0.0);
+    let a = -1.0 / (sgn + n.z);
+    let b = n.x * n.y * a;
+    let b1 = vec3(1.0 + sgn * n.x * n.x * a, sgn * b, -sgn * n.x);
+    let b2 = vec3(b, sgn + n.y * n.y * a, -n.y);
+    // b1, b2 and n are the columns, like the float3x3 in sampling.hlsl
+    return mat3x3(b1, b2, n);
+}
+
+// https://developer.nvidia.com/blog/profiling-dxr-shaders-with-timer-instrumentation/
+fn temperature(t: f32) -> vec3<f32> {
+    var c = array<vec3<f32>, 10>(
+        vec3(0.0 / 255.0, 2.0 / 255.0, 91.0 / 255.0),
+        vec3(0.0 / 255.0, 108.0 / 255.0, 251.0 / 255.0),
+        vec3(0.0 / 255.0, 221.0 / 255.0, 221.0 / 255.0),
+        vec3(51.0 / 255.0, 221.0 / 255.0, 0.0 / 255.0),
+        vec3(255.0 / 255.0, 252.0 / 255.0, 0.0 / 255.0),
+        vec3(255.0 / 255.0, 180.0 / 255.0, 0.0 / 255.0),
+        vec3(255.0 / 255.0, 104.0 / 255.0, 0.0 / 255.0),
+        vec3(226.0 / 255.0, 22.0 / 255.0, 0.0 / 255.0),
+        vec3(191.0 / 255.0, 0.0 / 255.0, 83.0 / 255.0),
+        vec3(145.0 / 255.0, 0.0 / 255.0, 65.0 / 255.0)
+    );
+
+    let s = t * 10.0;
+
+    let cur = select(9, i32(s), i32(s) <= 9);
+    let prv = select(0, cur - 1, cur >= 1);
+    let nxt = select(9, cur + 1, cur < 9);
+
+    let blur = 0.8;
+
+    let wc = smoothstep(f32(cur) - blur, f32(cur) + blur, s) * (1.0 - smoothstep(f32(cur + 1) - blur, f32(cur + 1) + blur, s));
+    let wp = 1.0 - smoothstep(f32(cur) - blur, f32(cur) + blur, s);
+    let wn = smoothstep(f32(cur + 1) - blur, f32(cur + 1) + blur, s);
+
+    let r = wc * c[cur] + wp * c[prv] + wn * c[nxt];
+    return clamp(r, vec3(0.0), vec3(1.0));
+}
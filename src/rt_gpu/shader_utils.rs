@@ -1,4 +1,15 @@
-use std::{io::Read, path::Path, process::Command};
+use std::{
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::bvh_cache::Fnv1a;
+
+/// Bump when the dxc arguments change so old cache entries are ignored. dxc itself isn't part of the key, clear
+/// `--shader-cache` after updating it.
+const SHADER_CACHE_VERSION: u32 = 1;
 
 pub fn load_shader_module(path: &Path) -> Vec<u8> {
     let mut f = std::fs::File::open(&path).expect("no file found");
@@ -8,14 +19,82 @@ pub fn load_shader_module(path: &Path) -> Vec<u8> {
     buffer
 }
 
-pub fn compile_to_spirv(src_path: &str, dst_string: &str, profile: &str) {
-    let out = Command::new("dxc")
+/// Compiles the HLSL at `src_path` to SPIR-V with dxc and returns the path of the SPIR-V in `cache_dir`. The file is
+/// named after the source and a hash of the source, everything it `#include`s, the profile and the `defines` (passed
+/// to dxc as `-D <define>`), so each variant has its own file and is only compiled again when one of those changes.
+/// Panics with dxc's output if the compile fails.
+pub fn compile_to_spirv(
+    src_path: &Path,
+    profile: &str,
+    defines: &[&str],
+    cache_dir: &str,
+) -> PathBuf {
+    let mut hash = Fnv1a::new();
+    hash.write(&SHADER_CACHE_VERSION.to_le_bytes());
+    hash.write(profile.as_bytes());
+    for define in defines {
+        hash.write(&[0]);
+        hash.write(define.as_bytes());
+    }
+    hash_sources(src_path, &mut hash, &mut Vec::new());
+
+    let stem = src_path.file_stem().unwrap().to_string_lossy();
+    let dst_path = Path::new(cache_dir).join(format!("{}_{:016x}.spv", stem, hash.finish()));
+    if dst_path.exists() {
+        return dst_path;
+    }
+
+    fs::create_dir_all(cache_dir)
+        .unwrap_or_else(|e| panic!("Failed creating shader cache {}: {}", cache_dir, e));
+    // dxc writes to a temporary file first so a failed or interrupted compile never leaves a cache entry behind.
+    let tmp_path = dst_path.with_extension("tmp");
+    let mut command = Command::new("dxc");
+    command
         .arg(src_path)
-        .args(["-O3", "-T", profile, "-spirv", "-Fo", dst_string])
-        .output()
-        .expect("failed to execute process");
+        .args(["-O3", "-T", profile, "-spirv", "-Fo"])
+        .arg(&tmp_path)
+        .args(defines.iter().flat_map(|define| ["-D", define]));
+    let out = command.output().unwrap_or_else(|e| {
+        panic!(
+            "Failed to run dxc, it needs to be in path (the GPU software path can use --wgsl instead): {}",
+            e
+        )
+    });
 
+    if !out.status.success() {
+        let _ = fs::remove_file(&tmp_path);
+        panic!(
+            "dxc failed ({}) compiling {}\n{:?}\n{}{}",
+            out.status,
+            src_path.display(),
+            command,
+            String::from_utf8_lossy(&out.stdout),
+            String::from_utf8_lossy(&out.stderr)
+        );
+    }
     if out.stderr.len() > 1 {
         println!("dxc stderr: {}", String::from_utf8_lossy(&out.stderr));
     }
+    fs::rename(&tmp_path, &dst_path)
+        .unwrap_or_else(|e| panic!("Failed writing {}: {}", dst_path.display(), e));
+    dst_path
+}
+
+/// Hashes the file and, recursively, the files of its `#include "..."` lines (relative to the including file).
+fn hash_sources(path: &Path, hash: &mut Fnv1a, visited: &mut Vec<PathBuf>) {
+    if visited.iter().any(|p| p == path) {
+        return;
+    }
+    visited.push(path.to_path_buf());
+    let src = fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("Failed reading shader {}: {}", path.display(), e));
+    hash.write(path.file_name().unwrap().as_encoded_bytes());
+    hash.write(src.as_bytes());
+    for line in src.lines() {
+        let Some(include) = line.trim().strip_prefix("#include") else {
+            continue;
+        };
+        let include = include.trim().trim_matches('"');
+        hash_sources(&path.parent().unwrap().join(include), hash, visited);
+    }
 }
@@ -0,0 +1,456 @@
+use std::time::{Duration, Instant};
+
+use obvhs::{
+    aabb::Aabb,
+    bvh2::{node::Bvh2Node, Bvh2},
+    cwbvh::{bvh2_to_cwbvh::Bvh2Converter, CwBvh},
+    triangle::Triangle,
+};
+
+use crate::{
+    metrics::{clip_triangle, SahCosts},
+    Options,
+};
+
+/// Spatial splits are only tried when the children of the best object split overlap by more than this, relative to
+/// the root area. The alpha from "Spatial Splits in Bounding Volume Hierarchies" (Stich et al. 2009).
+const SBVH_ALPHA: f32 = 1e-5;
+const SPATIAL_BINS: usize = 32;
+/// Nodes with more references than this build their children in parallel.
+const PARALLEL_THRESHOLD: usize = 4096;
+
+#[derive(Clone, Copy, Debug)]
+pub struct SweepSahParams {
+    pub max_prims_per_leaf: u32,
+    /// Also consider spatial splits (SBVH). Triangles can then be referenced by multiple leaves.
+    pub spatial_splits: bool,
+    pub costs: SahCosts,
+}
+
+impl SweepSahParams {
+    pub fn from_options(options: &Options) -> Self {
+        SweepSahParams {
+            max_prims_per_leaf: options.max_prims_per_leaf,
+            spatial_splits: options.build.starts_with("sbvh"),
+            costs: SahCosts::from_options(options),
+        }
+    }
+}
+
+/// Reference to a triangle. With spatial splits the bounds can be a chopped part of the triangle bounds.
+#[derive(Clone, Copy)]
+struct Reference {
+    aabb: Aabb,
+    index: u32,
+}
+
+enum BuildNode {
+    Leaf {
+        aabb: Aabb,
+        refs: Vec<u32>,
+    },
+    Inner {
+        aabb: Aabb,
+        children: Box<[BuildNode; 2]>,
+    },
+}
+
+struct Split {
+    cost: f32,
+    axis: usize,
+    kind: SplitKind,
+}
+
+enum SplitKind {
+    /// References sorted by centroid along the axis, the left child gets the first `index`.
+    Object {
+        index: usize,
+    },
+    Spatial {
+        position: f32,
+    },
+}
+
+/// Slow reference builder that evaluates every possible object split along each axis at every node (full sweep SAH),
+/// and optionally binned spatial splits. Meant as a near optimal quality baseline to compare the other builders
+/// against, not for fast builds.
+pub fn build_sweep_sah_bvh2(
+    tris: &[Triangle],
+    params: SweepSahParams,
+    core_build_time: &mut Duration,
+) -> Bvh2 {
+    let aabbs = tris.iter().map(|tri| tri.aabb()).collect::<Vec<_>>();
+    build_bvh2(tris, &aabbs, params, core_build_time)
+}
+
+/// Builds with `build_sweep_sah_bvh2` and converts to a CwBvh the same way as `embree_bvh2_cwbvh`.
+pub fn build_sweep_sah_cwbvh(
+    tris: &[Triangle],
+    options: &Options,
+    core_build_time: &mut Duration,
+) -> CwBvh {
+    let params = SweepSahParams {
+        // The converter collapses leaves itself and wants one primitive per leaf
+        max_prims_per_leaf: 1,
+        ..SweepSahParams::from_options(options)
+    };
+    let bvh2 = build_sweep_sah_bvh2(tris, params, core_build_time);
+    convert_to_cwbvh(&bvh2, options, core_build_time)
+}
+
+/// TLAS for the sweep_sah and sbvh builders, over the bounds of the instances. Only object splits are used, spatial
+/// splits need the triangles.
+pub fn build_sweep_sah_cwbvh_from_aabbs(
+    aabbs: &[Aabb],
+    options: &Options,
+    core_build_time: &mut Duration,
+) -> CwBvh {
+    let params = SweepSahParams {
+        max_prims_per_leaf: 1,
+        spatial_splits: false,
+        ..SweepSahParams::from_options(options)
+    };
+    let bvh2 = build_bvh2(&[], aabbs, params, core_build_time);
+    convert_to_cwbvh(&bvh2, options, core_build_time)
+}
+
+/// `tris` is only used for spatial splits and can be empty without them.
+fn build_bvh2(
+    tris: &[Triangle],
+    aabbs: &[Aabb],
+    params: SweepSahParams,
+    core_build_time: &mut Duration,
+) -> Bvh2 {
+    if aabbs.is_empty() {
+        return Bvh2::default();
+    }
+    let start_time = Instant::now();
+    let refs = aabbs
+        .iter()
+        .enumerate()
+        .map(|(i, aabb)| Reference {
+            aabb: *aabb,
+            index: i as u32,
+        })
+        .collect::<Vec<_>>();
+    let aabb = union_refs(&refs);
+    let builder = Builder {
+        tris,
+        params,
+        root_area: aabb.half_area(),
+    };
+    let root = builder.build(refs, aabb);
+
+    let mut bvh = Bvh2 {
+        nodes: vec![Bvh2Node::default()],
+        primitive_indices: Vec::with_capacity(aabbs.len()),
+        uses_spatial_splits: params.spatial_splits,
+        ..Default::default()
+    };
+    flatten(&mut bvh, &root, 0);
+    *core_build_time += start_time.elapsed();
+    bvh
+}
+
+fn convert_to_cwbvh(bvh2: &Bvh2, options: &Options, core_build_time: &mut Duration) -> CwBvh {
+    if bvh2.nodes.is_empty() {
+        return CwBvh::default();
+    }
+
+    let start_time = Instant::now();
+    let mut converter = Bvh2Converter::new(bvh2, true, false);
+    converter.calculate_cost(options.max_prims_per_leaf);
+    converter.convert_to_cwbvh();
+    *core_build_time += start_time.elapsed();
+
+    CwBvh {
+        nodes: converter.nodes,
+        primitive_indices: converter.primitive_indices,
+        total_aabb: bvh2.nodes[0].aabb,
+        exact_node_aabbs: None,
+        uses_spatial_splits: bvh2.uses_spatial_splits,
+    }
+}
+
+/// Writes `node` to `slot`, with the children of inner nodes next to each other as obvhs expects.
+fn flatten(bvh: &mut Bvh2, node: &BuildNode, slot: usize) {
+    match node {
+        BuildNode::Leaf { aabb, refs } => {
+            bvh.nodes[slot] =
+                Bvh2Node::new(*aabb, refs.len() as u32, bvh.primitive_indices.len() as u32);
+            bvh.primitive_indices.extend_from_slice(refs);
+        }
+        BuildNode::Inner { aabb, children } => {
+            let first = bvh.nodes.len();
+            bvh.nodes.push(Bvh2Node::default());
+            bvh.nodes.push(Bvh2Node::default());
+            bvh.nodes[slot] = Bvh2Node::new(*aabb, 0, first as u32);
+            flatten(bvh, &children[0], first);
+            flatten(bvh, &children[1], first + 1);
+        }
+    }
+}
+
+fn union_refs(refs: &[Reference]) -> Aabb {
+    refs.iter()
+        .fold(Aabb::INVALID, |aabb, r| aabb.union(&r.aabb))
+}
+
+struct Builder<'a> {
+    tris: &'a [Triangle],
+    params: SweepSahParams,
+    root_area: f32,
+}
+
+impl Builder<'_> {
+    fn build(&self, mut refs: Vec<Reference>, aabb: Aabb) -> BuildNode {
+        let n = refs.len();
+        let leaf = |refs: Vec<Reference>| BuildNode::Leaf {
+            aabb,
+            refs: refs.iter().map(|r| r.index).collect(),
+        };
+        if n <= 1 {
+            return leaf(refs);
+        }
+
+        let object_split = self.best_object_split(&mut refs, &aabb);
+        let spatial_split = self
+            .params
+            .spatial_splits
+            .then(|| self.best_spatial_split(&refs, &aabb, &object_split))
+            .flatten();
+        let split_cost = spatial_split.as_ref().map_or(object_split.cost, |s| s.cost);
+        let leaf_cost = self.params.costs.intersection * n as f32;
+        if n <= self.params.max_prims_per_leaf as usize && leaf_cost <= split_cost {
+            return leaf(refs);
+        }
+
+        // The plane can put slightly different references on each side than the bins counted, don't use the spatial
+        // split if that leaves a side empty or with all the references.
+        let spatial_partition = spatial_split
+            .and_then(|split| match split.kind {
+                SplitKind::Spatial { position } => {
+                    Some(self.spatial_partition(&refs, split.axis, position))
+                }
+                SplitKind::Object { .. } => None,
+            })
+            .filter(|(left, right)| (1..n).contains(&left.len()) && (1..n).contains(&right.len()));
+        let (left, right) = match (spatial_partition, object_split.kind) {
+            (Some(partition), _) => partition,
+            (None, SplitKind::Object { index }) => {
+                sort_by_centroid(&mut refs, object_split.axis);
+                let right = refs.split_off(index);
+                (refs, right)
+            }
+            (None, SplitKind::Spatial { .. }) => unreachable!(),
+        };
+        let (left_aabb, right_aabb) = (union_refs(&left), union_refs(&right));
+        let (left, right) = if n > PARALLEL_THRESHOLD {
+            rayon::join(
+                || self.build(left, left_aabb),
+                || self.build(right, right_aabb),
+            )
+        } else {
+            (self.build(left, left_aabb), self.build(right, right_aabb))
+        };
+        BuildNode::Inner {
+            aabb,
+            children: Box::new([left, right]),
+        }
+    }
+
+    fn split_cost(&self, parent_area: f32, left: (f32, usize), right: (f32, usize)) -> f32 {
+        let costs = &self.params.costs;
+        costs.traversal
+            + costs.intersection * (left.0 * left.1 as f32 + right.0 * right.1 as f32)
+                / parent_area.max(f32::MIN_POSITIVE)
+    }
+
+    /// Sorts by centroid along each axis and sweeps over every split position.
+    fn best_object_split(&self, refs: &mut [Reference], aabb: &Aabb) -> Split {
+        let n = refs.len();
+        let parent_area = aabb.half_area();
+        let mut right_areas = vec![0.0; n];
+        let mut best = Split {
+            cost: f32::MAX,
+            axis: 0,
+            kind: SplitKind::Object { index: n / 2 },
+        };
+        for axis in 0..3 {
+            sort_by_centroid(refs, axis);
+            let mut right = Aabb::INVALID;
+            for i in (1..n).rev() {
+                right = right.union(&refs[i].aabb);
+                right_areas[i] = right.half_area();
+            }
+            let mut left = Aabb::INVALID;
+            for i in 1..n {
+                left = left.union(&refs[i - 1].aabb);
+                let cost =
+                    self.split_cost(parent_area, (left.half_area(), i), (right_areas[i], n - i));
+                // Ties (e.g. all centroids on the same spot) go to the most even split, always taking the first one
+                // would split off one reference at a time and make the tree as deep as there are references.
+                let best_index = match best.kind {
+                    SplitKind::Object { index } => index,
+                    SplitKind::Spatial { .. } => unreachable!(),
+                };
+                if cost < best.cost
+                    || (cost == best.cost && i.abs_diff(n / 2) < best_index.abs_diff(n / 2))
+                {
+                    best = Split {
+                        cost,
+                        axis,
+                        kind: SplitKind::Object { index: i },
+                    };
+                }
+            }
+        }
+        best
+    }
+
+    /// Binned spatial split, only tried if the children of the best object split overlap enough.
+    fn best_spatial_split(
+        &self,
+        refs: &[Reference],
+        aabb: &Aabb,
+        object_split: &Split,
+    ) -> Option<Split> {
+        let (axis, index) = match object_split.kind {
+            SplitKind::Object { index } => (object_split.axis, index),
+            SplitKind::Spatial { .. } => return None,
+        };
+        let mut sorted = refs.to_vec();
+        sort_by_centroid(&mut sorted, axis);
+        let overlap = intersection(&union_refs(&sorted[..index]), &union_refs(&sorted[index..]));
+        if overlap.half_area() / self.root_area <= SBVH_ALPHA {
+            return None;
+        }
+
+        let n = refs.len();
+        let parent_area = aabb.half_area();
+        let mut best: Option<Split> = None;
+        for axis in 0..3 {
+            let (min, extent) = (aabb.min[axis], aabb.max[axis] - aabb.min[axis]);
+            if extent <= 0.0 {
+                continue;
+            }
+            let bin_position = |bin: usize| min + extent * bin as f32 / SPATIAL_BINS as f32;
+            let bin_of = |x: f32| {
+                (((x - min) / extent * SPATIAL_BINS as f32) as usize).min(SPATIAL_BINS - 1)
+            };
+            let mut bins = [Aabb::INVALID; SPATIAL_BINS];
+            let mut entries = [0usize; SPATIAL_BINS];
+            let mut exits = [0usize; SPATIAL_BINS];
+            for r in refs {
+                let (first, last) = (bin_of(r.aabb.min[axis]), bin_of(r.aabb.max[axis]));
+                entries[first] += 1;
+                exits[last] += 1;
+                for bin in first..=last {
+                    let chopped = self.chop(r, axis, bin_position(bin), bin_position(bin + 1));
+                    bins[bin] = bins[bin].union(&chopped);
+                }
+            }
+
+            let mut right_areas = [0.0; SPATIAL_BINS];
+            let mut right_counts = [0; SPATIAL_BINS];
+            let (mut right, mut right_count) = (Aabb::INVALID, 0);
+            for bin in (1..SPATIAL_BINS).rev() {
+                right = right.union(&bins[bin]);
+                right_count += exits[bin];
+                right_areas[bin] = right.half_area();
+                right_counts[bin] = right_count;
+            }
+            let (mut left, mut left_count) = (Aabb::INVALID, 0);
+            for bin in 1..SPATIAL_BINS {
+                left = left.union(&bins[bin - 1]);
+                left_count += entries[bin - 1];
+                // Every child needs to get fewer references than the parent for the build to terminate
+                if left_count == 0
+                    || right_counts[bin] == 0
+                    || left_count >= n
+                    || right_counts[bin] >= n
+                {
+                    continue;
+                }
+                let cost = self.split_cost(
+                    parent_area,
+                    (left.half_area(), left_count),
+                    (right_areas[bin], right_counts[bin]),
+                );
+                if cost < best.as_ref().map_or(object_split.cost, |b| b.cost) {
+                    best = Some(Split {
+                        cost,
+                        axis,
+                        kind: SplitKind::Spatial {
+                            position: bin_position(bin),
+                        },
+                    });
+                }
+            }
+        }
+        best
+    }
+
+    /// References entirely on one side of the plane go to that side, the others are chopped and go to both.
+    fn spatial_partition(
+        &self,
+        refs: &[Reference],
+        axis: usize,
+        position: f32,
+    ) -> (Vec<Reference>, Vec<Reference>) {
+        let mut left = Vec::with_capacity(refs.len());
+        let mut right = Vec::with_capacity(refs.len());
+        for r in refs {
+            if r.aabb.max[axis] <= position {
+                left.push(*r);
+            } else if r.aabb.min[axis] >= position {
+                right.push(*r);
+            } else {
+                left.push(Reference {
+                    aabb: self.chop(r, axis, f32::MIN, position),
+                    index: r.index,
+                });
+                right.push(Reference {
+                    aabb: self.chop(r, axis, position, f32::MAX),
+                    index: r.index,
+                });
+            }
+        }
+        (left, right)
+    }
+
+    /// Bounds of the part of the referenced triangle between `lo` and `hi` along `axis`.
+    fn chop(&self, r: &Reference, axis: usize, lo: f32, hi: f32) -> Aabb {
+        let mut slab = r.aabb;
+        slab.min[axis] = slab.min[axis].max(lo);
+        slab.max[axis] = slab.max[axis].min(hi);
+        let poly = clip_triangle(&self.tris[r.index as usize], &slab);
+        if poly.is_empty() {
+            // Only touches the slab, or was lost to precision
+            return slab;
+        }
+        let chopped = poly
+            .iter()
+            .fold(Aabb::INVALID, |aabb, v| aabb.union(&Aabb::new(*v, *v)));
+        intersection(&chopped, &slab)
+    }
+}
+
+/// Equal centroids are ordered by index, so the order doesn't depend on the previous one and the partition that was
+/// evaluated is the one that gets applied.
+fn sort_by_centroid(refs: &mut [Reference], axis: usize) {
+    refs.sort_unstable_by(|a, b| {
+        a.aabb.center()[axis]
+            .total_cmp(&b.aabb.center()[axis])
+            .then(a.index.cmp(&b.index))
+    });
+}
+
+fn intersection(a: &Aabb, b: &Aabb) -> Aabb {
+    let aabb = Aabb::new(a.min.max(b.min), a.max.min(b.max));
+    if aabb.min.cmpgt(aabb.max).any() {
+        Aabb::new(aabb.min, aabb.min)
+    } else {
+        aabb
+    }
+}
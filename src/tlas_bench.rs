@@ -0,0 +1,250 @@
+use std::time::{Duration, Instant};
+
+use glam::{Affine3A, Quat, Vec3};
+use obvhs::aabb::Aabb;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use tabled::{settings::Style, Table, Tabled};
+use wgpu::{util::initialize_adapter_from_env_or_default, *};
+
+#[cfg(feature = "embree")]
+use crate::embree_device_from_options;
+use crate::{
+    build_label,
+    cwbvh::{cwbvh_from_tris, tlas_from_aabbs},
+    dynamic::transform_aabb,
+    load_scene,
+    rt_gpu::SoftwareInstance,
+    Options,
+};
+
+/// Frames excluded from the averages while caches and clocks settle.
+const WARMUP_FRAMES: usize = 10;
+
+#[derive(Tabled, Clone)]
+struct TlasBenchStats {
+    name: String,
+    build: String,
+    instances: usize,
+    tlas_nodes: usize,
+    /// Transforming the BLAS bounds of every instance.
+    instance_update_ms: f32,
+    tlas_build_ms: f32,
+    /// Writing the TLAS tail of the BVH buffer and the instance buffer, until the GPU is done with the copy. 0 with
+    /// --cpu.
+    upload_ms: f32,
+    total_ms: f32,
+}
+
+/// Moves every instance every frame and rebuilds the TLAS from scratch, like a game would. The BLAS of each object
+/// in the scene are built once, instances cycle through the objects and are laid out on a grid. Reports the steady
+/// state per frame cost for each instance count in `--tlas-bench-instances`.
+pub fn tlas_rebuild_benchmark(options: &Options) {
+    if options.build != "ploc_cwbvh" && options.build != "embree_cwbvh" {
+        panic!("TLAS benchmark supports ploc_cwbvh and embree_cwbvh");
+    }
+    if options.tlas_bench_frames <= WARMUP_FRAMES {
+        panic!("--tlas-bench-frames needs to be above {}", WARMUP_FRAMES);
+    }
+    #[cfg(feature = "embree")]
+    let embree_device =
+        (options.build == "embree_cwbvh").then(|| embree_device_from_options(options));
+
+    let instance_counts = options
+        .tlas_bench_instances
+        .split(",")
+        .map(|n| n.trim().parse::<usize>().expect("Invalid instance count"))
+        .collect::<Vec<_>>();
+
+    let mut stats = Vec::new();
+    for input in options.input.split(",") {
+        let (file_name, _scene, objects) = load_scene(input, &mut None);
+
+        let mut blas_build_time = Duration::ZERO;
+        let blas = objects
+            .iter()
+            .map(|tris| {
+                cwbvh_from_tris(
+                    tris,
+                    options,
+                    &mut blas_build_time,
+                    #[cfg(feature = "embree")]
+                    embree_device.as_ref(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let mut blas_bytes: Vec<u8> = Vec::new();
+        let mut blas_mapping = Vec::new(); // Mapping from blas index to offset
+        for b in &blas {
+            blas_mapping.push((blas_bytes.len() / 80) as u32);
+            blas_bytes.extend_from_slice(bytemuck::cast_slice(&b.nodes));
+        }
+        let spacing = blas
+            .iter()
+            .map(|b| b.total_aabb.diagonal().length())
+            .fold(0.0, f32::max);
+
+        for &instance_count in &instance_counts {
+            let grid_size = (instance_count as f32).cbrt().ceil() as usize;
+            // A CWBVH never has more nodes than primitives
+            let uploader = (!options.cpu)
+                .then(|| TlasUploader::new(&blas_bytes, instance_count.max(1), instance_count));
+
+            let mut instance_update_time = Duration::ZERO;
+            let mut tlas_build_time = Duration::ZERO;
+            let mut upload_time = Duration::ZERO;
+            let mut tlas_nodes = 0;
+            for frame in 0..options.tlas_bench_frames {
+                if frame == WARMUP_FRAMES {
+                    instance_update_time = Duration::ZERO;
+                    tlas_build_time = Duration::ZERO;
+                    upload_time = Duration::ZERO;
+                }
+
+                let start_time = Instant::now();
+                let instance_aabbs = (0..instance_count)
+                    .into_par_iter()
+                    .map(|i| {
+                        let transform = instance_transform(i, frame, grid_size, spacing);
+                        transform_aabb(&transform, &blas[i % blas.len()].total_aabb)
+                    })
+                    .collect::<Vec<Aabb>>();
+                instance_update_time += start_time.elapsed();
+
+                let tlas = tlas_from_aabbs(
+                    &instance_aabbs,
+                    options,
+                    &mut tlas_build_time,
+                    #[cfg(feature = "embree")]
+                    embree_device.as_ref(),
+                );
+                tlas_nodes = tlas.nodes.len();
+
+                if let Some(uploader) = &uploader {
+                    let start_time = Instant::now();
+                    // Same layout as in cwbvh_gpu_runner, the instance buffer is in TLAS primitive order.
+                    let instances = tlas
+                        .primitive_indices
+                        .iter()
+                        .map(|i| {
+                            let i = *i as usize;
+                            SoftwareInstance::new(
+                                blas_mapping[i % blas.len()],
+                                &instance_transform(i, frame, grid_size, spacing),
+                            )
+                        })
+                        .collect::<Vec<_>>();
+                    uploader.upload(
+                        bytemuck::cast_slice(&tlas.nodes),
+                        bytemuck::cast_slice(&instances),
+                    );
+                    upload_time += start_time.elapsed();
+                }
+            }
+
+            let frames = (options.tlas_bench_frames - WARMUP_FRAMES) as f32;
+            let instance_update_ms = instance_update_time.as_secs_f32() * 1000.0 / frames;
+            let tlas_build_ms = tlas_build_time.as_secs_f32() * 1000.0 / frames;
+            let upload_ms = upload_time.as_secs_f32() * 1000.0 / frames;
+            stats.push(TlasBenchStats {
+                name: file_name.clone(),
+                build: build_label(options),
+                instances: instance_count,
+                tlas_nodes,
+                instance_update_ms,
+                tlas_build_ms,
+                upload_ms,
+                total_ms: instance_update_ms + tlas_build_ms + upload_ms,
+            });
+        }
+    }
+    println!("{}", Table::new(stats).with(Style::blank()));
+}
+
+/// Instances sit on a grid and each one wobbles and spins around its cell.
+fn instance_transform(i: usize, frame: usize, grid_size: usize, spacing: f32) -> Affine3A {
+    let cell = Vec3::new(
+        (i % grid_size) as f32,
+        ((i / grid_size) % grid_size) as f32,
+        (i / (grid_size * grid_size)) as f32,
+    );
+    let t = frame as f32 * 0.1 + i as f32;
+    Affine3A::from_rotation_translation(
+        Quat::from_rotation_y(t),
+        (cell + Vec3::new(t.sin(), t.cos(), 0.0) * 0.25) * spacing,
+    )
+}
+
+/// Headless GPU buffers laid out like in `cwbvh_gpu_runner`: BLAS nodes followed by the TLAS nodes, and the
+/// instance buffer. Only the TLAS tail and the instances are written each frame.
+struct TlasUploader {
+    device: Device,
+    queue: Queue,
+    bvh_buffer: Buffer,
+    instance_buffer: Buffer,
+    tlas_offset: u64,
+}
+
+impl TlasUploader {
+    fn new(blas_bytes: &[u8], max_tlas_nodes: usize, instance_count: usize) -> Self {
+        futures::executor::block_on(async {
+            let instance = Instance::new(&InstanceDescriptor {
+                backends: Backends::PRIMARY,
+                ..Default::default()
+            });
+            let adapter = initialize_adapter_from_env_or_default(&instance, None)
+                .await
+                .expect("Failed to find an appropriate adapter");
+            let mut limits = Limits::default();
+            limits.max_storage_buffer_binding_size =
+                adapter.limits().max_storage_buffer_binding_size;
+            limits.max_buffer_size = adapter.limits().max_buffer_size;
+            let (device, queue) = adapter
+                .request_device(&DeviceDescriptor {
+                    label: None,
+                    required_features: Features::empty(),
+                    required_limits: limits,
+                    memory_hints: Default::default(),
+                    trace: Trace::Off,
+                })
+                .await
+                .expect("Failed to create device");
+
+            let tlas_offset = blas_bytes.len() as u64;
+            let bvh_buffer = device.create_buffer(&BufferDescriptor {
+                label: Some("BLAS Buffer"),
+                size: tlas_offset + max_tlas_nodes as u64 * 80,
+                usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+            let instance_buffer = device.create_buffer(&BufferDescriptor {
+                label: Some("Instance Buffer"),
+                size: (instance_count.max(1) * size_of::<SoftwareInstance>()) as u64,
+                usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+            if !blas_bytes.is_empty() {
+                queue.write_buffer(&bvh_buffer, 0, blas_bytes);
+            }
+            queue.submit(None);
+            device.poll(PollType::Wait).unwrap();
+
+            TlasUploader {
+                device,
+                queue,
+                bvh_buffer,
+                instance_buffer,
+                tlas_offset,
+            }
+        })
+    }
+
+    /// Writes the TLAS nodes after the BLAS and the instance buffer, then waits for the GPU to finish the copy.
+    fn upload(&self, tlas_bytes: &[u8], instance_bytes: &[u8]) {
+        self.queue
+            .write_buffer(&self.bvh_buffer, self.tlas_offset, tlas_bytes);
+        self.queue
+            .write_buffer(&self.instance_buffer, 0, instance_bytes);
+        self.queue.submit(None);
+        self.device.poll(PollType::Wait).unwrap();
+    }
+}
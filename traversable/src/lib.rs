@@ -25,6 +25,14 @@ pub trait Traversable {
     /// Retrieves the transform of a specific instance. This refers to the transform that is to be applied to an instance
     /// of a primitive in the traversable scene.
     fn get_instance_transform(&self, instance_id: u32) -> Mat4;
+
+    /// Normal of the primitive that was hit, in world space. Scenes with instance transforms override this, since their
+    /// primitives are in the object space of the instance.
+    #[inline(always)]
+    fn hit_normal(&self, hit: &RayHit, ray: &Ray) -> Vec3A {
+        self.get_primitive(hit.geometry_id, hit.primitive_id)
+            .compute_normal(ray)
+    }
 }
 
 /// A trait for types that can be intersected by a ray.
@@ -44,6 +52,12 @@ pub trait Intersectable {
 #[derive(Clone, Copy)]
 pub struct SceneRtTri(pub RtTriangle);
 
+impl From<&Triangle> for SceneRtTri {
+    fn from(tri: &Triangle) -> Self {
+        SceneRtTri(tri.into())
+    }
+}
+
 impl Intersectable for SceneRtTri {
     #[inline(always)]
     fn intersect(&self, ray: &Ray) -> f32 {